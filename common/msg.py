@@ -86,6 +86,51 @@ class Message:
     def response(self, resp):
         self._msg['resp'] = resp
 
+    @property
+    def body(self):
+        return self._msg.get('body')
+
+    @body.setter
+    def body(self, body):
+        self._msg['body'] = body
+
+    @property
+    def forward(self):
+        return self._msg.get('forward')
+
+    def forward_to(self, role=None, addr=None, uuid=None, action=None):
+        """
+        Pushes a target onto the forwarding chain of the message
+        When the message is answered (ie. 'resp' is filled in), the manager sends the answer on to the first
+        target in the chain instead of back to the sender, replacing the action if one is given
+        """
+        target = {}
+        if role is not None:
+            target['role'] = role
+        if addr is not None:
+            target['addr'] = addr
+        if uuid is not None:
+            target['uuid'] = uuid
+        if action is not None:
+            target['action'] = action
+
+        forward = self._msg.get('forward')
+        if isinstance(forward, dict):
+            forward = [ forward ]
+        elif not isinstance(forward, list):
+            forward = []
+
+        forward.append(target)
+        self._msg['forward'] = forward
+
+    @property
+    def expects_reply(self):
+        return self._msg.get('expects_reply', False)
+
+    @expects_reply.setter
+    def expects_reply(self, value):
+        self._msg['expects_reply'] = bool(value)
+
     @property
     def broadcast(self):
         return self._msg['dest'].get('broadcast', False)
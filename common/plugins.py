@@ -79,7 +79,8 @@ class Plugin:
 
         These messages generally provide feedback about the state of the request
         """
-        self._log.debug("Handled ack message: {}".format(msg.id))
+        status = msg.args[0] if len(msg.args) != 0 else {}
+        self._log.debug("Handled ack message for id={}: status={}".format(msg.parent_id, status.get('status')))
 
     async def handle_error(self, msg, _comm):
         """
@@ -1,86 +1,172 @@
-
-import asyncio
-import json
-import queue
-import struct
-
-from common.msg import Message
-
-class MessageEvent(asyncio.Event):
-    """
-    Custom event for handling message responses
-    """
-    value = None
-
-
-class CommChannel:
-    """
-    Custom communication channel to wrap client behaviors
-    """
-    def __init__(self, handles):
-        self._event_queue = {}
-        self._msg_queue = queue.Queue()
-        self._handles = handles
-
-    async def wait_for_response(self, msg, log):
-        """
-        Send a message to some other plugin and wait for a response message
-        """
-        self.send(msg, log)
-        self._event_queue[msg.id] = MessageEvent()
-        await self._event_queue[msg.id].wait()
-
-        resp = self._event_queue[msg.id].value
-        del self._event_queue[msg.id]
-        return resp
-
-    def send(self, msg, log):
-        self._msg_queue.put(msg)
-
-    def get_msg(self):
-        return self._msg_queue.get()
-
-    @property
-    def handles(self):
-        return self._handles
-
-    @property
-    def events(self):
-        return self._event_queue
-
-
-class JsonCodec:
-    @staticmethod
-    def send_message(msg, sock, log):
-        """
-        Automaticaly wrap message in correct json protocol
-        """
-        if isinstance(msg, Message):
-            msg = msg.json_packet
-
-        log.info("Sending message id={}: {}".format(msg.get('message_id'), msg))
-
-        data = json.dumps(msg).encode('utf-8')
-        frame = struct.pack('>I', len(data))
-        sock.sendall(frame + data)
-
-    @staticmethod
-    def get_messages(sock, log):
-        """
-        Generator to automatically parse json protocol
-        """
-        try:
-            while True:
-                len_buf = sock.recv(4)
-                msg_len = struct.unpack('>I', len_buf)[0]
-                buf = sock.recv(msg_len)
-                msg = json.loads(buf.decode('utf-8'))
-
-                log.info("Received message id={}: {}".format(msg.get('message_id'), msg))
-                yield Message.from_json(msg)
-
-        except ConnectionResetError as e:
-            log.error("Lost connection to server")
-
-        except Exception as e:
-            log.error("Exception while waiting for messages: {}".format(e))
+
+import asyncio
+import json
+import queue
+import struct
+
+from common.msg import Message
+
+# The version of the message protocol spoken by the python apps (see `protocol.rs` in the server crate)
+PROTOCOL_VERSION = 2
+
+class MessageEvent(asyncio.Event):
+    """
+    Custom event for handling message responses
+    """
+    value = None
+
+
+class CommChannel:
+    """
+    Custom communication channel to wrap client behaviors
+    """
+    def __init__(self, handles):
+        self._event_queue = {}
+        self._msg_queue = queue.Queue()
+        self._handles = handles
+
+    async def wait_for_response(self, msg, log):
+        """
+        Send a message to some other plugin and wait for a response message
+        """
+        # Let the device-manager tell us if the response never arrives
+        msg.expects_reply = True
+        self.send(msg, log)
+        self._event_queue[msg.id] = MessageEvent()
+        await self._event_queue[msg.id].wait()
+
+        resp = self._event_queue[msg.id].value
+        del self._event_queue[msg.id]
+        return resp
+
+    def send(self, msg, log):
+        self._msg_queue.put(msg)
+
+    def send_heartbeat(self, frame):
+        """
+        Queue a heartbeat frame (ie. a pong answering the device-manager's ping)
+        """
+        self._msg_queue.put(frame)
+
+    def get_msg(self):
+        return self._msg_queue.get()
+
+    @property
+    def handles(self):
+        return self._handles
+
+    @property
+    def events(self):
+        return self._event_queue
+
+
+def is_heartbeat(msg):
+    """
+    Check whether the frame is a heartbeat instead of a message (see `heartbeat.rs` in the server crate)
+    """
+    return isinstance(msg, dict) and 'heartbeat' in msg
+
+
+class JsonCodec:
+    """
+    Length-delimited json frames (the default codec, which needs no preamble)
+    """
+    name = 'json'
+
+    @staticmethod
+    def encode(msg):
+        return json.dumps(msg).encode('utf-8')
+
+    @staticmethod
+    def decode(buf):
+        return json.loads(buf.decode('utf-8'))
+
+    @classmethod
+    def select(cls, sock, log):
+        """
+        Pick this codec for the connection. This must be called before any message is sent
+        """
+        if cls.name != JsonCodec.name:
+            log.info("Selecting the {} codec for the connection".format(cls.name))
+            sock.sendall(JsonCodec._frame({'codec': cls.name}))
+
+    @classmethod
+    def send_message(cls, msg, sock, log):
+        """
+        Automaticaly wrap message in correct protocol
+        """
+        if isinstance(msg, Message):
+            msg = msg.json_packet
+
+        if not is_heartbeat(msg):
+            log.info("Sending message id={}: {}".format(msg.get('message_id'), msg))
+
+        sock.sendall(cls._frame(msg))
+
+    @classmethod
+    def _frame(cls, msg):
+        data = cls.encode(msg)
+        return struct.pack('>I', len(data)) + data
+
+    @classmethod
+    def get_messages(cls, sock, log, on_heartbeat=None):
+        """
+        Generator to automatically parse protocol, answering heartbeat pings through `on_heartbeat`
+        """
+        try:
+            while True:
+                len_buf = sock.recv(4)
+                msg_len = struct.unpack('>I', len_buf)[0]
+                buf = sock.recv(msg_len)
+                msg = cls.decode(buf)
+
+                if is_heartbeat(msg):
+                    if msg['heartbeat'] == 'ping' and on_heartbeat is not None:
+                        on_heartbeat({'heartbeat': 'pong', 'seq': msg.get('seq', 0)})
+                    continue
+
+                log.info("Received message id={}: {}".format(msg.get('message_id'), msg))
+                yield Message.from_json(msg)
+
+        except ConnectionResetError as e:
+            log.error("Lost connection to server")
+
+        except Exception as e:
+            log.error("Exception while waiting for messages: {}".format(e))
+
+
+class MessagePackCodec(JsonCodec):
+    """
+    Length-delimited MessagePack frames (requires the `msgpack` package)
+    """
+    name = 'msgpack'
+
+    @staticmethod
+    def encode(msg):
+        import msgpack
+        return msgpack.packb(msg, use_bin_type=True)
+
+    @staticmethod
+    def decode(buf):
+        import msgpack
+        return msgpack.unpackb(buf, raw=False)
+
+
+class CborCodec(JsonCodec):
+    """
+    Length-delimited CBOR frames (requires the `cbor2` package)
+    """
+    name = 'cbor'
+
+    @staticmethod
+    def encode(msg):
+        import cbor2
+        return cbor2.dumps(msg)
+
+    @staticmethod
+    def decode(buf):
+        import cbor2
+        return cbor2.loads(buf)
+
+
+CODECS = {codec.name: codec for codec in (JsonCodec, MessagePackCodec, CborCodec)}
@@ -0,0 +1,136 @@
+
+use ring::{constant_time, hmac};
+use serde_json::Value;
+
+/*
+When the manager is given a secret (see `--auth-secret`), every connection must authenticate in its handshake
+Before it may send anything else. Apps authenticate by giving either the secret itself, or a token that was
+Signed with the secret for the role the app registers as (the hex-encoded HMAC-SHA256 of the role), or for both
+Its role and uuid (the HMAC of the role and uuid joined by a newline):
+
+  "body": { "auth": { "secret": "correct horse battery staple" } }
+  "body": { "auth": { "token": "5d41402abc4b2a76b9719d911017c592..." } }
+
+Tokens let each app be handed a credential that only works for its own role. The uuid that an app registers with
+Is only taken as proven when its token was signed for it (or it gave the secret), so that permissions granted to a
+Specific app can't be picked up by any app of another role. Device managers authenticate their links with a token
+For the `manager` role.
+*/
+
+// What an app's credentials proved about it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verified {
+    // The app may register as its role, but its uuid is only what it claimed
+    Role,
+
+    // The app's uuid is proven as well (or authentication is disabled, so nothing is proven at all)
+    App,
+}
+
+#[derive(Default)]
+pub struct Authenticator {
+    secret: Option<String>,
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_secret(&mut self, secret: &str) {
+        self.secret = Some(secret.to_string());
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    // Sign a token that authenticates an app as `role`
+    pub fn token(&self, role: &str) -> Option<String> {
+        self.sign(role.as_bytes())
+    }
+
+    // Check the `auth` that an app gave in its handshake to register as `role` (with `uuid`)
+    pub fn verify(&self, role: &str, uuid: Option<&str>, auth: Option<&Value>) -> Result<Verified, String> {
+        let secret = match self.secret {
+            Some(ref secret) => secret,
+            None => return Ok(Verified::App),
+        };
+
+        let auth = auth.ok_or("Handshake must give `auth` credentials".to_string())?;
+        if let Some(given) = auth.get("secret").and_then(|secret| secret.as_str()) {
+            return constant_time::verify_slices_are_equal(given.as_bytes(), secret.as_bytes())
+                .map(|_| Verified::App)
+                .map_err(|_| "Handshake gave an incorrect secret".to_string());
+        }
+
+        if let Some(token) = auth.get("token").and_then(|token| token.as_str()) {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+            let tag = decode_hex(token).ok_or("Handshake token must be hex-encoded".to_string())?;
+            if let Some(uuid) = uuid {
+                if hmac::verify(&key, app_subject(role, uuid).as_bytes(), &tag).is_ok() {
+                    return Ok(Verified::App);
+                }
+            }
+            return hmac::verify(&key, role.as_bytes(), &tag)
+                .map(|_| Verified::Role)
+                .map_err(|_| format!("Handshake token was not signed for role {:?}", role));
+        }
+
+        Err("Handshake `auth` must give either a `secret` or a `token`".to_string())
+    }
+
+    fn sign(&self, subject: &[u8]) -> Option<String> {
+        self.secret.as_ref().map(|secret| {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+            hmac::sign(&key, subject).as_ref()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect()
+        })
+    }
+}
+
+fn app_subject(role: &str, uuid: &str) -> String {
+    format!("{}\n{}", role, uuid)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|idx| hex.get(idx..idx + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticator() -> Authenticator {
+        let mut auth = Authenticator::new();
+        auth.set_secret("secret");
+        auth
+    }
+
+    #[test]
+    fn role_tokens_only_prove_the_role() {
+        let auth = authenticator();
+        let token = json!({ "token": auth.token("cli").unwrap() });
+
+        assert_eq!(auth.verify("cli", Some("admin-uuid"), Some(&token)), Ok(Verified::Role));
+        assert!(auth.verify("audio", Some("admin-uuid"), Some(&token)).is_err());
+    }
+
+    #[test]
+    fn app_tokens_prove_the_uuid() {
+        let auth = authenticator();
+        let token = json!({ "token": auth.sign(app_subject("cli", "admin-uuid").as_bytes()).unwrap() });
+
+        assert_eq!(auth.verify("cli", Some("admin-uuid"), Some(&token)), Ok(Verified::App));
+        assert!(auth.verify("cli", Some("other-uuid"), Some(&token)).is_err());
+        assert!(auth.verify("audio", Some("admin-uuid"), Some(&token)).is_err());
+    }
+}
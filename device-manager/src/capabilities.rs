@@ -0,0 +1,128 @@
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use serde_json;
+
+/*
+Apps advertise the actions that they handle in the `body` of their handshake message, ie.
+
+  "body": {
+      "actions": {
+          "play": { "help": "Play the requested song", "args": [{ "name": "song" }] },
+          "stop": {}
+      }
+  }
+
+The manager collects these into a registry mapping each role to the actions it handles and the connections
+That provide them, which apps can query through the `capabilities` action.
+*/
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ActionInfo {
+    #[serde(default)]
+    pub help: Option<String>,
+    #[serde(default)]
+    pub args: Vec<ArgInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArgInfo {
+    pub name: String,
+    #[serde(default)]
+    pub help: Option<String>,
+}
+
+// Extract the advertised actions from a handshake message body
+pub fn parse_actions(body: &Option<serde_json::Value>) -> Result<HashMap<String, ActionInfo>, serde_json::Error> {
+    match body.as_ref().and_then(|body| body.get("actions")) {
+        Some(actions) => serde_json::from_value(actions.clone()),
+        None => Ok(HashMap::new()),
+    }
+}
+
+struct Provider {
+    addr: SocketAddr,
+    uuid: String,
+}
+
+struct Capability {
+    info: ActionInfo,
+    providers: Vec<Provider>,
+}
+
+#[derive(Default)]
+pub struct CapabilityRegistry {
+    roles: HashMap<String, HashMap<String, Capability>>,
+}
+
+impl CapabilityRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Register actions that are handled internally by the device manager (ie. have no connection)
+    pub fn register_local(&mut self, role: &str, actions: HashMap<String, ActionInfo>) {
+        let role_actions = self.roles.entry(role.to_string()).or_insert(HashMap::new());
+        for (action, info) in actions {
+            role_actions.insert(action, Capability{ info: info, providers: Vec::new() });
+        }
+    }
+
+    // Record that the connection at `addr` handles the given actions for `role`
+    pub fn register(&mut self, role: &str, addr: SocketAddr, uuid: &str, actions: HashMap<String, ActionInfo>) {
+        let role_actions = self.roles.entry(role.to_string()).or_insert(HashMap::new());
+        for (action, info) in actions {
+            let capability = role_actions.entry(action).or_insert(Capability{ info: info.clone(), providers: Vec::new() });
+
+            // NOTE: The most recent registration determines the advertised description
+            capability.info = info;
+            capability.providers.retain(|provider| provider.addr != addr);
+            capability.providers.push(Provider{ addr: addr, uuid: uuid.to_string() });
+        }
+    }
+
+    // Remove the connection from every action it provided, returning the `(role, action)` pairs it was providing
+    // Roles and actions are dropped from the registry once nothing provides them anymore
+    pub fn unregister(&mut self, addr: &SocketAddr) -> Vec<(String, String)> {
+        let mut removed = Vec::new();
+        for (role, actions) in self.roles.iter_mut() {
+            for (action, capability) in actions.iter_mut() {
+                let before = capability.providers.len();
+                capability.providers.retain(|provider| provider.addr != *addr);
+                if capability.providers.len() != before {
+                    removed.push((role.clone(), action.clone()));
+                }
+            }
+
+            let removed = &removed;
+            actions.retain(|action, capability| !capability.providers.is_empty()
+                || !removed.iter().any(|&(ref r, ref a)| r == role && a == action));
+        }
+
+        self.roles.retain(|_, actions| !actions.is_empty());
+        removed
+    }
+
+    // Produce a description of the registry, optionally restricted to a single role
+    pub fn to_json(&self, role: Option<&str>) -> serde_json::Value {
+        let roles = self.roles.iter()
+            .filter(|&(name, _)| role.map_or(true, |role| role == name))
+            .map(|(name, actions)| {
+                let actions = actions.iter()
+                    .map(|(action, capability)| (action.clone(), json!({
+                        "help": capability.info.help,
+                        "args": capability.info.args,
+                        "providers": capability.providers.iter()
+                            .map(|provider| json!({ "addr": provider.addr.to_string(), "uuid": provider.uuid }))
+                            .collect::<Vec<_>>(),
+                    })))
+                    .collect::<serde_json::Map<_, _>>();
+
+                (name.clone(), serde_json::Value::Object(actions))
+            })
+            .collect::<serde_json::Map<_, _>>();
+
+        serde_json::Value::Object(roles)
+    }
+}
@@ -2,19 +2,37 @@
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use futures::sync::oneshot;
 use get_if_addrs;
 use multimap::MultiMap;
 use serde_json;
 use tokio::io::{Error, ErrorKind};
+use uuid::Uuid;
 
 use networking;
 use networking::{Closer, Communicator};
+use networking::heartbeat::{Heartbeat, HeartbeatOptions};
+use networking::protocol::{self, VersionRange};
+use networking::queue::{OverflowPolicy, QueueOptions};
 
 use seshat;
 use seshat::index as idx;
 
+use auth::{Authenticator, Verified};
+use capabilities;
+use capabilities::CapabilityRegistry;
+use guard::{RoutingGuard, Verdict};
+use links::LinkTable;
+use local::{LocalPeers, PeerCredentials};
 use message;
+use permissions::PermissionPolicy;
+use requests::{PendingRequest, RequestTracker};
+use schedule::CrawlSchedule;
+use selection::{Candidate, RoleSelector, SelectionPolicy};
+use shutdown::Shutdown;
+use uplink::Uplink;
 
 #[derive(Clone)]
 pub struct DeviceManager {
@@ -22,18 +40,33 @@ pub struct DeviceManager {
     role_map: Arc<Mutex<MultiMap<String, SocketAddr>>>,
     uuid_map: Arc<Mutex<HashMap<String, SocketAddr>>>,
     handle_map: Arc<Mutex<HashMap<String, DeviceCallback>>>,
-
-    cancel: Closer,
+    capabilities: Arc<Mutex<CapabilityRegistry>>,
+    selector: Arc<Mutex<RoleSelector>>,
+    guard: Arc<Mutex<RoutingGuard>>,
+    requests: Arc<Mutex<RequestTracker>>,
+    links: Arc<Mutex<LinkTable>>,
+    uplink: Arc<Mutex<Option<Uplink>>>,
+    queue_options: Arc<Mutex<QueueOptions>>,
+    heartbeat_options: Arc<Mutex<Option<HeartbeatOptions>>>,
+    auth: Arc<Mutex<Authenticator>>,
+    permissions: Arc<Mutex<PermissionPolicy>>,
+    local_peers: Arc<Mutex<LocalPeers>>,
+
+    // Connections that are being closed as part of the shutdown, waiting on their outgoing queues to flush
+    draining: Arc<Mutex<HashMap<SocketAddr, Closer>>>,
+    shutdown: Shutdown,
     index: idx::Index,
+    schedule: CrawlSchedule,
 
     // NOTE: We can remove the option once we can determine the device's public ip addr
-    public_ip: IpAddr
+    public_ip: IpAddr,
+    device_id: String,
 }
 
 // TODO: I need to add in the capability to recognize sent messages (for broadcasts specifically)
 // TODO: I want to have the device's address here
 impl DeviceManager {
-    pub fn new(index: idx::Index, cancel: Closer) -> Self {
+    pub fn new(index: idx::Index, shutdown: Shutdown) -> Self {
         // Extract the device's public ip (NOTE: For now I'm just taking the first non-localhost interface on the system)
         let my_public_ip = get_if_addrs::get_if_addrs()
             .ok()
@@ -51,8 +84,17 @@ impl DeviceManager {
         let mut handle_map = HashMap::<String, DeviceCallback>::new();
         handle_map.insert("handshake".to_string(), Self::handshake);
         handle_map.insert("search".to_string(), Self::handle_search);
+        handle_map.insert("schedule".to_string(), Self::handle_schedule);
+        handle_map.insert("reindex".to_string(), Self::handle_reindex);
         handle_map.insert("stop".to_string(), Self::handle_stop);
         handle_map.insert("quit".to_string(), Self::handle_quit);
+        handle_map.insert("capabilities".to_string(), Self::handle_capabilities);
+        handle_map.insert("diagnostics".to_string(), Self::handle_diagnostics);
+        handle_map.insert("links".to_string(), Self::handle_links);
+
+        // Advertise the server message callbacks alongside the actions registered by apps
+        let mut capabilities = CapabilityRegistry::new();
+        capabilities.register_local("manager", manager_actions());
 
         // Finalize the device manager
         Self{
@@ -60,9 +102,26 @@ impl DeviceManager {
             role_map: Arc::new(Mutex::new(MultiMap::new())),
             uuid_map: Arc::new(Mutex::new(HashMap::new())),
             handle_map: Arc::new(Mutex::new(handle_map)),
-            cancel: cancel,
+            capabilities: Arc::new(Mutex::new(capabilities)),
+            selector: Arc::new(Mutex::new(RoleSelector::new())),
+            guard: Arc::new(Mutex::new(RoutingGuard::new(DEFAULT_SEEN_CACHE_SIZE, DEFAULT_MAX_HOPS))),
+            requests: Arc::new(Mutex::new(RequestTracker::new(Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS)))),
+            links: Arc::new(Mutex::new(LinkTable::new())),
+            uplink: Arc::new(Mutex::new(None)),
+            queue_options: Arc::new(Mutex::new(QueueOptions::default())),
+            heartbeat_options: Arc::new(Mutex::new(Some(HeartbeatOptions{
+                interval: Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_SECS),
+                misses: DEFAULT_HEARTBEAT_MISSES,
+            }))),
+            auth: Arc::new(Mutex::new(Authenticator::new())),
+            permissions: Arc::new(Mutex::new(PermissionPolicy::new())),
+            local_peers: Arc::new(Mutex::new(LocalPeers::new())),
+            draining: Arc::new(Mutex::new(HashMap::new())),
+            shutdown: shutdown,
             index: index,
+            schedule: CrawlSchedule::new(),
             public_ip: my_public_ip,
+            device_id: Uuid::new_v4().to_string(),
         }
     }
 
@@ -72,45 +131,301 @@ impl DeviceManager {
     fn handshake(&mut self, msg: &mut message::Message, addr: &SocketAddr) -> CallbackResult {
         trace!("Received handshake request from {:?}", addr);
 
-        // NOTE: This may not borrow check
-        let mut conn_lock = self.connections.lock().unwrap();
-        let mut conn = conn_lock.get_mut(&addr);
+        // Other device managers register a link instead of an app
+        let is_link = msg.body.as_ref()
+            .and_then(|body| body.get("link"))
+            .map_or(false, |link| link == "manager");
+
+        // Make sure the app is allowed to register before changing anything
+        let role = if is_link { Some("manager".to_string()) } else { msg.sender.role.clone() };
+        let auth = msg.body.as_ref().and_then(|body| body.get("auth"));
+        let verified = self.auth.lock().unwrap().verify(role.as_ref().map_or("", |role| role.as_str()), msg.sender.uuid.as_ref().map(|uuid| uuid.as_str()), auth);
+        let verified = match verified {
+            Ok(verified) => verified,
+            Err(reason) => {
+                warn!("Rejecting handshake from {:?} as role {:?}: {}", addr, role, reason);
+                return Some(self.send_error(addr, msg, message::ErrorCode::Unauthenticated, &reason));
+            },
+        };
+
+        // Settle on the protocol version used for the rest of the connection
+        let range = match VersionRange::parse(msg.body.as_ref()) {
+            Ok(range) => range,
+            Err(err) => return malformed_args(&err),
+        };
+        let version = match VersionRange::supported().negotiate(&range) {
+            Some(version) => version,
+            None => {
+                let reason = format!("The device manager speaks protocol {}, but the app speaks {}", VersionRange::supported().describe(), range.describe());
+                warn!("Rejecting handshake from {:?} as role {:?}: {}", addr, role, reason);
+                let sent = self.send_error(addr, msg, message::ErrorCode::IncompatibleProtocol, &reason);
+
+                // NOTE: Nothing else can be said over the connection, so close it once the error has been sent
+                if let Some(conn) = self.connections.lock().unwrap().get(addr) {
+                    conn.queue.close();
+                }
+                return Some(sent);
+            },
+        };
 
-        if let Some(uuid) = msg.sender.uuid.clone() {
-            info!("Adding uuid {:?} to point to socket address {:?}", uuid, addr);
-            self.uuid_map.lock().unwrap().insert(uuid.clone(), addr.clone());
-            if let Some(ref mut conn) = conn {
-                conn.uuid = uuid;
+        // Start pinging the connection if it says that it answers heartbeats
+        let answers_heartbeats = msg.body.as_ref()
+            .and_then(|body| body.get("heartbeat"))
+            .and_then(|heartbeat| heartbeat.as_bool())
+            .unwrap_or(false);
+
+        // NOTE: Only links that gave credentials for the `manager` role are trusted to have applied their own policy
+        let trusted = is_link && self.auth.lock().unwrap().is_enabled();
+        if let Some(conn) = self.connections.lock().unwrap().get_mut(addr) {
+            if conn.protocol != version {
+                info!("Connection {:?} speaks protocol version {}", addr, version);
             }
+            conn.authenticated = true;
+            conn.uuid_verified = verified == Verified::App;
+            conn.trusted = trusted;
+            conn.protocol = version;
+            if answers_heartbeats {
+                if let Some(ref heartbeat) = conn.heartbeat {
+                    heartbeat.activate();
+                }
+            }
+        }
+        msg.resp = Some(json!({
+            "protocol": {
+                "version": version,
+                "supported": VersionRange::supported().to_json(),
+            },
+        }));
+
+        if is_link {
+            return self.register_link(msg, addr);
         }
 
+        // Extract the actions that the app is advertising
+        let actions = match capabilities::parse_actions(&msg.body) {
+            Ok(actions) => actions,
+            Err(err) => return malformed_args(&format!("Malformed `actions` in handshake: {}", err)),
+        };
+
+        // Extract how the app wants to be chosen among the other providers of its role
+        let body = msg.body.clone().unwrap_or(json!({}));
+        let priority = match body.get("priority") {
+            Some(priority) => match priority.as_i64() {
+                Some(priority) => priority,
+                None => return malformed_args("`priority` in handshake must be an integer"),
+            },
+            None => 0,
+        };
+        let policy = match body.get("selection") {
+            Some(policy) => match policy.as_str().map(SelectionPolicy::parse) {
+                Some(Ok(policy)) => Some(policy),
+                Some(Err(err)) => return malformed_args(&err),
+                None => return malformed_args("`selection` in handshake must be a string"),
+            },
+            None => None,
+        };
+
+        // Extract what the app wants done with its messages when it falls behind on its outgoing queue
+        let overflow = match body.get("overflow") {
+            Some(overflow) => match overflow.as_str().and_then(OverflowPolicy::parse) {
+                Some(overflow) => Some(overflow),
+                None => return malformed_args("`overflow` in handshake must be one of `block`, `drop-oldest`, `drop-newest`, or `disconnect`"),
+            },
+            None => None,
+        };
+
+        let roles_before = self.local_roles();
+        let previous = {
+            let mut conn_lock = self.connections.lock().unwrap();
+            let previous = conn_lock.get(&addr)
+                .map(|conn| (conn.role.clone(), conn.uuid.clone()))
+                .unwrap_or((String::new(), String::new()));
+
+            if let Some(uuid) = msg.sender.uuid.clone() {
+                let mut uuid_map = self.uuid_map.lock().unwrap();
+
+                // The app is re-identifying itself, so its old uuid no longer routes here
+                if !previous.1.is_empty() && previous.1 != uuid && uuid_map.get(&previous.1) == Some(addr) {
+                    info!("Removing uuid {:?} from socket address {:?}", previous.1, addr);
+                    uuid_map.remove(&previous.1);
+                }
+
+                info!("Adding uuid {:?} to point to socket address {:?}", uuid, addr);
+                uuid_map.insert(uuid.clone(), addr.clone());
+                if let Some(conn) = conn_lock.get_mut(&addr) {
+                    conn.uuid = uuid;
+                }
+            }
+
+            if let Some(role) = msg.sender.role.clone() {
+                let mut role_map = self.role_map.lock().unwrap();
+
+                // The app is taking on a new role, so stop routing its old role here
+                if !previous.0.is_empty() && previous.0 != role {
+                    info!("Removing role {:?} from socket address {:?}", previous.0, addr);
+                    remove_role_route(&mut role_map, &previous.0, *addr, &conn_lock);
+                }
+
+                let registered = role_map.get_vec(&role).map_or(false, |addrs| addrs.contains(addr));
+                if !registered {
+                    match conn_lock.get(&addr).and_then(|conn| conn.peer) {
+                        Some(peer) => info!("Adding role {:?} to point to socket address {:?} (process {:?}, uid {})", role, addr, peer.pid, peer.uid),
+                        None => info!("Adding role {:?} to point to socket address {:?}", role, addr),
+                    }
+                    role_map.insert(role.clone(), addr.clone());
+                }
+                if let Some(conn) = conn_lock.get_mut(&addr) {
+                    conn.role = role.clone();
+                    conn.priority = priority;
+                }
+
+                if let Some(policy) = policy {
+                    info!("Setting {:?} selection policy for role {:?}", policy, role);
+                    self.selector.lock().unwrap().advertise(&role, policy);
+                }
+            }
+
+            if let (Some(overflow), Some(conn)) = (overflow, conn_lock.get(&addr)) {
+                info!("Setting {:?} overflow policy for the outgoing queue of {:?}", overflow, addr);
+                conn.queue.set_policy(overflow);
+            }
+
+            previous
+        };
+
+        // Let the linked managers know that they can reach a new role through us
+        if self.local_roles() != roles_before {
+            self.announce_roles();
+        }
+
+        // Drop the actions the app registered in any previous handshake
+        let removed = self.capabilities.lock().unwrap().unregister(addr);
+        if !removed.is_empty() {
+            let actions = removed.into_iter().map(|(_, action)| action).collect();
+            self.notify_capabilities_changed("left", &previous.0, &previous.1, actions, Some(addr));
+        }
+
+        // Register the advertised actions and let everyone else know about them
         if let Some(role) = msg.sender.role.clone() {
-            info!("Adding role {:?} to point to socket address {:?}", role, addr);
-            self.role_map.lock().unwrap().insert(role.clone(), addr.clone());
-            if let Some(ref mut conn) = conn {
-                conn.role = role;
+            if !actions.is_empty() {
+                let uuid = msg.sender.uuid.clone().unwrap_or(String::new());
+                let action_names = actions.keys().cloned().collect::<Vec<_>>();
+                info!("Registering actions {:?} for role {:?} on {:?}", action_names, role, addr);
+
+                self.capabilities.lock().unwrap().register(&role, *addr, &uuid, actions);
+                self.notify_capabilities_changed("joined", &role, &uuid, action_names, Some(addr));
             }
         }
 
         None
     }
 
+    fn handle_capabilities(&mut self, msg: &mut message::Message, addr: &SocketAddr) -> CallbackResult {
+        trace!("Received capabilities request from {:?}", addr);
+
+        // Report the actions registered for every role, or just for the requested role
+        let role = match msg.args.as_ref().and_then(|args| args.get(0)) {
+            Some(role) => match role.as_str() {
+                Some(role) => Some(role.to_string()),
+                None => return malformed_args("`capabilities` role argument must be a string"),
+            },
+            None => None,
+        };
+
+        msg.resp = Some(self.capabilities.lock().unwrap().to_json(role.as_ref().map(|role| role.as_str())));
+        None
+    }
+
+    fn handle_links(&mut self, msg: &mut message::Message, addr: &SocketAddr) -> CallbackResult {
+        trace!("Received links request from {:?}", addr);
+
+        msg.resp = Some(json!({
+            "addr": self.public_ip,
+            "device_id": self.device_id,
+            "managers": self.links.lock().unwrap().to_json(),
+            "parent": self.uplink.lock().unwrap().as_ref().map(|uplink| uplink.to_json()),
+        }));
+        None
+    }
+
+    fn handle_diagnostics(&mut self, msg: &mut message::Message, addr: &SocketAddr) -> CallbackResult {
+        trace!("Received diagnostics request from {:?}", addr);
+
+        let queues = self.connections.lock().unwrap().iter()
+            .map(|(addr, conn)| {
+                let mut queue = conn.queue.to_json();
+                queue["addr"] = json!(addr.to_string());
+                queue["role"] = json!(conn.role);
+                queue["uuid"] = json!(conn.uuid);
+                queue["peer"] = json!(conn.peer.map(|peer| peer.to_json()));
+                queue["heartbeat"] = json!(conn.heartbeat.as_ref().map(|heartbeat| heartbeat.to_json()));
+                queue["protocol"] = json!(conn.protocol);
+                queue
+            })
+            .collect::<Vec<_>>();
+
+        msg.resp = Some(json!({
+            "routing": self.guard.lock().unwrap().stats(),
+            "requests": self.requests.lock().unwrap().stats(),
+            "queues": queues,
+        }));
+        None
+    }
+
     fn handle_search(&mut self, msg: &mut message::Message, addr: &SocketAddr) -> CallbackResult {
         trace!("Received search request from {:?}", addr);
 
         // Perform a filesystem search over the given arguments
-        if let Some(ref args) = msg.args {
-            info!("Searching for {:?}", args);
+        let query = match msg.args.as_ref().and_then(|args| args.get(0)) {
+            Some(query) => query.clone(),
+            None => return malformed_args("`search` requires a query argument"),
+        };
+        info!("Searching for {:?}", query);
 
-            if let Some(query) = &args[0].as_str() {
-                let results = seshat::default_search(query, &self.index);
-                msg.resp = Some(json!(results));
-                info!("Found results: {:?}", msg.resp);
+        if let Some(query) = query.as_str() {
+            let results = seshat::default_search(query, &self.index);
+            msg.resp = Some(json!(results));
+            info!("Found results: {:?}", msg.resp);
 
-            } else {
-                debug!("Could not cast query arg to string: {:?}", args[0]);
+        } else {
+            debug!("Could not cast query arg to string: {:?}", query);
+            return malformed_args("`search` query argument must be a string");
+        }
+        None
+    }
+
+    fn handle_schedule(&mut self, msg: &mut message::Message, addr: &SocketAddr) -> CallbackResult {
+        trace!("Received schedule request from {:?}", addr);
+
+        // Report the crawl schedule (and next run) for every index root
+        msg.resp = Some(self.schedule.status());
+        None
+    }
+
+    fn handle_reindex(&mut self, msg: &mut message::Message, addr: &SocketAddr) -> CallbackResult {
+        trace!("Received reindex request from {:?}", addr);
+
+        // Queue the requested folders for crawling, defaulting to every index root
+        let folders = match msg.args {
+            Some(ref args) if !args.is_empty() => args.iter()
+                .map(|arg| arg.as_str().map(|folder| folder.to_string()))
+                .collect::<Option<Vec<_>>>(),
+            _ => Some(self.schedule.roots()),
+        };
+        let folders = match folders {
+            Some(folders) => folders,
+            None => return malformed_args("`reindex` folder arguments must be strings"),
+        };
+
+        for folder in &folders {
+            info!("Queueing {:?} for reindexing", folder);
+            if let Err(err) = self.index.push_folder(folder) {
+                debug!("Failed to queue {:?} for reindexing: {:?}", folder, err);
             }
+            self.schedule.mark_run(folder);
         }
+
+        msg.resp = Some(json!(folders));
         None
     }
 
@@ -123,95 +438,230 @@ impl DeviceManager {
     fn handle_quit(&mut self, _msg: &mut message::Message, addr: &SocketAddr) -> CallbackResult {
         trace!("Received quit request from {:?}", addr);
 
-        // Send a close signal to all connected devices
-        // NOTE: We don't remove the connections as the manager is closing anyways
-        // TODO: Wouldn't this message actually be received as a broadcast?
-        // TODO: Shouldn't we close the connection that gave us the message first (to prevent loops)
-        let mut conns = self.connections.lock().unwrap();
-        for (addr, _) in conns.iter() {
-            trace!("Closing connection on {:?} in response to `quit` request", *addr);
-            self.on_connection_close(&conns, *addr);
-        }
-
-        conns.clear();
-        info!("Sent asynchronous close requests to all connections. Closing device manager");
-
-        // Send the server close signal
-        Some(self.cancel.send(())
-            .map_err(|_| Error::new(ErrorKind::ConnectionAborted, "Failed to send cancel signal")))
+        // NOTE: The connections are closed by the shutdown's drain phase (including the one that sent the `quit`)
+        self.shutdown.trigger(&format!("received `quit` request from {:?}", addr));
+        Some(Ok(()))
     }
 
     //
     // Server helper methods
     //
-    // TODO: Correctly implement this to clean out the whole cache
     fn on_connection_close(&self, conns: &HashMap<SocketAddr, Connection>, addr: SocketAddr) {
-        let _role_map = self.role_map.lock().unwrap();
+        // Stop routing any roles to the connection, promoting the next live connection for the role
+        // NOTE: Every role is checked in case the connection was registered under more than one
+        {
+            let mut role_map = self.role_map.lock().unwrap();
+            let roles = role_map.keys().cloned().collect::<Vec<_>>();
+            for role in roles {
+                remove_role_route(&mut role_map, &role, addr, conns);
+            }
+        }
 
-        if let Some(ref conn) = conns.get(&addr) {
-            // for role in &conn.roles {
-            //     role_map.get_vec_mut(role)
-            //         .and_then(|vec| vec.iter()
-            //             .position(|ad| *ad == addr)
-            //             .map(|e| vec.remove(e)));
-            // }
+        // Stop routing any uuids to the connection
+        self.uuid_map.lock().unwrap().retain(|uuid, ad| {
+            if *ad == addr {
+                debug!("Removing uuid {:?} from socket address {:?}", uuid, addr);
+            }
+            *ad != addr
+        });
 
-            conn.close.send(()).expect("Failed to send closing signal to communicator");
+        if let Some(ref conn) = conns.get(&addr) {
+            conn.close.trigger();
 
         } else {
             debug!("Couldn't close connection {:?}: Connection was not found in the connections map", addr);
         }
 
         // NOTE: We purposefully do not remove the connection from the connection map here
-        // TODO: This is an optimization for "quit", could we also get this optimization for the 'role_map'
-    }
-
-    // Resolve who sent the message
-    // TODO: I'm not sure why I need this (or how it's different from resolve_destination)
-    fn resolve_connection(&self, send: &message::MessageSender) -> Option<Option<SocketAddr>> {
-        let dest = send.clone().into();
-        self.resolve_destination(&dest)
+        // This is an optimization for "quit", which clears the whole map after closing every connection
     }
 
     // Resolve where the message is being requested to be directed
-    fn resolve_destination(&self, dest: &message::MessageDest) -> Option<Option<SocketAddr>> {
+    // Role destinations produce every provider of the role, ordered by the role's selection policy for `from`
+    // NOTE: Returns `None` when the message is for the device manager, and an empty list if nothing matched
+    fn resolve_destination(&self, dest: &message::MessageDest, from: Option<&SocketAddr>, route: &[IpAddr]) -> Option<Vec<SocketAddr>> {
         trace!("Resolving destination labels to sending socket address");
 
+        // Messages for other devices are sent over the link towards that device's manager
+        if let Some(ip) = dest.addr {
+            if !self.is_local_ip(&ip) {
+                if dest.intra_device.unwrap_or(false) {
+                    debug!("Refusing to route intra-device message to remote device {:?}", ip);
+                    return Some(Vec::new());
+                }
+
+                // NOTE: Devices that we don't know how to reach are assumed to be reachable through our parent
+                let link = self.links.lock().unwrap().route_to(&ip, route, &self.public_ip)
+                    .or_else(|| self.uplink.lock().unwrap().as_ref().and_then(|uplink| uplink.conn()));
+                match link {
+                    Some(link) => debug!("Resolved destination device {:?} to manager link {:?}", ip, link),
+                    None => debug!("Failed to resolve destination: No link to a manager on device {:?}", ip),
+                }
+                return Some(link.into_iter().collect());
+            }
+        }
+
         // If the specific app is specified, send it there
         if let Some(ref uuid) = dest.uuid {
             let uuid_map = self.uuid_map.lock().unwrap();
             if uuid_map.contains_key(uuid) {
-                return Some(uuid_map.get(uuid).map(|addr| addr.to_owned()));
+                return Some(uuid_map.get(uuid).map(|addr| addr.to_owned()).into_iter().collect());
             }
 
             debug!("Requested sending to uuid {:?} but no such application was found", uuid);
         }
 
-        // If the device IP is specified, send it there
-        // NOTE: This won't currently work, because we don't send things correctly
-        // if let Some(addr) = dest.addr {
-        //     if self.connections.lock().unwrap().contains_key(&addr) {
-        //         return addr.clone();
-        //     }
-        // }
-
         let role = dest.role.clone().unwrap_or(UNMATCHABLE_STRING.to_string());
         let dest = match role.as_str() {
             "manager" => None,
             "device" => None,
-            role => Some(self.role_map.lock().unwrap().get(role).map(|addr| addr.clone()))
+            role => {
+                // Fall back to the apps on other devices if no app on this device provides the role
+                let providers = self.select_providers(role, from);
+                if providers.is_empty() && !dest.intra_device.unwrap_or(false) {
+                    Some(self.links.lock().unwrap().providers_of(role, route))
+                } else {
+                    Some(providers)
+                }
+            },
         };
 
         // Log resolution status
         match dest {
-            Some(Some(addr)) => debug!("Resolved destination connection: {:?}", addr),
-            Some(None) => debug!("Failed to resolve destination: No connection registered for {:?}", role),
+            Some(ref addrs) if addrs.is_empty() => debug!("Failed to resolve destination: No connection registered for {:?}", role),
+            Some(ref addrs) => debug!("Resolved destination connections: {:?}", addrs),
             None => debug!("Resolved destination connection: device-manager"),
         }
 
         dest
     }
 
+    // The roles provided by the apps connected to this manager
+    fn local_roles(&self) -> Vec<String> {
+        let mut roles = self.role_map.lock().unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        roles.sort();
+        roles
+    }
+
+    fn is_local_ip(&self, ip: &IpAddr) -> bool {
+        *ip == self.public_ip || ip.is_loopback() || ip.is_unspecified()
+    }
+
+    // Order the live providers of the role according to its selection policy
+    // NOTE: Without a sender, providers are given in order of registration
+    fn select_providers(&self, role: &str, from: Option<&SocketAddr>) -> Vec<SocketAddr> {
+        let conns = self.connections.lock().unwrap();
+        let addrs = self.role_map.lock().unwrap()
+            .get_vec(role)
+            .map(|addrs| addrs.iter()
+                .filter(|addr| conns.contains_key(addr))
+                .cloned()
+                .collect::<Vec<_>>())
+            .unwrap_or(Vec::new());
+
+        let from = match from {
+            Some(from) if addrs.len() > 1 => from,
+            _ => return addrs,
+        };
+
+        let requests = self.requests.lock().unwrap();
+        let candidates = addrs.into_iter()
+            .map(|addr| {
+                let conn = &conns[&addr];
+                Candidate{
+                    addr: addr,
+                    priority: conn.priority,
+                    load: requests.load(&addr),
+                    last_active: conn.last_active,
+                }
+            })
+            .collect();
+
+        self.selector.lock().unwrap().order(role, from, candidates)
+    }
+
+    // Set the size of the seen message cache and the maximum number of routing steps a message may take
+    pub fn set_routing_limits(&self, seen_cache_size: usize, max_hops: usize) {
+        info!("Configured routing guard with a seen cache of {} messages and a hop limit of {}", seen_cache_size, max_hops);
+        self.guard.lock().unwrap().set_limits(seen_cache_size, max_hops);
+    }
+
+    // Set how long routed requests may wait on a response before they are failed
+    pub fn set_request_timeout(&self, timeout: Duration) {
+        info!("Configured request timeout of {:?}", timeout);
+        self.requests.lock().unwrap().set_timeout(timeout);
+    }
+
+    // Set the capacity and default overflow policy of the outgoing queues of new connections
+    pub fn set_queue_options(&self, capacity: usize, policy: OverflowPolicy) {
+        info!("Configured outgoing queues with capacity {} and {:?} overflow policy", capacity, policy);
+        let mut options = self.queue_options.lock().unwrap();
+        options.capacity = capacity;
+        options.policy = policy;
+    }
+
+    // Configure the heartbeats of new connections (an interval of 0 disables them)
+    pub fn set_heartbeat_options(&self, interval: Duration, misses: u32) {
+        let options = if interval == Duration::from_secs(0) {
+            info!("Disabled connection heartbeats");
+            None
+        } else {
+            info!("Configured connection heartbeats every {:?}, allowing {} misses", interval, misses);
+            Some(HeartbeatOptions{ interval: interval, misses: misses })
+        };
+        *self.heartbeat_options.lock().unwrap() = options;
+    }
+
+    // Fail every routed request that has waited on a response for too long
+    pub fn expire_requests(&self) {
+        let expired = self.requests.lock().unwrap().expire();
+        for pending in expired {
+            let description = format!("No response was received from {:?} before the request timed out", pending.dest);
+            self.fail_request(pending, message::ErrorCode::RequestTimeout, &description);
+        }
+    }
+
+    // Inform the sender of a routed request that it will never receive a response
+    fn fail_request(&self, pending: PendingRequest, code: message::ErrorCode, description: &str) {
+        info!("Failing request {:?} from {:?} with {:?}: {}", pending.request.message_id, pending.sender, code, description);
+
+        let reply = pending.request.error_reply(self.manager_sender(), code, description);
+        let sent = serde_json::to_value(reply)
+            .map_err(Error::from)
+            .and_then(|reply| self.send_to_connection(&pending.sender, reply));
+
+        if let Err(err) = sent {
+            debug!("Failed to send request failure to {:?}: {:?}", pending.sender, err);
+        }
+    }
+
+    // Set the selection policy for the role from the manager's configuration
+    pub fn set_auth_secret(&self, secret: &str) {
+        self.auth.lock().unwrap().set_secret(secret);
+    }
+
+    pub fn set_permission_default(&self, allow: bool) {
+        self.permissions.lock().unwrap().set_default(allow);
+    }
+
+    pub fn add_permission_rules(&self, spec: &str, allow: bool) -> Result<(), String> {
+        self.permissions.lock().unwrap().add_rules(spec, allow)
+    }
+
+    // Pick the address that a unix socket connection from the `peer` process is registered under
+    pub fn reserve_local_addr(&self, peer: PeerCredentials) -> Option<SocketAddr> {
+        let conns = self.connections.lock().unwrap();
+        self.local_peers.lock().unwrap().reserve(peer, |addr| conns.contains_key(addr))
+    }
+
+    pub fn set_role_policy(&self, role: &str, policy: SelectionPolicy) {
+        info!("Configured {:?} selection policy for role {:?}", policy, role);
+        self.selector.lock().unwrap().configure(role, policy);
+    }
+
     // Handle any server specific requests
     fn route_server_message(&mut self, mut msg: message::Message, addr: &SocketAddr) -> Result<(), Error> {
         trace!("Handling server request");
@@ -222,17 +672,33 @@ impl DeviceManager {
         // Dispatch the action into the registered handles
         let action = msg.action.clone().unwrap_or(UNMATCHABLE_STRING.to_string());
         let handles = handle_map.lock().unwrap();
-        if let Some(result) = handles.get(&action)
-            .and_then(|handle| handle(self, &mut msg, addr))
-        {
-            return result;
+        match handles.get(&action).map(|handle| handle(self, &mut msg, addr)) {
+            None => {
+                let description = format!("The device manager does not handle the {:?} action", msg.action);
+                return self.send_error(addr, &msg, message::ErrorCode::UnknownAction, &description);
+            },
+            Some(Some(Err(err))) => {
+                let code = match err.kind() {
+                    ErrorKind::InvalidInput => message::ErrorCode::MalformedArguments,
+                    _ => message::ErrorCode::HandlerFailed,
+                };
+                return self.send_error(addr, &msg, code, &err.to_string());
+            },
+            Some(Some(Ok(()))) => return Ok(()),
+            Some(None) => {},
+        }
+
+        // Pass the results on to the next step of the forwarding chain
+        if msg.is_forwardable() {
+            msg.forward_step(self.manager_sender());
+            debug!("Forwarding results of {:?} to {:?}", action, msg.dest);
+            return self.route_forwarded_message(msg, addr);
         }
 
         // Return the message to the sender
         msg.dest = msg.sender.clone().into();
-        if let Some(ref conn) = self.connections.lock().unwrap().get(&addr) {
-            conn.queue.unbounded_send(serde_json::to_value(msg)?)
-                .map_err(|_err| Error::new(ErrorKind::Other, "Failed to send message through pipe"))?;
+        if let Some(queue) = self.queue_of(addr) {
+            queue.send(serde_json::to_value(msg)?)?;
 
         } else if action != "stop" {
             debug!("Failed to send response to unrecognized address {:?}: {:?}", addr, msg);
@@ -240,10 +706,269 @@ impl DeviceManager {
         Ok(())
     }
 
+    // Route a message that was readdressed to the next step of its forwarding chain
+    // NOTE: Forwarded messages are responses, so they are never waited on
+    fn route_forwarded_message(&mut self, msg: message::Message, addr: &SocketAddr) -> Result<(), Error> {
+        match self.resolve_destination(&msg.dest, Some(addr), &msg.route) {
+            None => self.route_server_message(msg, addr),
+            Some(dests) => self.route_network_message(msg, dests, addr, false),
+        }
+    }
+
+    // Identify the app on the connection as the sender of messages sent on its behalf
+    fn connection_sender(&self, addr: &SocketAddr) -> message::MessageSender {
+        let conns = self.connections.lock().unwrap();
+        let conn = conns.get(addr);
+        let non_empty = |field: Option<&String>| field.filter(|field| !field.is_empty()).cloned();
+
+        message::MessageSender{
+            uuid: non_empty(conn.map(|conn| &conn.uuid)),
+            role: non_empty(conn.map(|conn| &conn.role)),
+            addr: Some(self.public_ip),
+        }
+    }
+
+    // Inform the sending connection that its message could not be handled
+    fn send_error(&self, addr: &SocketAddr, msg: &message::Message, code: message::ErrorCode, description: &str) -> Result<(), Error> {
+        // NOTE: Never respond to an error with another error, as that could bounce between two endpoints forever
+        if msg.action.as_ref().map_or(false, |action| action == "error") {
+            debug!("Dropping undeliverable error message from {:?}: {:?}", addr, msg);
+            return Ok(());
+        }
+
+        info!("Sending {:?} error to {:?} in response to message {:?}: {}", code, addr, msg.message_id, description);
+        let reply = msg.error_reply(self.manager_sender(), code, description);
+        self.send_to_connection(addr, serde_json::to_value(reply)?)
+    }
+
+    fn send_to_connection(&self, addr: &SocketAddr, msg: serde_json::Value) -> Result<(), Error> {
+        if let Some(queue) = self.queue_of(addr) {
+            queue.send(msg)?;
+
+        } else {
+            debug!("Failed to send message to unrecognized address {:?}: {:?}", addr, msg);
+        }
+        Ok(())
+    }
+
+    // Get the outgoing queue of the connection
+    // NOTE: Messages are never sent while holding the connections lock, so that routing never waits on a queue
+    fn queue_of(&self, addr: &SocketAddr) -> Option<Communicator> {
+        self.connections.lock().unwrap().get(addr).map(|conn| conn.queue.clone())
+    }
+
+    // Get the outgoing queues of every connection that passes the filter
+    fn queues_where<F: Fn(&SocketAddr, &Connection) -> bool>(&self, filter: F) -> Vec<(SocketAddr, Communicator)> {
+        self.connections.lock().unwrap().iter()
+            .filter(|&(addr, conn)| filter(addr, conn))
+            .map(|(addr, conn)| (*addr, conn.queue.clone()))
+            .collect()
+    }
+
+    // Broadcast a notification that the set of available actions has changed
+    fn notify_capabilities_changed(&self, event: &str, role: &str, uuid: &str, actions: Vec<String>, skip: Option<&SocketAddr>) {
+        let mut dest = message::MessageDest::default();
+        dest.broadcast = Some(true);
+
+        let mut msg = message::Message::new(self.manager_sender(), dest);
+        msg.action = Some("capabilities.changed".to_string());
+        msg.args = Some(vec![json!({
+            "event": event,
+            "role": role,
+            "uuid": uuid,
+            "actions": actions,
+        })]);
+
+        let msg = match serde_json::to_value(msg) {
+            Ok(msg) => msg,
+            Err(err) => return error!("Failed to serialize capabilities notification: {:?}", err),
+        };
+
+        // NOTE: Other managers aren't notified, as the actions are only available to apps on this device
+        debug!("Notifying connections that {:?} {} with actions {:?}", role, event, actions);
+        for (conn_addr, queue) in self.queues_where(|conn_addr, conn| Some(conn_addr) != skip && !conn.linked) {
+            if let Err(err) = queue.send(msg.clone()) {
+                debug!("Failed to send capabilities notification to {:?}: {:?}", conn_addr, err);
+            }
+        }
+    }
+
+    // Start a link with the device manager on the other side of the connection
+    pub fn link_manager(&self, conn: SocketAddr) {
+        info!("Sending link handshake to device manager at {:?}", conn);
+
+        let mut dest = message::MessageDest::default();
+        dest.role = Some("manager".to_string());
+
+        let mut msg = message::Message::new(self.manager_sender(), dest);
+        msg.action = Some("handshake".to_string());
+        msg.body = Some(json!({
+            "link": "manager",
+            "device_id": self.device_id,
+            "roles": self.local_roles(),
+            "protocol": VersionRange::supported().to_json(),
+            "heartbeat": true,
+        }));
+
+        // NOTE: Linked managers are expected to share our secret, so they can check the token we sign for ourselves
+        if let Some(token) = self.auth.lock().unwrap().token("manager") {
+            if let Some(body) = msg.body.as_mut().and_then(|body| body.as_object_mut()) {
+                body.insert("auth".to_string(), json!({ "token": token }));
+            }
+        }
+
+        if let Some(conn) = self.connections.lock().unwrap().get_mut(&conn) {
+            conn.linked = true;
+        }
+
+        let sent = serde_json::to_value(msg)
+            .map_err(Error::from)
+            .and_then(|msg| self.send_to_connection(&conn, msg));
+        if let Err(err) = sent {
+            error!("Failed to send link handshake to {:?}: {:?}", conn, err);
+        }
+    }
+
+    // Re-send our link handshake to every linked manager, so they know which roles they can reach through us
+    fn announce_roles(&self) {
+        let linked = self.connections.lock().unwrap().iter()
+            .filter(|&(_, conn)| conn.linked)
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        for conn in linked {
+            self.link_manager(conn);
+        }
+    }
+
+    // Configure the parent device manager that we keep a link to
+    pub fn set_parent(&self, parent: SocketAddr, buffer_size: usize) {
+        info!("Configured parent device manager at {:?}", parent);
+        *self.uplink.lock().unwrap() = Some(Uplink::new(parent, buffer_size));
+    }
+
+    pub fn uplink_connecting(&self) {
+        if let Some(ref mut uplink) = *self.uplink.lock().unwrap() {
+            uplink.connecting();
+        }
+    }
+
+    pub fn uplink_failed(&self) {
+        if let Some(ref mut uplink) = *self.uplink.lock().unwrap() {
+            uplink.failed();
+        }
+    }
+
+    // Link with the parent on the new connection and send it everything that was waiting on the link
+    // Returns a signal that resolves once the connection to the parent closes
+    pub fn uplink_connected(&self, conn: SocketAddr) -> Option<oneshot::Receiver<()>> {
+        self.link_manager(conn);
+
+        let (closed, buffered) = match *self.uplink.lock().unwrap() {
+            Some(ref mut uplink) => (uplink.connected(conn), uplink.drain()),
+            None => return None,
+        };
+
+        info!("Sending {} buffered messages to the parent device manager", buffered.len());
+        for msg in buffered {
+            if let Err(err) = self.send_to_connection(&conn, msg) {
+                error!("Failed to send buffered message to the parent device manager: {:?}", err);
+            }
+        }
+        Some(closed)
+    }
+
+    // Register the device manager that sent the link handshake, answering with our own handshake if needed
+    fn register_link(&mut self, msg: &message::Message, addr: &SocketAddr) -> CallbackResult {
+        let remote = match msg.sender.addr {
+            Some(remote) => remote,
+            None => return malformed_args("Link handshake must give the manager's `sender.addr`"),
+        };
+        let device_id = msg.body.as_ref()
+            .and_then(|body| body.get("device_id"))
+            .and_then(|id| id.as_str())
+            .unwrap_or("")
+            .to_string();
+
+        let roles = msg.body.as_ref()
+            .and_then(|body| body.get("roles"))
+            .and_then(|roles| serde_json::from_value::<Vec<String>>(roles.clone()).ok())
+            .unwrap_or(Vec::new());
+
+        info!("Linking device manager {:?} (device {:?}) with roles {:?} through {:?}", remote, device_id, roles, addr);
+        self.links.lock().unwrap().insert(remote, *addr, device_id, roles);
+
+        let linked = self.connections.lock().unwrap().get(addr).map_or(true, |conn| conn.linked);
+        if !linked {
+            self.link_manager(*addr);
+        }
+        Some(Ok(()))
+    }
+
+    // Send every connection a close notice, closing it once its outgoing queue has been flushed
+    // NOTE: Apps are sent a `quit` and linked managers a `stop`, so that we never shut down another device
+    pub fn drain_connections(&self) -> usize {
+        let conns = self.connections.lock().unwrap().drain().collect::<Vec<_>>();
+        let mut draining = self.draining.lock().unwrap();
+
+        for (addr, conn) in conns {
+            let mut dest = message::MessageDest::default();
+            dest.uuid = Some(conn.uuid.clone()).filter(|uuid| !uuid.is_empty());
+
+            let mut notice = message::Message::new(self.manager_sender(), dest);
+            notice.action = Some(if conn.linked { "stop" } else { "quit" }.to_string());
+
+            trace!("Sending close notice to {:?}", addr);
+            let sent = serde_json::to_value(notice)
+                .map_err(Error::from)
+                .and_then(|notice| conn.queue.send(notice));
+            if let Err(err) = sent {
+                debug!("Failed to send close notice to {:?}: {:?}", addr, err);
+            }
+
+            // NOTE: Closing the queue lets the writer finish once the queued messages have been sent
+            conn.queue.close();
+            draining.insert(addr, conn.close.clone());
+        }
+
+        if draining.is_empty() {
+            self.shutdown.drained();
+        }
+        draining.len()
+    }
+
+    // Close every remaining connection immediately, without waiting on their outgoing queues
+    pub fn close_connections(&self) {
+        for (addr, close) in self.draining.lock().unwrap().drain() {
+            debug!("Forcibly closing connection to {:?}", addr);
+            close.trigger();
+        }
+
+        for (addr, conn) in self.connections.lock().unwrap().drain() {
+            debug!("Forcibly closing connection to {:?}", addr);
+            conn.close.trigger();
+        }
+        self.shutdown.drained();
+    }
+
+    // Identify the device manager as the sender of messages it creates
+    fn manager_sender(&self) -> message::MessageSender {
+        message::MessageSender{
+            uuid: None,
+            role: Some("manager".to_string()),
+            addr: Some(self.public_ip),
+        }
+    }
+
     // Handle routing the message to the requested destination
-    fn route_network_message(&mut self, msg: message::Message, dest: Option<SocketAddr>) -> Result<(), Error> {
+    // NOTE: `expects_reply` indicates whether the sender should be told if the message never gets a response
+    fn route_network_message(&mut self, mut msg: message::Message, dests: Vec<SocketAddr>, addr: &SocketAddr, expects_reply: bool) -> Result<(), Error> {
         trace!("Sending the message to another modality");
 
+        // The ack (if requested) is split off from the message, so the destination never sees the request for it
+        let acked = msg.clone();
+        msg.ack_uuid = None;
+
         if !msg.dest.broadcast.unwrap_or(false) {
             // NOTE: If we want to turn the `dest` field into an array, we must instead push the queues onto a vector, ala.
             // let mut send_queue = Vec::new();
@@ -251,60 +976,191 @@ impl DeviceManager {
 
             debug!("Routing the message according to it's `dest` field");
 
-            // Add the specified destination device to the queue
-            if let Some(dest) = dest {
-                let conns = self.connections.lock().unwrap();
-                if let Some(ref conn) = conns.get(&dest) {
-                    debug!("Sending message to {:?}", dest);
-                    conn.queue.unbounded_send(serde_json::to_value(msg.clone())?)
-                        .map_err(|_err| Error::new(ErrorKind::Other, "Failed to send message through pipe"))?;
-
-                    // Send an ack message to the original sender if desired
-                    if let Some(Some(sender)) = self.resolve_connection(&msg.sender) {
-                        if sender != dest {
-                            debug!("The receiving app was not the same as the sending message. Sending ack message to {:?}", sender);
-
-                            if let Some(ref conn) = conns.get(&sender) {
-                                let mut msg = msg.clone();
-                                msg.action = Some("ack".to_string());
-                                conn.queue.unbounded_send(serde_json::to_value(msg)?)
-                                    .map_err(|_err| Error::new(ErrorKind::Other, "Failed to send message through pipe"))?;
-
-                            } else {
-                                debug!("Failed to send ack message to unknown address {:?}: {:?}", sender, msg);
-                            }
+            // Send the message to the most preferred destination, failing over to the next one if it has gone away
+            let packet = serde_json::to_value(msg.clone())?;
+            let delivered = {
+                let mut delivered = None;
+                for dest in dests {
+                    if let Some(queue) = self.queue_of(&dest) {
+                        debug!("Sending message to {:?}", dest);
+                        match queue.send(packet.clone()) {
+                            Ok(()) => {
+                                if expects_reply {
+                                    self.requests.lock().unwrap().track(&msg, *addr, dest);
+                                }
+                                delivered = Some(dest);
+                                break;
+                            },
+                            Err(err) => info!("Failed to send message to {:?}: {:?}. Failing over to the next provider", dest, err),
                         }
+
+                    } else {
+                        debug!("Failed to send message to unknown address {:?}: {:?}", dest, msg);
                     }
+                }
+                delivered
+            };
 
-                } else {
-                    debug!("Failed to send message to unknown address {:?}: {:?}", dest, msg);
+            match delivered {
+                Some(dest) => self.send_ack(&acked, message::AckStatus::Delivered, Some(dest), addr)?,
+                None if self.buffer_upstream(&msg)? => {},
+                None => {
+                    self.send_ack(&acked, message::AckStatus::Undeliverable, None, addr)?;
+
+                    let description = format!("No connection is registered for destination {:?}", msg.dest);
+                    return self.send_error(addr, &msg, message::ErrorCode::UnknownDestination, &description);
                 }
-            }
+            };
 
         // Otherwise send a broadcast message to all connections
+        // NOTE: Broadcasts are never acked, as the `ack_uuid` app receives the message anyways
+        // NOTE: Broadcasts are only sent on to the managers that they haven't passed through yet
         } else {
+            let (links, unvisited) = {
+                let links = self.links.lock().unwrap();
+                let conns = self.connections.lock().unwrap();
+                let all = conns.keys().filter(|conn| links.is_link(conn)).cloned().collect::<Vec<_>>();
+                (all, links.unvisited(&msg.route))
+            };
             let msg = serde_json::to_value(msg)?;
 
             debug!("Performing broadcast of {:?} to all registered modalities", msg);
-            for (_, ref conn) in self.connections.lock().unwrap().iter() {
-                conn.queue.unbounded_send(msg.clone())
-                    .map_err(|_err| Error::new(ErrorKind::Other, "Failed to send message through pipe"))?;
+            let queues = self.queues_where(|conn_addr, _| !links.contains(conn_addr) || unvisited.contains(conn_addr));
+            for (conn_addr, queue) in queues {
+                // NOTE: A single slow consumer shouldn't keep the broadcast from reaching everyone else
+                if let Err(err) = queue.send(msg.clone()) {
+                    info!("Failed to send broadcast to {:?}: {:?}", conn_addr, err);
+                }
             }
         }
 
         Ok(())
     }
 
+    // Hold on to a message for another device while the link to our parent is down, returning whether it was buffered
+    fn buffer_upstream(&self, msg: &message::Message) -> Result<bool, Error> {
+        let upstream = !msg.dest.intra_device.unwrap_or(false)
+            && msg.dest.addr.map_or(false, |ip| !self.is_local_ip(&ip));
+
+        let mut uplink = self.uplink.lock().unwrap();
+        match *uplink {
+            Some(ref mut uplink) if upstream && !uplink.is_connected() => {
+                debug!("Buffering message {:?} until the link to our parent is re-established", msg.message_id);
+                uplink.buffer(serde_json::to_value(msg)?);
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    // Send an ack to the app requested in the message's `ack_uuid` (if any), reporting whether it was delivered
+    // NOTE: No ack is sent when the `ack_uuid` app is the one that the message was delivered to
+    fn send_ack(&self, msg: &message::Message, status: message::AckStatus, delivered_to: Option<SocketAddr>, addr: &SocketAddr) -> Result<(), Error> {
+        let ack_uuid = match msg.ack_uuid {
+            Some(ref ack_uuid) => ack_uuid,
+            None => return Ok(()),
+        };
+
+        let target = self.uuid_map.lock().unwrap().get(ack_uuid).cloned();
+        if target.is_some() && target == delivered_to {
+            debug!("Not splitting ack for message {:?}: The `ack_uuid` app received the message", msg.message_id);
+            return Ok(());
+        }
+
+        // Inform the sender if the ack could not be delivered
+        let sent = match target {
+            Some(target) => {
+                debug!("Sending {:?} ack for message {:?} to {:?}", status, msg.message_id, target);
+                let ack = serde_json::to_value(msg.ack(self.manager_sender(), status))?;
+                self.queue_of(&target).map_or(false, |queue| queue.send(ack).is_ok())
+            },
+            None => false,
+        };
+
+        if !sent {
+            warn!("Failed to deliver ack for message {:?} to app {:?}", msg.message_id, ack_uuid);
+            let description = format!("Failed to deliver the requested ack to app {:?}", ack_uuid);
+            return self.send_error(addr, msg, message::ErrorCode::AckUndeliverable, &description);
+        }
+        Ok(())
+    }
+
     pub fn get_index(&self) -> &idx::Index {
         &self.index
     }
+
+    pub fn get_schedule(&self) -> &CrawlSchedule {
+        &self.schedule
+    }
+
+    pub fn get_shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
 }
 
 impl networking::BasicServer for DeviceManager {
-    fn handle_request(&mut self, msg: serde_json::Value, addr: &SocketAddr) -> Result<(), Error> {
-        let mut msg: message::Message = serde_json::from_value(msg)?;
+    fn handle_request(&mut self, mut msg: serde_json::Value, addr: &SocketAddr) -> Result<(), Error> {
+        // Bring messages from apps that speak an older protocol up to date
+        // NOTE: Connections speak the legacy protocol until their handshake says otherwise
+        let version = self.connections.lock().unwrap().get(addr).map_or(protocol::LEGACY_VERSION, |conn| conn.protocol);
+        protocol::upgrade(&mut msg, version);
+
+        // Inform the sender if the message doesn't follow the protocol, rather than dropping the connection
+        let message_id = msg.get("message_id")
+            .and_then(|id| id.as_str())
+            .map(|id| id.to_string());
+        let mut msg: message::Message = match serde_json::from_value(msg) {
+            Ok(msg) => msg,
+            Err(err) => {
+                info!("Received malformed message from {:?}: {:?}", addr, err);
+                let description = format!("Message does not follow protocol version {}: {}", version, err);
+                let reply = message::Message::error(self.manager_sender(), message_id, message::ErrorCode::MalformedMessage, &description);
+                return self.send_to_connection(addr, serde_json::to_value(reply)?);
+            }
+        };
         debug!("Parsed message {:?}", msg);
 
+        // Connections may only handshake until they have authenticated
+        // NOTE: Apps are only held to the rules for their uuid once they have proven it
+        let (authenticated, trusted, role, uuid) = match self.connections.lock().unwrap().get(addr) {
+            Some(conn) if conn.linked => (conn.authenticated, conn.trusted, "manager".to_string(), String::new()),
+            Some(conn) if conn.uuid_verified => (conn.authenticated, conn.trusted, conn.role.clone(), conn.uuid.clone()),
+            Some(conn) => (conn.authenticated, conn.trusted, conn.role.clone(), String::new()),
+            None => (false, false, String::new(), String::new()),
+        };
+        if !authenticated && msg.action.as_ref().map_or(true, |action| action != "handshake") {
+            warn!("Rejecting message {:?} from unauthenticated connection {:?}", msg.message_id, addr);
+            return self.send_error(addr, &msg, message::ErrorCode::Unauthenticated, "Connection must authenticate in its handshake first");
+        }
+
+        // Any traffic means the device is in use, so hold off on any crawls that wait for it to be idle
+        self.schedule.touch();
+
+        // Make sure the message isn't caught in a routing loop before going any further
+        let verdict = self.guard.lock().unwrap().check(&msg, addr, &self.public_ip);
+        match verdict {
+            Verdict::Accept => {},
+            Verdict::Duplicate => {
+                info!("Dropping duplicate message {:?} from {:?}", msg.message_id, addr);
+                return Ok(());
+            },
+            Verdict::HopLimit(hops) => {
+                warn!("Dropping message {:?} from {:?} after {} routing steps", msg.message_id, addr, hops);
+                let description = format!("Message exceeded the hop limit after {} routing steps", hops);
+                return self.send_error(addr, &msg, message::ErrorCode::HopLimitExceeded, &description);
+            },
+            Verdict::Revisit => {
+                warn!("Dropping message {:?} from {:?} which revisited this manager: route={:?}", msg.message_id, addr, msg.route);
+                let description = format!("Message revisited the device manager at {:?}", self.public_ip);
+                return self.send_error(addr, &msg, message::ErrorCode::RoutingLoop, &description);
+            },
+            Verdict::PingPong(a, b) => {
+                warn!("Dropping message {:?} which is bouncing between {:?} and {:?}", msg.message_id, a, b);
+                let description = format!("Message is bouncing between {:?} and {:?}", a, b);
+                return self.send_error(addr, &msg, message::ErrorCode::RoutingLoop, &description);
+            },
+        }
+
         // 1) Append the current device addr to the route array
         // 2) Set the sender's addr value if not already set
         msg.route.push(self.public_ip);
@@ -313,64 +1169,276 @@ impl networking::BasicServer for DeviceManager {
         }
         trace!("Appended required sender data");
 
+        // Keep track of how responsive the sending app is (for role selection policies)
+        if let Some(conn) = self.connections.lock().unwrap().get_mut(addr) {
+            conn.last_active = Instant::now();
+        }
+
+        // Stop waiting on any request that this message answers
+        let answers_request = self.requests.lock().unwrap().resolve(&msg, addr).is_some();
+
+        // Make sure the app is allowed to send the message
+        // NOTE: Replies to a request are always allowed, as are messages from authenticated links (which applied their
+        // Own policy). Messages over any other link are checked as coming from the `manager` role
+        if !answers_request && !trusted {
+            if let Err(reason) = self.permissions.lock().unwrap().check(&role, &uuid, &msg) {
+                warn!("Denying message {:?} from {:?}: {}", msg.message_id, addr, reason);
+                return self.send_error(addr, &msg, message::ErrorCode::PermissionDenied, &reason);
+            }
+        }
+
+        // Responses marked for forwarding are sent on to the next target on behalf of the responding app
+        if msg.is_forwardable() {
+            let sender = self.connection_sender(addr);
+            msg.forward_step(sender);
+            debug!("Forwarding response from {:?} to {:?}", addr, msg.dest);
+            return self.route_forwarded_message(msg, addr);
+        }
+
+        let expects_reply = !answers_request && msg.wants_reply();
+
         // Handle the message as requested by the sender
-        match self.resolve_destination(&msg.dest) {
+        match self.resolve_destination(&msg.dest, Some(addr), &msg.route) {
             None => self.route_server_message(msg, addr)?,
-            Some(dest) => self.route_network_message(msg, dest)?
+            Some(_) if !authenticated => {
+                warn!("Rejecting handshake {:?} from unauthenticated connection {:?} to {:?}", msg.message_id, addr, msg.dest);
+                self.send_error(addr, &msg, message::ErrorCode::Unauthenticated, "Connection must authenticate in its handshake first")?
+            },
+            Some(dests) => self.route_network_message(msg, dests, addr, expects_reply)?
         };
 
         Ok(())
     }
 
-    // TODO: Why do we have this method?
-    #[allow(unused_variables, unused_mut)]
+    // Bring messages down to the protocol version spoken by the connection they're written to
     fn handle_response(&mut self, mut msg: serde_json::Value, addr: &SocketAddr) -> serde_json::Value {
+        let version = self.connections.lock().unwrap().get(addr).map_or(protocol::LEGACY_VERSION, |conn| conn.protocol);
+        protocol::downgrade(&mut msg, version);
         msg
     }
 
     fn add_connection(&self, addr: SocketAddr, close_signal: Closer, write_signal: Communicator) -> Result<(), Error> {
         trace!("Adding connection to {:?}", addr);
         let mut conns = self.connections.lock().unwrap();
-        conns.insert(addr, Connection::new(addr.clone(), close_signal, write_signal));
+        let mut conn = Connection::new(close_signal, write_signal);
+        conn.authenticated = !self.auth.lock().unwrap().is_enabled();
+        conn.peer = self.local_peers.lock().unwrap().claim(&addr);
+        conns.insert(addr, conn);
         info!("Added connection to {:?}", addr);
         Ok(())
     }
 
+    fn queue_options(&self) -> QueueOptions {
+        *self.queue_options.lock().unwrap()
+    }
+
+    fn heartbeat_options(&self) -> Option<HeartbeatOptions> {
+        *self.heartbeat_options.lock().unwrap()
+    }
+
+    fn watch_connection(&self, addr: SocketAddr, heartbeat: Heartbeat) {
+        if let Some(conn) = self.connections.lock().unwrap().get_mut(&addr) {
+            conn.heartbeat = Some(heartbeat);
+        }
+    }
+
+    // Let everyone else know that the connection's app stopped responding (the connection is dropped afterwards)
+    fn connection_lost(&mut self, addr: SocketAddr) {
+        let lost = self.connections.lock().unwrap().get(&addr)
+            .map(|conn| json!({
+                "addr": addr.to_string(),
+                "role": conn.role,
+                "uuid": conn.uuid,
+                "heartbeat": conn.heartbeat.as_ref().map(|heartbeat| heartbeat.to_json()),
+            }));
+        let lost = match lost {
+            Some(lost) => lost,
+            None => return,
+        };
+
+        let mut dest = message::MessageDest::default();
+        dest.broadcast = Some(true);
+
+        let mut msg = message::Message::new(self.manager_sender(), dest);
+        msg.action = Some("connection.lost".to_string());
+        msg.args = Some(vec![lost]);
+
+        let msg = match serde_json::to_value(msg) {
+            Ok(msg) => msg,
+            Err(err) => return error!("Failed to serialize connection lost notification: {:?}", err),
+        };
+
+        // NOTE: Other managers aren't notified, as they only route to the connection through us
+        info!("Notifying connections that {:?} was lost", addr);
+        for (conn_addr, queue) in self.queues_where(|conn_addr, conn| *conn_addr != addr && !conn.linked) {
+            if let Err(err) = queue.send(msg.clone()) {
+                debug!("Failed to send connection lost notification to {:?}: {:?}", conn_addr, err);
+            }
+        }
+    }
+
     // TODO: Change the return type of this to `Result<(), Error>`
     fn drop_connection(&mut self, addr: SocketAddr) {
         trace!("Dropping connection to {:?}", addr);
-        let mut conns = self.connections.lock().unwrap();
-        self.on_connection_close(&conns, addr);
-        conns.remove(&addr);
+        let roles_before = self.local_roles();
+        let closed = {
+            let mut conns = self.connections.lock().unwrap();
+            self.on_connection_close(&conns, addr);
+            conns.remove(&addr)
+        };
         info!("Dropped connection to {:?}", addr);
+
+        // Finish the shutdown's drain phase once the last connection has closed
+        {
+            let mut draining = self.draining.lock().unwrap();
+            if draining.remove(&addr).is_some() && draining.is_empty() {
+                self.shutdown.drained();
+            }
+        }
+
+        // Stop routing messages for other devices through the connection
+        for remote in self.links.lock().unwrap().remove_conn(&addr) {
+            info!("Lost link to device manager {:?}", remote);
+        }
+        if let Some(ref mut uplink) = *self.uplink.lock().unwrap() {
+            uplink.disconnected(&addr);
+        }
+
+        // Let the linked managers know that they can no longer reach the connection's role through us
+        if self.local_roles() != roles_before {
+            self.announce_roles();
+        }
+
+        // Nothing that was waiting on the connection will ever receive a response
+        let failed = self.requests.lock().unwrap().close_connection(&addr);
+        for pending in failed {
+            let description = format!("The connection to {:?} closed before it responded", addr);
+            self.fail_request(pending, message::ErrorCode::DestinationClosed, &description);
+        }
+
+        // Let everyone else know that the connection's actions are no longer available
+        let removed = self.capabilities.lock().unwrap().unregister(&addr);
+        if let Some(conn) = closed {
+            if !removed.is_empty() {
+                let actions = removed.into_iter().map(|(_, action)| action).collect();
+                self.notify_capabilities_changed("left", &conn.role, &conn.uuid, actions, None);
+            }
+        }
     }
 }
 
 // Structure to encapsulate connection state for storage
 struct Connection {
-    pub addr: SocketAddr,
     pub close: Closer,
     pub queue: Communicator,
     pub role: String,
-    pub uuid: String
+    pub uuid: String,
+
+    // Routing state used by the role selection policies
+    pub priority: i64,
+    pub last_active: Instant,
+
+    // Whether we have sent a link handshake over the connection (ie. it leads to another manager)
+    pub linked: bool,
+
+    // Whether the connection has given valid credentials (always true when authentication is disabled)
+    pub authenticated: bool,
+
+    // Whether the app proved the uuid it registered with (so rules for that app apply to it)
+    pub uuid_verified: bool,
+
+    // Whether the connection is a link that authenticated as a manager (so its messages skip our permission policy)
+    pub trusted: bool,
+
+    // The process on the other end of the connection (for unix socket connections)
+    pub peer: Option<PeerCredentials>,
+
+    // When the app was last heard from (when heartbeats are enabled)
+    pub heartbeat: Option<Heartbeat>,
+
+    // The protocol version settled on in the handshake
+    pub protocol: u32,
 }
 
 impl Connection {
-    pub fn new(addr: SocketAddr, close: Closer, queue: Communicator) -> Self {
+    pub fn new(close: Closer, queue: Communicator) -> Self {
         Self{
-            addr: addr,
             close: close,
             queue: queue,
             role: "".to_string(),
             uuid: "".to_string(),
+            priority: 0,
+            last_active: Instant::now(),
+            linked: false,
+            authenticated: true,
+            uuid_verified: false,
+            trusted: false,
+            peer: None,
+            heartbeat: None,
+            protocol: protocol::LEGACY_VERSION,
         }
     }
 }
 
 // Types for the device callback functions
+// NOTE: Returning an error sends an `error` reply to the sender (`ErrorKind::InvalidInput` marks bad arguments)
 type CallbackResult = Option<Result<(), Error>>;        // Some(_) indicates return early with the result
 type DeviceCallback = fn(&mut DeviceManager, &mut message::Message, addr: &SocketAddr) -> CallbackResult;
 
+// Remove `addr` from the connections registered to `role`, dropping any that are no longer alive
+// NOTE: The first address for a role is the one that messages get routed to, so removing it "promotes" the next one
+fn remove_role_route(role_map: &mut MultiMap<String, SocketAddr>, role: &str, addr: SocketAddr, conns: &HashMap<SocketAddr, Connection>) {
+    let now_empty = match role_map.get_vec_mut(role) {
+        Some(addrs) => {
+            let primary = addrs.first().cloned();
+            addrs.retain(|ad| *ad != addr && conns.contains_key(ad));
+
+            match addrs.first() {
+                Some(next) if Some(*next) != primary => info!("Promoted {:?} to handle role {:?}", next, role),
+                _ => {}
+            }
+            addrs.is_empty()
+        },
+        None => false,
+    };
+
+    if now_empty {
+        info!("No connections remain for role {:?}", role);
+        role_map.remove(role);
+    }
+}
+
+// Describe the actions handled by the device manager itself
+fn manager_actions() -> HashMap<String, capabilities::ActionInfo> {
+    let describe = |help: &str, args: &[&str]| capabilities::ActionInfo{
+        help: Some(help.to_string()),
+        args: args.iter()
+            .map(|arg| capabilities::ArgInfo{ name: arg.to_string(), help: None })
+            .collect(),
+    };
+
+    let mut actions = HashMap::new();
+    actions.insert("handshake".to_string(), describe("Register the app's role, uuid, and actions with the device manager", &[]));
+    actions.insert("search".to_string(), describe("Search the device's file system index", &["query"]));
+    actions.insert("schedule".to_string(), describe("Report the crawl schedule for every index root", &[]));
+    actions.insert("reindex".to_string(), describe("Queue folders for reindexing (defaults to every index root)", &["folder..."]));
+    actions.insert("capabilities".to_string(), describe("Report the actions registered for every role", &["role?"]));
+    actions.insert("diagnostics".to_string(), describe("Report the device manager's routing counters", &[]));
+    actions.insert("links".to_string(), describe("Report the device managers linked to this one", &[]));
+    actions.insert("stop".to_string(), describe("Close the sending app's connection", &[]));
+    actions.insert("quit".to_string(), describe("Shut down the device manager and all connected apps", &[]));
+    actions
+}
+
+fn malformed_args(description: &str) -> CallbackResult {
+    Some(Err(Error::new(ErrorKind::InvalidInput, description)))
+}
+
 // NOTE: This is used to get around the borrow checker when matching against the `message` structs
 // For some reason, the borrow checker wouldn't allow me to transform an `Option<String>` into an `Option<&str>` temporarily
 const UNMATCHABLE_STRING: &'static str = "DO_NOT_MATCH_THIS_STRING";
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 15;
+pub const DEFAULT_HEARTBEAT_MISSES: u32 = 3;
+pub const DEFAULT_SEEN_CACHE_SIZE: usize = 4096;
+pub const DEFAULT_MAX_HOPS: usize = 16;
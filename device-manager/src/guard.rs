@@ -0,0 +1,257 @@
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use message::{Message, MessageDest, MessageSender};
+
+/*
+The routing guard protects the network from messages that would otherwise circulate forever. Every message
+Received by the manager is checked before it is routed, and is dropped if:
+
+  duplicate    the exact same message (id, sender, action, and destination) already arrived over any connection
+  hop limit    the message has passed through more routing steps than allowed (see `--max-hops`)
+  revisit      the message has come back to this manager after being routed to another device
+  ping-pong    the message keeps bouncing back and forth between the same two connections
+
+Messages are remembered in a bounded cache of recently seen `message_id`s, along with the app that first sent
+Them. Replies that reuse the id of the message they answer (ie. through `return_to_sender`) are still allowed,
+As they are addressed elsewhere or carry a response. Replies are expected to travel back through the managers that routed the
+Request, so they are never counted as revisits, but only while they're addressed back to the request's sender
+(forwarded responses are checked like any other message).
+*/
+
+// How many times a message may alternate between two connections before it's considered a ping-pong
+const PING_PONG_LIMIT: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Accept,
+    Duplicate,
+    HopLimit(usize),
+    Revisit,
+    PingPong(SocketAddr, SocketAddr),
+}
+
+// Counters for diagnosing routing problems
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RoutingStats {
+    pub accepted: u64,
+    pub duplicates: u64,
+    pub hop_limit: u64,
+    pub revisits: u64,
+    pub ping_pongs: u64,
+}
+
+// What tells apart the messages sharing a `message_id`, whichever connection or route they arrived through
+// NOTE: The sender's addr is left out as it's only filled in once the message reaches its first manager
+#[derive(PartialEq)]
+struct Variant {
+    origin: (Option<String>, Option<String>),
+    action: Option<String>,
+    dest: MessageDest,
+    replying: bool,
+}
+
+impl<'a> From<&'a Message> for Variant {
+    fn from(msg: &'a Message) -> Self {
+        Self{
+            origin: (msg.sender.uuid.clone(), msg.sender.role.clone()),
+            action: msg.action.clone(),
+            dest: msg.dest.clone(),
+            replying: msg.resp.is_some(),
+        }
+    }
+}
+
+// A single arrival of a message at the manager
+struct Arrival {
+    from: SocketAddr,
+    variant: Variant,
+}
+
+struct Seen {
+    first_seen: Instant,
+    arrivals: Vec<Arrival>,
+
+    // The app that sent the message when it first arrived
+    origin: MessageSender,
+}
+
+pub struct RoutingGuard {
+    seen: HashMap<String, Seen>,
+    order: VecDeque<String>,
+    capacity: usize,
+    expiry: Duration,
+    max_hops: usize,
+    stats: RoutingStats,
+}
+
+impl RoutingGuard {
+    pub fn new(capacity: usize, max_hops: usize) -> Self {
+        Self{
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity,
+            expiry: Duration::from_secs(300),
+            max_hops: max_hops,
+            stats: RoutingStats::default(),
+        }
+    }
+
+    pub fn set_limits(&mut self, capacity: usize, max_hops: usize) {
+        self.capacity = capacity;
+        self.max_hops = max_hops;
+        self.evict();
+    }
+
+    pub fn stats(&self) -> &RoutingStats {
+        &self.stats
+    }
+
+    // Decide whether the message received from `from` should be routed by the manager at `me`
+    // NOTE: This must be called before `me` is appended to the message's route
+    pub fn check(&mut self, msg: &Message, from: &SocketAddr, me: &IpAddr) -> Verdict {
+        let verdict = self.judge(msg, from, me);
+        match verdict {
+            Verdict::Accept => self.stats.accepted += 1,
+            Verdict::Duplicate => self.stats.duplicates += 1,
+            Verdict::HopLimit(_) => self.stats.hop_limit += 1,
+            Verdict::Revisit => self.stats.revisits += 1,
+            Verdict::PingPong(_, _) => self.stats.ping_pongs += 1,
+        }
+        verdict
+    }
+
+    fn judge(&mut self, msg: &Message, from: &SocketAddr, me: &IpAddr) -> Verdict {
+        let hops = msg.route.len();
+        if hops >= self.max_hops {
+            return Verdict::HopLimit(hops);
+        }
+
+        // A message revisits this manager if we show up earlier in its route
+        // NOTE: Replies returning to the sender of their request are expected to travel back through us
+        let returning = msg.parent_id.iter()
+            .chain(Some(&msg.message_id))
+            .filter_map(|id| self.seen.get(id))
+            .any(|seen| addressed_to(&msg.dest, &seen.origin));
+        let earlier_route = &msg.route[..hops.saturating_sub(1)];
+        if !returning && earlier_route.contains(me) {
+            return Verdict::Revisit;
+        }
+
+        // Record the arrival, checking it against the earlier arrivals of the same message
+        // NOTE: Broadcasts can reach us over several links, only the first copy is routed
+        let arrival = Arrival{ from: *from, variant: Variant::from(msg) };
+        let verdict = match self.seen.get_mut(&msg.message_id) {
+            Some(seen) => {
+                if seen.arrivals.iter().any(|earlier| earlier.variant == arrival.variant) {
+                    return Verdict::Duplicate;
+                }
+
+                seen.arrivals.push(arrival);
+                ping_pong(&seen.arrivals)
+            },
+            None => {
+                self.seen.insert(msg.message_id.clone(), Seen{ first_seen: Instant::now(), arrivals: vec![arrival], origin: msg.sender.clone() });
+                self.order.push_back(msg.message_id.clone());
+                Verdict::Accept
+            }
+        };
+
+        self.evict();
+        verdict
+    }
+
+    // Forget the oldest messages once the cache is full or they have expired
+    fn evict(&mut self) {
+        while let Some(id) = self.order.pop_front() {
+            let keep = self.order.len() < self.capacity
+                && self.seen.get(&id).map_or(false, |seen| seen.first_seen.elapsed() < self.expiry);
+
+            if keep {
+                self.order.push_front(id);
+                break;
+            }
+            self.seen.remove(&id);
+        }
+    }
+}
+
+// Check whether the destination leads back to the app that sent a message
+pub fn addressed_to(dest: &MessageDest, origin: &MessageSender) -> bool {
+    match origin.uuid {
+        Some(ref uuid) => dest.uuid.as_ref() == Some(uuid),
+        None => origin.role.is_some() && dest.role == origin.role && (origin.addr.is_none() || dest.addr == origin.addr),
+    }
+}
+
+// Check whether the most recent arrivals alternate between two connections
+fn ping_pong(arrivals: &[Arrival]) -> Verdict {
+    if arrivals.len() < PING_PONG_LIMIT {
+        return Verdict::Accept;
+    }
+
+    let recent = &arrivals[arrivals.len() - PING_PONG_LIMIT..];
+    let (a, b) = (recent[0].from, recent[1].from);
+    let alternating = a != b && recent.iter()
+        .enumerate()
+        .all(|(idx, arrival)| arrival.from == if idx % 2 == 0 { a } else { b });
+
+    if alternating {
+        Verdict::PingPong(a, b)
+    } else {
+        Verdict::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn broadcast() -> Message {
+        let mut sender = MessageSender::default();
+        sender.role = Some("search".to_string());
+        let mut dest = MessageDest::default();
+        dest.broadcast = Some(true);
+
+        let mut msg = Message::new(sender, dest);
+        msg.action = Some("query".to_string());
+        msg
+    }
+
+    #[test]
+    fn duplicates_across_links() {
+        let mut guard = RoutingGuard::new(16, 8);
+        let me: IpAddr = "10.0.0.1".parse().unwrap();
+        let link_a: SocketAddr = "10.0.0.2:6222".parse().unwrap();
+        let link_b: SocketAddr = "10.0.0.3:6222".parse().unwrap();
+
+        // The same broadcast reaches us through two other managers, over routes of different lengths
+        let mut first = broadcast();
+        first.route = vec!["10.0.0.9".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        let mut second = first.clone();
+        second.route = vec!["10.0.0.9".parse().unwrap(), "10.0.0.4".parse().unwrap(), "10.0.0.3".parse().unwrap()];
+
+        assert_eq!(guard.check(&first, &link_a, &me), Verdict::Accept);
+        assert_eq!(guard.check(&second, &link_b, &me), Verdict::Duplicate);
+        assert_eq!(guard.stats().duplicates, 1);
+    }
+
+    #[test]
+    fn replies_reusing_the_id_are_accepted() {
+        let mut guard = RoutingGuard::new(16, 8);
+        let me: IpAddr = "10.0.0.1".parse().unwrap();
+        let app: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+
+        let request = broadcast();
+        let mut reply = request.clone();
+        reply.dest = request.sender.clone().into();
+        reply.resp = Some(json!(["result"]));
+
+        assert_eq!(guard.check(&request, &app, &me), Verdict::Accept);
+        assert_eq!(guard.check(&reply, &other, &me), Verdict::Accept);
+        assert_eq!(guard.check(&reply, &other, &me), Verdict::Duplicate);
+    }
+}
@@ -1,22 +1,25 @@
 
+use std::collections::HashMap;
 use std::io::ErrorKind;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::path;
 use std::time;
-use std::thread;
 
 use chrono;
 use clap;
 use futures::{future, Future, Stream};
 use tokio;
-use walkdir::{DirEntry, WalkDir};
+use walkdir::DirEntry;
 
 use seshat::crawl::*;
 use seshat::handle;
-use seshat::index::IndexWriter;
+use seshat::index::{Index, IndexWriter};
+use seshat::persist;
 use tags;
 
 use device::DeviceManager;
+use schedule;
+use schedule::CrawlSchedule;
 
 // Create the fs crawler according to the configuration
 fn create_crawler<'a>(_args: &'a clap::ArgMatches) -> impl Crawler {
@@ -29,35 +32,85 @@ fn create_crawler<'a>(_args: &'a clap::ArgMatches) -> impl Crawler {
     crawler
 }
 
+// The writer is shared between the reindexing task, the periodic cache saver, and the shutdown handler
+pub type SharedWriter = Arc<Mutex<IndexWriter>>;
+
 // Delay the initial loading of the index from a file for a little bit
 // This helps us spawn up the server slightly faster, avoiding reconnection issues with the modalities
-type LazyLoader = Box<dyn Future<Item=(time::Instant, IndexWriter), Error=()> + Send>;
-pub fn load_index<'a>(args: &'a clap::ArgMatches, mut writer: IndexWriter) -> LazyLoader {
-    let hour = chrono::Duration::hours(1).to_std().unwrap();
+type LazyLoader = Box<dyn Future<Item=time::Instant, Error=()> + Send>;
+pub fn load_index<'a>(args: &'a clap::ArgMatches, writer: SharedWriter, index: Index, root_folders: Vec<String>) -> LazyLoader {
     let index_cache = args.value_of("index-cache")
         .and_then(|dst| Some(dst.to_string()));
 
+    // Queue up all root folders so that the first reindexing pass performs a full crawl
+    let queue_crawl = move || {
+        for root_folder in &root_folders {
+            if let Err(err) = index.push_folder(root_folder) {
+                error!("Failed to queue root folder {:?} for crawling: {:?}", root_folder, err);
+            }
+        }
+    };
+
     match index_cache {
         Some(file) => {
             trace!("Found configuration for index cache file. Spawning task to load index from file `{:?}`", file);
 
             Box::new(future::lazy(move || {
-                info!("Loading index cache file `{:?}`", file);
                 let file = path::Path::new(&file);
-                writer.load_file(file);
-                Ok((time::Instant::now() + hour, writer))
+                let mut writer = writer.lock().unwrap();
+
+                // NOTE: The queue is polled right away either way, as the crawl schedule decides what gets queued
+                if file.exists() || persist::journal_path(file).exists() {
+                    info!("Loading index cache file `{:?}`", file);
+                    writer.load_file(file);
+
+                } else {
+                    info!("Index cache file `{:?}` does not exist. Queueing an immediate crawl of all root folders", file);
+                    queue_crawl();
+                }
+
+                if let Err(err) = writer.persist_to(file) {
+                    error!("Failed to open the index journal for `{:?}`. Index changes will only be saved on checkpoints: {:?}", file, err);
+                }
+
+                Ok(time::Instant::now())
             }))
         },
-        None => Box::new(future::lazy(|| Ok((time::Instant::now(), writer))))
+        None => {
+            debug!("Configuration did not specify a value for `index-cache`. Index will not be saved between runs");
+            Box::new(future::lazy(move || {
+                queue_crawl();
+                Ok(time::Instant::now())
+            }))
+        }
     }
 }
 
-pub fn launch<'a>(device: DeviceManager, args: &'a clap::ArgMatches, writer: IndexWriter) -> impl Future {
+// Write the current state of the index out to the cache file (if one was configured)
+pub fn save_index(writer: &SharedWriter) {
+    let mut writer = writer.lock().unwrap();
+    let file = match writer.snapshot_path() {
+        Some(file) => file.to_path_buf(),
+        None => return,
+    };
+
+    trace!("Saving index to cache file `{:?}`", file);
+    match writer.checkpoint() {
+        Ok(_) => info!("Saved index to cache file `{:?}`", file),
+        Err(err) => error!("Failed to save index to cache file `{:?}`: {:?}", file, err),
+    }
+}
+
+pub fn launch<'a>(device: DeviceManager, args: &'a clap::ArgMatches, writer: SharedWriter) -> impl Future {
     trace!("Launching indexer task system");
 
     // Create indexer constants
-    let hour = chrono::Duration::hours(1).to_std().unwrap();
-    let week = chrono::Duration::weeks(1).to_std().expect("Unable to convert 1 week to seconds");
+    let poll = chrono::Duration::minutes(1).to_std().unwrap();
+    // NOTE: The value was already checked by `positive_minutes` when the arguments were parsed
+    let save_interval = args.value_of("index-save-interval")
+        .map(|mins| mins.parse::<u32>().expect("Value of `index-save-interval` field was not a valid number"))
+        .unwrap_or(DEFAULT_SAVE_INTERVAL_MINS);
+    let save_interval = time::Duration::from_secs(u64::from(save_interval) * 60);
 
     // Extract the root folders from the configuration, allowing for no-values to take the default root
     let root_folders: Option<Vec<String>> = args.values_of("index-root")
@@ -68,56 +121,135 @@ pub fn launch<'a>(device: DeviceManager, args: &'a clap::ArgMatches, writer: Ind
     let root_folders = root_folders.unwrap_or(vec![DEFAULT_ROOT.to_string()]);
     debug!("Extracted root folders for index crawling operations: {:?}", root_folders);
 
+    // Register the crawl schedule for every root
+    let schedule = device.get_schedule().clone();
+    configure_schedule(&schedule, args, &root_folders);
+
     // Create the crawler
     let crawler = create_crawler(args);
 
-    // Load the index, then setup a periodic check every hour for reindexing
-    let indexer = load_index(args, writer)
-        .and_then(move |(delay, mut writer)| {
-            trace!("Spawning reindexer tasks on hourly timetable. Next task in {:?}", delay);
-            tokio::timer::Interval::new(delay, hour)
+    // Load the index, then setup a periodic check for any folders that have been queued for reindexing
+    let crawl_writer = writer.clone();
+    let crawl_roots = root_folders.clone();
+    let indexer = load_index(args, writer.clone(), device.get_index().clone(), root_folders.clone())
+        .and_then(move |delay| {
+            trace!("Spawning reindexer tasks, polling every {:?}. Next task in {:?}", poll, delay);
+            tokio::timer::Interval::new(delay, poll)
                 .for_each(move |_| {
+                    let mut writer = crawl_writer.lock().unwrap();
                     let folders = writer.queued_folders();
                     trace!("Performing reindexing on the following folders: {:?}", folders);
 
-                    // TODO: Perform some degree of subsumption, etc. on the roots
-                    // NOTE: If I'm pushing on any root file, we need to erase the index
-
                     // Spawn-and-forget the crawling in a separate thread
                     // NOTE: Performing crawling within the sequential code-block causes tokio's processing
                     // To grind to a halt, harming system-wide uptime and responsiveness
                     // TODO: We can't do this just yet because of the borrow checker
                     // thread::spawn(move || {
-                        for root in folders {
-                            info!("Starting crawling of {:?}", root);
-                            crawler.crawl(WalkDir::new(root), &mut writer);
+                        let crawled = !folders.is_empty();
+                        for folder in folders {
+                            let root = owning_root(&folder, &crawl_roots);
+                            info!("Starting crawling of {:?} (root {:?})", folder, root);
+                            crawler.recrawl(&folder, &root, &mut writer);
                         }
 
                         trace!("Commiting reindexing changes to index term map");
                         writer.commit();
                     // });
 
+                    // Save the results of the crawl so that we don't have to redo it on restart
+                    if crawled && writer.snapshot_path().is_some() {
+                        if let Err(err) = writer.checkpoint() {
+                            error!("Failed to save index cache after crawling: {:?}", err);
+                        }
+                    }
+
                     Ok(())
                 })
                 .map_err(|_| ())
         });
 
-    // Periodically push on all root folders to force re-indexing
+    // Periodically save the index to the cache file
+    // NOTE: Changes are journaled as they are made, so this mainly serves to keep the journal from growing without bound
+    let save_instant = time::Instant::now() + save_interval;
+    trace!("Spawning indexer task to save the index cache every {:?} (next: {:?})", save_interval, save_instant);
+    let save_writer = writer.clone();
+    let save_cache = tokio::timer::Interval::new(save_instant, save_interval)
+        .for_each(move |_| {
+            save_index(&save_writer);
+            Ok(())
+        })
+        .map_err(|_| ());
+
+    // Push root folders onto the reindex queue as their crawl schedules come due
     // NOTE: This capability means that to support 'file-watchers', we just add an event to push the new folder on the channel
-    let root_queue_instant = time::Instant::now() + week;
-    trace!("Spawning indexer task to automatically refresh the filesystem data every {:?} (next: {:?})", week, root_queue_instant);
-    let queue_roots = tokio::timer::Interval::new(root_queue_instant, week)
+    trace!("Spawning indexer task to queue root folders according to their crawl schedules: {}", schedule.status());
+    let queue_roots = tokio::timer::Interval::new(time::Instant::now(), poll)
         .for_each(move |_| {
-            trace!("Adding root folders to reindex queue to refresh filesystem data: {:?}", root_folders);
-            for root_folder in &root_folders {
+            let due = schedule.take_due();
+            if !due.is_empty() {
+                trace!("Adding root folders to reindex queue according to their schedules: {:?}", due);
+            }
+
+            for root_folder in &due {
                 device.get_index().push_folder(root_folder)
                     .map_err(|_| tokio::timer::Error::shutdown())?
             }
 
             Ok(())
-        });
+        })
+        .map_err(|_| ());
+
+    indexer.select2(save_cache).select2(queue_roots)
+}
+
+// Register each root's crawl schedule, taking the default schedule where none was configured
+fn configure_schedule<'a>(schedule: &CrawlSchedule, args: &'a clap::ArgMatches, root_folders: &[String]) {
+    let idle_after = args.value_of("index-idle-after")
+        .and_then(|mins| mins.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_IDLE_AFTER_MINS);
+    schedule.set_idle_after(chrono::Duration::minutes(idle_after));
 
-    indexer.select2(queue_roots)
+    let default_spec = args.value_of("index-default-schedule").unwrap_or(DEFAULT_SCHEDULE);
+
+    // Extract the per-root schedule overrides, which are given as `ROOT=SCHEDULE`
+    let mut specs = HashMap::new();
+    for entry in args.values_of("index-schedule").into_iter().flat_map(|entries| entries) {
+        match entry.rfind('=') {
+            Some(idx) => {
+                let root = &entry[..idx];
+                if !root_folders.iter().any(|folder| folder == root) {
+                    warn!("Ignoring crawl schedule for {:?}: It is not a configured `index-root`", root);
+                    continue;
+                }
+                specs.insert(root.to_string(), entry[idx + 1..].to_string());
+            },
+            None => warn!("Ignoring malformed crawl schedule {:?}: Expected `ROOT=SCHEDULE`", entry),
+        }
+    }
+
+    for root in root_folders {
+        let spec = specs.get(root).map(|spec| spec.as_str()).unwrap_or(default_spec);
+        let triggers = match schedule::parse_schedule(spec) {
+            Ok(triggers) => triggers,
+            Err(err) => {
+                error!("Invalid crawl schedule {:?} for {:?}, using {:?} instead: {}", spec, root, DEFAULT_SCHEDULE, err);
+                schedule::parse_schedule(DEFAULT_SCHEDULE).expect("Default crawl schedule should be valid")
+            }
+        };
+
+        debug!("Registering crawl schedule {:?} for root folder {:?}", spec, root);
+        schedule.add_root(root, spec, triggers);
+    }
+}
+
+// Find the configured root folder that `folder` lives under
+// Folders outside of every configured root are treated as their own root
+fn owning_root(folder: &str, root_folders: &[String]) -> String {
+    root_folders.iter()
+        .filter(|root| path::Path::new(folder).starts_with(root))
+        .max_by_key(|root| root.len())
+        .map(|root| root.to_string())
+        .unwrap_or(folder.to_string())
 }
 
 pub fn add_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
@@ -128,6 +260,29 @@ pub fn add_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
             .help("location of the index cache storage file")
             .value_name("JSON")
             .takes_value(true))
+        .arg(Arg::with_name("index-schedule")
+            .long("index-schedule")
+            .help("Crawl schedule for a specific root folder, given as `ROOT=SCHEDULE`")
+            .value_name("ROOT=SCHEDULE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("index-default-schedule")
+            .long("index-default-schedule")
+            .help("Crawl schedule for root folders without an `index-schedule` entry")
+            .value_name("SCHEDULE")
+            .takes_value(true))
+        .arg(Arg::with_name("index-idle-after")
+            .long("index-idle-after")
+            .help("Number of minutes without any messages before the device is considered idle")
+            .value_name("MINUTES")
+            .takes_value(true))
+        .arg(Arg::with_name("index-save-interval")
+            .long("index-save-interval")
+            .help("Number of minutes between automatic saves of the index cache")
+            .value_name("MINUTES")
+            .validator(positive_minutes)
+            .takes_value(true))
         .arg(Arg::with_name("index-root")
             .long("index-root")
             .help("Root folder path for index crawling")
@@ -138,6 +293,15 @@ pub fn add_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
 }
 
 
+// Check that an argument is a whole number of minutes, greater than zero (ie. for intervals that repeat)
+fn positive_minutes(mins: String) -> Result<(), String> {
+    match mins.parse::<u32>() {
+        Ok(0) => Err("must be at least one minute".to_string()),
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("{:?} is not a whole number of minutes", mins)),
+    }
+}
+
 // Specify all the file handlers for the index system
 struct MusicHandler;
 impl handle::FileHandler for MusicHandler {
@@ -172,6 +336,13 @@ impl handle::FileHandler for MusicHandler {
     }
 }
 
+// Save the index cache every half hour unless otherwise configured
+const DEFAULT_SAVE_INTERVAL_MINS: u32 = 30;
+
+// Refresh every root weekly, and consider the device idle after 15 minutes, unless otherwise configured
+const DEFAULT_SCHEDULE: &'static str = "every 1w";
+const DEFAULT_IDLE_AFTER_MINS: i64 = 15;
+
 // Specify the default system root folder (for if none is specified in config)
 #[cfg(unix)]
 const DEFAULT_ROOT: &'static str = "/";
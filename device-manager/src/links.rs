@@ -0,0 +1,109 @@
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use chrono::{DateTime, Local};
+use serde_json;
+
+/*
+Device managers connect to each other to route messages between devices. Either side of a connection can
+Start the link by sending a `handshake` whose `body` marks it as coming from a manager:
+
+  "sender": { "role": "manager", "addr": "41.249.102.177" },
+  "body": { "link": "manager", "device_id": "84707676-d2c3-50ce-8c1e-f8fe62ffda3c", "roles": ["audio"] }
+
+The other side registers the link and answers with its own link handshake. Afterwards, any message whose
+`dest.addr` is the address of a linked manager is sent over the link, and the remote manager resolves the
+Role or uuid against its own apps. Messages for a role that no local app provides are sent on to a linked
+Manager that advertised the role. Managers re-send their link handshake whenever the roles of their apps change.
+*/
+
+pub struct RemoteManager {
+    pub conn: SocketAddr,
+    pub device_id: String,
+    pub roles: Vec<String>,
+    pub linked_at: DateTime<Local>,
+}
+
+#[derive(Default)]
+pub struct LinkTable {
+    remotes: HashMap<IpAddr, RemoteManager>,
+}
+
+impl LinkTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Record that the manager at `addr` (the remote device's address) is reachable through `conn`
+    pub fn insert(&mut self, addr: IpAddr, conn: SocketAddr, device_id: String, roles: Vec<String>) {
+        self.remotes.insert(addr, RemoteManager{
+            conn: conn,
+            device_id: device_id,
+            roles: roles,
+            linked_at: Local::now(),
+        });
+    }
+
+    // Remove every remote manager reached through the closed connection
+    pub fn remove_conn(&mut self, conn: &SocketAddr) -> Vec<IpAddr> {
+        let removed = self.remotes.iter()
+            .filter(|&(_, remote)| remote.conn == *conn)
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        for addr in &removed {
+            self.remotes.remove(addr);
+        }
+        removed
+    }
+
+    pub fn is_link(&self, conn: &SocketAddr) -> bool {
+        self.remotes.values().any(|remote| remote.conn == *conn)
+    }
+
+    // Find the connection that leads towards the manager at `addr`
+    // NOTE: If we aren't linked to the manager directly, the message is sent back along the route it took
+    pub fn route_to(&self, addr: &IpAddr, route: &[IpAddr], me: &IpAddr) -> Option<SocketAddr> {
+        if let Some(remote) = self.remotes.get(addr) {
+            return Some(remote.conn);
+        }
+
+        route.iter()
+            .rev()
+            .filter(|hop| *hop != me)
+            .filter_map(|hop| self.remotes.get(hop))
+            .map(|remote| remote.conn)
+            .next()
+    }
+
+    // The links to the managers that advertised apps for the role, skipping those already in `route`
+    pub fn providers_of(&self, role: &str, route: &[IpAddr]) -> Vec<SocketAddr> {
+        self.remotes.iter()
+            .filter(|&(addr, remote)| !route.contains(addr) && remote.roles.iter().any(|r| r == role))
+            .map(|(_, remote)| remote.conn)
+            .collect()
+    }
+
+    // The links that a message which has already passed through `route` still needs to be sent to
+    pub fn unvisited(&self, route: &[IpAddr]) -> Vec<SocketAddr> {
+        self.remotes.iter()
+            .filter(|&(addr, _)| !route.contains(addr))
+            .map(|(_, remote)| remote.conn)
+            .collect()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let remotes = self.remotes.iter()
+            .map(|(addr, remote)| json!({
+                "addr": addr.to_string(),
+                "conn": remote.conn.to_string(),
+                "device_id": remote.device_id,
+                "roles": remote.roles,
+                "linked_at": remote.linked_at.to_rfc3339(),
+            }))
+            .collect::<Vec<_>>();
+
+        json!(remotes)
+    }
+}
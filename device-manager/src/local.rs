@@ -0,0 +1,205 @@
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+use futures;
+use serde_json;
+
+use device::DeviceManager;
+
+/*
+Local apps may connect over a unix domain socket (see `--socket`) instead of the tcp port. Access to the socket
+Is controlled by its file permissions (see `--socket-mode`), and every connection records the credentials of the
+Process on the other end (its pid, uid, and gid), so that local apps can be identified by the process that owns
+Them. The listener may also be limited to processes run by specific users (see `--socket-allow-uid`). A socket
+File left behind by a previous run is replaced, unless another manager is still listening on it.
+
+Unix socket connections have no socket address, so each is registered under a made-up address on the unspecified
+Address `0.0.0.0` (with ports counting up), which no tcp peer can connect from. Any address still in use by another
+Connection is skipped.
+*/
+
+#[derive(Debug, Clone)]
+pub struct LocalOptions {
+    pub path: PathBuf,
+    pub mode: u32,
+    pub allowed_uids: Vec<u32>,
+}
+
+// The credentials of the process on the other end of a unix socket connection
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCredentials {
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl PeerCredentials {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "pid": self.pid,
+            "uid": self.uid,
+            "gid": self.gid,
+        })
+    }
+}
+
+// Hands out the made-up addresses of unix socket connections, holding their credentials until they are registered
+pub struct LocalPeers {
+    next_port: u16,
+    pending: HashMap<SocketAddr, PeerCredentials>,
+}
+
+impl LocalPeers {
+    pub fn new() -> Self {
+        Self{
+            next_port: 1,
+            pending: HashMap::new(),
+        }
+    }
+
+    // Pick an address for a new connection, skipping any that are still in use
+    pub fn reserve<F: Fn(&SocketAddr) -> bool>(&mut self, peer: PeerCredentials, in_use: F) -> Option<SocketAddr> {
+        for _ in 0..u16::max_value() {
+            let addr = SocketAddr::new(LOCAL_SOCKET_IP, self.next_port);
+            self.next_port = self.next_port.checked_add(1).unwrap_or(1);
+
+            if !in_use(&addr) && !self.pending.contains_key(&addr) {
+                self.pending.insert(addr, peer);
+                return Some(addr);
+            }
+        }
+        None
+    }
+
+    // Take the credentials of the connection registered under `addr` (if it's a unix socket connection)
+    pub fn claim(&mut self, addr: &SocketAddr) -> Option<PeerCredentials> {
+        self.pending.remove(addr)
+    }
+}
+
+#[cfg(unix)]
+pub fn listen(device: DeviceManager, options: LocalOptions) -> Box<dyn futures::Future<Item=(), Error=()> + Send> {
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixStream;
+    use tokio::prelude::*;
+    use tokio::net::UnixListener;
+    use libc;
+
+    use networking::codec::Negotiation;
+    use networking::spawn::spawn_stream;
+
+    // NOTE: A socket file left behind by a previous run would stop us from binding, but one that still answers
+    // Belongs to a running manager
+    if let Ok(metadata) = fs::symlink_metadata(&options.path) {
+        if !metadata.file_type().is_socket() {
+            panic!("Refusing to replace {:?} with the socket file: It is not a socket", options.path);
+        }
+        if UnixStream::connect(&options.path).is_ok() {
+            panic!("Another process is already listening on the socket file {:?}", options.path);
+        }
+
+        warn!("Removing stale socket file {:?}", options.path);
+        fs::remove_file(&options.path).expect("Failed to remove stale socket file");
+    }
+
+    // NOTE: The socket file is created by the bind, so it's created with the right permissions through the umask
+    // Rather than left open to other users until its permissions are set
+    info!("Spawning device manager server listening on unix socket {:?}", options.path);
+    let umask = unsafe { libc::umask(!options.mode as libc::mode_t & 0o777) };
+    let listener = UnixListener::bind(&options.path);
+    unsafe { libc::umask(umask) };
+
+    let listener = listener.expect("Failed to bind server to specified socket path");
+    fs::set_permissions(&options.path, fs::Permissions::from_mode(options.mode))
+        .expect("Failed to set the permissions of the socket file");
+
+    let path = options.path.clone();
+    device.get_shutdown().on_shutdown("socket file", move || {
+        if let Err(err) = fs::remove_file(&path) {
+            warn!("Failed to remove socket file {:?}: {:?}", path, err);
+        }
+    });
+
+    let allowed_uids = options.allowed_uids;
+    let server = listener.incoming()
+        .for_each(move |conn| {
+            let peer = match peer_credentials(&conn) {
+                Ok(peer) => peer,
+                Err(err) => {
+                    warn!("Rejecting unix socket connection with unknown credentials: {:?}", err);
+                    return Ok(());
+                },
+            };
+
+            if !allowed_uids.is_empty() && !allowed_uids.contains(&peer.uid) {
+                warn!("Rejecting unix socket connection from process {:?} run by disallowed user {}", peer.pid, peer.uid);
+                return Ok(());
+            }
+
+            match device.reserve_local_addr(peer) {
+                Some(addr) => {
+                    info!("Accepted unix socket connection from process {:?} (uid {}) as {:?}", peer.pid, peer.uid, addr);
+                    spawn_stream(conn, addr, device.clone(), Negotiation::Accept);
+                },
+                None => warn!("Rejecting unix socket connection from process {:?}: No addresses are free", peer.pid),
+            }
+            Ok(())
+        })
+        .map_err(|err| error!("Unix Socket Server Error: {:?}", err));
+
+    Box::new(server)
+}
+
+#[cfg(not(unix))]
+pub fn listen(_device: DeviceManager, options: LocalOptions) -> Box<dyn futures::Future<Item=(), Error=()> + Send> {
+    error!("Unable to listen on {:?}: Unix domain sockets are not supported on this platform", options.path);
+    Box::new(futures::future::ok(()))
+}
+
+#[cfg(target_os = "linux")]
+fn peer_credentials(conn: &::tokio::net::UnixStream) -> Result<PeerCredentials, ::std::io::Error> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    // NOTE: `SO_PEERCRED` also gives the peer's pid, unlike `UnixStream::peer_cred`
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(conn.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if res != 0 {
+        return Err(::std::io::Error::last_os_error());
+    }
+
+    Ok(PeerCredentials{ pid: Some(cred.pid), uid: cred.uid, gid: cred.gid })
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn peer_credentials(conn: &::tokio::net::UnixStream) -> Result<PeerCredentials, ::std::io::Error> {
+    let cred = conn.peer_cred()?;
+    Ok(PeerCredentials{ pid: None, uid: cred.uid, gid: cred.gid })
+}
+
+// NOTE: This must never be the address of a tcp peer, or a tcp connection could replace a unix socket connection
+const LOCAL_SOCKET_IP: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_addrs_cannot_be_tcp_peers() {
+        let mut peers = LocalPeers::new();
+        let peer = PeerCredentials{ pid: Some(1), uid: 0, gid: 0 };
+        let taken = SocketAddr::new(LOCAL_SOCKET_IP, 1);
+
+        let addr = peers.reserve(peer, |addr| *addr == taken).unwrap();
+        assert!(addr.ip().is_unspecified());
+        assert_ne!(addr, taken);
+        assert!(peers.claim(&addr).is_some());
+    }
+}
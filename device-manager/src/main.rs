@@ -4,15 +4,20 @@ extern crate clap;
 extern crate fern;
 extern crate futures;
 extern crate get_if_addrs;
+#[cfg(unix)]
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate multimap;
+extern crate ring;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 extern crate tokio;
+extern crate tokio_signal;
+extern crate uuid;
 extern crate walkdir;
 
 // Local crates
@@ -21,14 +26,25 @@ extern crate seshat;
 extern crate tags;
 
 // Local modules
+mod auth;
+mod capabilities;
 mod device;
+mod guard;
 mod indexer;
+mod links;
+mod local;
 mod logging;
-mod server;
 mod message;
+mod permissions;
+mod requests;
+mod schedule;
+mod selection;
+mod server;
+mod shutdown;
+mod uplink;
 
 // Imports
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 
 use futures::Future;
 
@@ -57,14 +73,18 @@ fn main() {
     trace!("Created device fs index");
 
     // Create the device manager
-    let (tx, cancel) = mpsc::channel();
-    let manager = device::DeviceManager::new(index, tx.clone());
+    let shutdown = shutdown::create(&args);
+    let manager = device::DeviceManager::new(index, shutdown.clone());
     trace!("Created device state manager");
 
     // Create the seshat indexer (and search engine portal)
-    let indexer = indexer::launch(manager.clone(), &args, writer);
+    let writer = Arc::new(Mutex::new(writer));
+    let indexer = indexer::launch(manager.clone(), &args, writer.clone());
     trace!("Created async fs indexer");
 
+    // Make sure the index cache is up to date for the next run
+    shutdown.on_shutdown("index cache", move || indexer::save_index(&writer));
+
     // TODO: Figure out how these will interact with the new system
     // TODO: Spawn any persistent system tools and register them with the server
         // Non-persistent tasks can be spawned by the server as needed (using tokio)
@@ -77,13 +97,15 @@ fn main() {
     // Combine all futures
     let device = server
         .select2(indexer)
-        ;
+        .then(|_| Ok::<(), ()>(()));
 
-    // Add in the ability to pre-emptively short-circuit all computations
+    // Stop every task once the shutdown is triggered, then drain the connections
+    let drain_state = shutdown.clone();
     let device = device
-        .select2(networking::comm::FutureChannel::new(cancel))
-        .map(move |_| { trace!("Closing device") })
-        .map_err(move |_| {});
+        .select2(shutdown.wait())
+        .then(move |_| shutdown::drain(manager, drain_state))
+        .join(shutdown::listen_for_signals(shutdown.clone()))
+        .map(move |_| { trace!("Closing device") });
     trace!("Created tokio task description");
 
     // Spawn the futures in the tokio event loop
@@ -107,6 +129,7 @@ fn load_configuration<'a>() -> clap::ArgMatches<'a> {
     let app = indexer::add_args(app);
     let app = server::add_args(app);
     let app = logging::add_args(app);
+    let app = shutdown::add_args(app);
 
     // Return the command line matches
     app.get_matches()
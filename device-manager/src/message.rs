@@ -3,6 +3,7 @@ use std::convert;
 use std::net::IpAddr;
 
 use serde_json;
+use uuid::Uuid;
 
 // TODO: This doesn't allow any other fields than what I've specified
 // How would I be able to get a view on the aspects of the message that I care about?
@@ -15,8 +16,11 @@ pub struct Message {
     pub parent_id: Option<String>,
     pub ack_uuid: Option<String>,
     pub route: Vec<IpAddr>,
-    pub forward: Option<bool>,
+    pub forward: Option<Forward>,
     pub sender: MessageSender,
+
+    // Set by senders that are waiting on a reply, so the manager can tell them if it never arrives
+    pub expects_reply: Option<bool>,
     pub dest: MessageDest,
 
     // TODO: Need to figure out what I'm currently using
@@ -29,14 +33,194 @@ pub struct Message {
     pub body: Option<serde_json::Value>,
 }
 
+impl Message {
+    pub fn new(sender: MessageSender, dest: MessageDest) -> Self {
+        Self{
+            message_id: Uuid::new_v4().to_string(),
+            parent_id: None,
+            ack_uuid: None,
+            route: Vec::new(),
+            forward: None,
+            sender: sender,
+            expects_reply: None,
+            dest: dest,
+            action: None,
+            args: None,
+            resp: None,
+            body: None,
+        }
+    }
+
+    // Create a new message responding to this one, addressed back to the original sender
+    pub fn reply(&self, sender: MessageSender, action: &str) -> Self {
+        let mut reply = Self::new(sender, self.sender.clone().into());
+        reply.parent_id = Some(self.message_id.clone());
+        reply.action = Some(action.to_string());
+        reply
+    }
+
+    // Create an `error` reply informing the sender that this message could not be handled
+    pub fn error_reply(&self, sender: MessageSender, code: ErrorCode, description: &str) -> Self {
+        let mut reply = self.reply(sender, "error");
+        reply.args = Some(vec![error_args(code, description, self.action.clone())]);
+        reply
+    }
+
+    // Create an `ack` for the app requested in `ack_uuid`, reporting whether this message could be delivered
+    // NOTE: Acks only carry the routing information of the message, never its contents
+    pub fn ack(&self, sender: MessageSender, status: AckStatus) -> Self {
+        let mut dest = MessageDest::default();
+        dest.uuid = self.ack_uuid.clone();
+
+        let mut ack = Self::new(sender, dest);
+        ack.parent_id = Some(self.message_id.clone());
+        ack.route = self.route.clone();
+        ack.action = Some("ack".to_string());
+        ack.args = Some(vec![json!({
+            "status": status,
+            "message_id": self.message_id,
+            "action": self.action,
+            "sender": self.sender,
+            "dest": self.dest,
+        })]);
+        ack
+    }
+
+    // Check whether the sender is waiting on a reply to this message
+    // NOTE: Replies and notifications are never waited on, even if they were copied from a request that was
+    pub fn wants_reply(&self) -> bool {
+        self.expects_reply.unwrap_or(false)
+            && self.parent_id.is_none()
+            && self.resp.is_none()
+            && !self.dest.broadcast.unwrap_or(false)
+            && self.action.as_ref().map_or(true, |action| action != "ack" && action != "error")
+    }
+
+    // Check whether this message is a response that should be passed on to the next `forward` target
+    pub fn is_forwardable(&self) -> bool {
+        let has_target = match self.forward {
+            Some(Forward::Target(_)) => true,
+            Some(Forward::Stack(ref stack)) => !stack.is_empty(),
+            _ => false,
+        };
+        has_target && self.resp.is_some()
+    }
+
+    // Readdress the message from `sender` to the next `forward` target, returning false if there is none
+    // NOTE: The forwarded message is marked as a response to this message (unless it already was one)
+    pub fn forward_step(&mut self, sender: MessageSender) -> bool {
+        let mut stack = match self.forward.take() {
+            Some(Forward::Target(target)) => vec![target],
+            Some(Forward::Stack(stack)) => stack,
+            forward => {
+                self.forward = forward;
+                return false;
+            }
+        };
+
+        if stack.is_empty() {
+            return false;
+        }
+
+        let target = stack.remove(0);
+        self.forward = if stack.is_empty() { None } else { Some(Forward::Stack(stack)) };
+        if self.parent_id.is_none() {
+            self.parent_id = Some(self.message_id.clone());
+        }
+
+        self.sender = sender;
+        self.dest = target.dest();
+        if target.action.is_some() {
+            self.action = target.action;
+        }
+        true
+    }
+
+    // Create an `error` message for a message that could not even be parsed
+    pub fn error(sender: MessageSender, parent_id: Option<String>, code: ErrorCode, description: &str) -> Self {
+        let mut msg = Self::new(sender, MessageDest::default());
+        msg.parent_id = parent_id;
+        msg.action = Some("error".to_string());
+        msg.args = Some(vec![error_args(code, description, None)]);
+        msg
+    }
+}
+
+// Error codes sent in the `args` of `error` messages
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MalformedMessage,
+    MalformedArguments,
+    UnknownAction,
+    UnknownDestination,
+    HandlerFailed,
+    HopLimitExceeded,
+    RoutingLoop,
+    RequestTimeout,
+    DestinationClosed,
+    AckUndeliverable,
+    Unauthenticated,
+    PermissionDenied,
+    IncompatibleProtocol,
+}
+
+// Delivery statuses sent in the `args` of `ack` messages
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AckStatus {
+    Delivered,
+    Undeliverable,
+}
+
+fn error_args(code: ErrorCode, description: &str, action: Option<String>) -> serde_json::Value {
+    json!({
+        "code": code,
+        "message": description,
+        "action": action,
+    })
+}
+
+// Where a response should be sent next, instead of back to the sender
+// NOTE: Older apps send `forward` as a boolean, which never forwards anything (see `networking::protocol`)
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Forward {
+    Flag(bool),
+    Target(ForwardTarget),
+    Stack(Vec<ForwardTarget>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ForwardTarget {
+    pub role: Option<String>,
+    pub addr: Option<IpAddr>,
+    pub uuid: Option<String>,
+
+    // Replaces the message's action when it is forwarded (ie. search results forwarded as `play`)
+    pub action: Option<String>,
+}
+
+impl ForwardTarget {
+    pub fn dest(&self) -> MessageDest {
+        MessageDest{
+            broadcast: None,
+            role: self.role.clone(),
+            addr: self.addr,
+            uuid: self.uuid.clone(),
+            intra_device: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct MessageSender {
     pub uuid: Option<String>,
     pub role: Option<String>,
     pub addr: Option<IpAddr>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct MessageDest {
     pub broadcast: Option<bool>,
     pub role: Option<String>,
@@ -56,3 +240,38 @@ impl convert::Into<MessageDest> for MessageSender {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(action: &str) -> Message {
+        let mut dest = MessageDest::default();
+        dest.role = Some("audio".to_string());
+
+        let mut msg = Message::new(MessageSender::default(), dest);
+        msg.action = Some(action.to_string());
+        msg
+    }
+
+    #[test]
+    fn fire_and_forget_wants_no_reply() {
+        assert!(!message("play").wants_reply());
+    }
+
+    #[test]
+    fn requests_want_replies() {
+        let mut msg = message("search");
+        msg.expects_reply = Some(true);
+        assert!(msg.wants_reply());
+
+        // NOTE: Apps usually reply by sending the request back with `resp` filled in
+        let mut reply = msg.clone();
+        reply.resp = Some(json!(["song"]));
+        assert!(!reply.wants_reply());
+
+        let mut broadcast = msg.clone();
+        broadcast.dest.broadcast = Some(true);
+        assert!(!broadcast.wants_reply());
+    }
+}
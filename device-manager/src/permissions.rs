@@ -0,0 +1,195 @@
+
+use message::Message;
+
+/*
+The permission policy decides what each app is allowed to do. Rules are given through the `--allow` and `--deny`
+Arguments in the form `SUBJECT=PERMISSION[,PERMISSION...]`, where the subject is a role, an app (`uuid:UUID`),
+Or every app (`*`), and each permission is one of:
+
+  action:NAME   send messages with the `NAME` action (eg. `action:quit`)
+  broadcast     send broadcast messages
+  route:ROLE    send messages addressed to the `ROLE` role
+  *             everything
+
+Rules for an app only apply once it has proven its uuid (see `auth.rs`), as any app could claim any uuid otherwise.
+When several rules match, rules for the app take precedence over rules for its role, which take precedence over
+Rules for every app. Denials win over allowances of the same precedence. Anything that no rule matches falls
+Back to the default (see `--permission-default`). For example, to only let the cli shut down the device:
+
+  --deny *=action:quit --allow cli=action:quit
+
+Handshakes and `stop` messages are always allowed, as is anything sent over a link from another manager that
+Authenticated with credentials for the `manager` role (see `--auth-secret`). Messages sent over any other link are
+Checked as coming from the `manager` role, so that unauthenticated managers can be allowed with `manager=*`.
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subject {
+    Any,
+    Role(String),
+    App(String),
+}
+
+impl Subject {
+    fn matches(&self, role: &str, uuid: &str) -> bool {
+        match *self {
+            Subject::Any => true,
+            Subject::Role(ref r) => r == role,
+            Subject::App(ref u) => u == uuid,
+        }
+    }
+
+    // How specific the subject is, for deciding between conflicting rules
+    fn precedence(&self) -> u8 {
+        match *self {
+            Subject::Any => 0,
+            Subject::Role(_) => 1,
+            Subject::App(_) => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Permission {
+    Any,
+    Action(String),
+    Broadcast,
+    Route(String),
+}
+
+impl Permission {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+        if spec == "*" {
+            return Ok(Permission::Any);
+        } else if spec == "broadcast" {
+            return Ok(Permission::Broadcast);
+        }
+
+        match spec.find(':').map(|idx| (&spec[..idx], &spec[idx + 1..])) {
+            Some(("action", action)) if !action.is_empty() => Ok(Permission::Action(action.to_string())),
+            Some(("route", role)) if !role.is_empty() => Ok(Permission::Route(role.to_string())),
+            _ => Err(format!("Unrecognized permission {:?}", spec)),
+        }
+    }
+
+    fn covers(&self, other: &Permission) -> bool {
+        *self == Permission::Any || self == other
+    }
+
+    fn describe(&self) -> String {
+        match *self {
+            Permission::Any => "do anything".to_string(),
+            Permission::Action(ref action) => format!("send {:?} messages", action),
+            Permission::Broadcast => "send broadcasts".to_string(),
+            Permission::Route(ref role) => format!("send messages to role {:?}", role),
+        }
+    }
+}
+
+struct Rule {
+    subject: Subject,
+    permission: Permission,
+    allow: bool,
+}
+
+pub struct PermissionPolicy {
+    rules: Vec<Rule>,
+    default_allow: bool,
+}
+
+impl PermissionPolicy {
+    pub fn new() -> Self {
+        Self{
+            rules: Vec::new(),
+            default_allow: true,
+        }
+    }
+
+    pub fn set_default(&mut self, allow: bool) {
+        self.default_allow = allow;
+    }
+
+    // Add the rules given by a `SUBJECT=PERMISSION[,PERMISSION...]` spec
+    pub fn add_rules(&mut self, spec: &str, allow: bool) -> Result<(), String> {
+        let idx = spec.find('=').ok_or("Expected a value of the form SUBJECT=PERMISSION[,PERMISSION...]".to_string())?;
+        let subject = match spec[..idx].trim() {
+            "*" => Subject::Any,
+            subject if subject.starts_with("uuid:") => Subject::App(subject["uuid:".len()..].to_string()),
+            role => Subject::Role(role.to_string()),
+        };
+
+        let permissions = spec[idx + 1..].split(',')
+            .map(Permission::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        for permission in permissions {
+            self.rules.push(Rule{ subject: subject.clone(), permission: permission, allow: allow });
+        }
+        Ok(())
+    }
+
+    // Decide whether the app registered as `role` (with `uuid`) has the permission
+    pub fn allows(&self, role: &str, uuid: &str, permission: &Permission) -> bool {
+        let matching = self.rules.iter()
+            .filter(|rule| rule.subject.matches(role, uuid) && rule.permission.covers(permission))
+            .collect::<Vec<_>>();
+
+        match matching.iter().map(|rule| rule.subject.precedence()).max() {
+            Some(precedence) => matching.iter()
+                .filter(|rule| rule.subject.precedence() == precedence)
+                .all(|rule| rule.allow),
+            None => self.default_allow,
+        }
+    }
+
+    // Check every permission that the app needs to send the message, describing the first one it lacks
+    pub fn check(&self, role: &str, uuid: &str, msg: &Message) -> Result<(), String> {
+        // NOTE: Apps have no role until their handshake, and must always be able to leave
+        let mut needed = Vec::new();
+        match msg.action {
+            Some(ref action) if action == "handshake" || action == "stop" => return Ok(()),
+            Some(ref action) => needed.push(Permission::Action(action.clone())),
+            None => {},
+        }
+
+        if msg.dest.broadcast.unwrap_or(false) {
+            needed.push(Permission::Broadcast);
+        } else if let Some(ref role) = msg.dest.role {
+            needed.push(Permission::Route(role.clone()));
+        }
+
+        match needed.into_iter().find(|permission| !self.allows(role, uuid, permission)) {
+            Some(permission) => {
+                let who = if uuid.is_empty() { format!("Role {:?}", role) } else { format!("App {:?} (role {:?})", uuid, role) };
+                Err(format!("{} is not permitted to {}", who, permission.describe()))
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use message::{MessageDest, MessageSender};
+
+    use super::*;
+
+    fn message(action: &str, dest_role: &str) -> Message {
+        let mut dest = MessageDest::default();
+        dest.role = Some(dest_role.to_string());
+
+        let mut msg = Message::new(MessageSender::default(), dest);
+        msg.action = Some(action.to_string());
+        msg
+    }
+
+    #[test]
+    fn handshakes_allowed_by_deny_default() {
+        let mut policy = PermissionPolicy::new();
+        policy.set_default(false);
+
+        assert!(policy.check("", "", &message("handshake", "manager")).is_ok());
+        assert!(policy.check("", "", &message("stop", "manager")).is_ok());
+        assert!(policy.check("", "", &message("capabilities", "manager")).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use guard;
+use message::Message;
+
+/*
+The manager keeps track of every request that it routes between apps, so that senders aren't left waiting
+Forever on an app that will never respond. A request is answered when its destination sends a message whose
+`parent_id` is the request's `message_id` back to the request's sender, or sends back the request itself (ie.
+Through `return_to_sender`). Other messages that happen to name the request are left to the usual checks.
+Requests that aren't answered within the timeout (see `--request-timeout`), or whose destination disconnects
+Before answering, are failed with an `error` sent back to the original sender.
+*/
+
+pub struct PendingRequest {
+    pub request: Message,
+    pub sender: SocketAddr,
+    pub dest: SocketAddr,
+    deadline: Instant,
+}
+
+// Counters for diagnosing unresponsive apps
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RequestStats {
+    pub pending: usize,
+    pub answered: u64,
+    pub timed_out: u64,
+    pub dest_closed: u64,
+}
+
+pub struct RequestTracker {
+    pending: HashMap<String, PendingRequest>,
+    timeout: Duration,
+    stats: RequestStats,
+}
+
+impl RequestTracker {
+    pub fn new(timeout: Duration) -> Self {
+        Self{
+            pending: HashMap::new(),
+            timeout: timeout,
+            stats: RequestStats::default(),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // Start waiting on a response to the request that was just sent from `sender` to `dest`
+    pub fn track(&mut self, msg: &Message, sender: SocketAddr, dest: SocketAddr) {
+        // NOTE: We only need the routing information to report failures
+        let mut request = msg.clone();
+        request.args = None;
+        request.resp = None;
+        request.body = None;
+
+        self.pending.insert(msg.message_id.clone(), PendingRequest{
+            request: request,
+            sender: sender,
+            dest: dest,
+            deadline: Instant::now() + self.timeout,
+        });
+    }
+
+    // Check whether the message received from `from` answers a pending request
+    // NOTE: Only the request's destination can answer it, and only by replying to the request's sender
+    pub fn resolve(&mut self, msg: &Message, from: &SocketAddr) -> Option<PendingRequest> {
+        let answered = msg.parent_id.iter()
+            .chain(Some(&msg.message_id))
+            .find(|id| self.pending.get(*id).map_or(false, |pending| {
+                pending.dest == *from && guard::addressed_to(&msg.dest, &pending.request.sender)
+            }))
+            .cloned();
+
+        let pending = answered.and_then(|id| self.pending.remove(&id));
+        if pending.is_some() {
+            self.stats.answered += 1;
+        }
+        pending
+    }
+
+    // Remove every request that has waited past its deadline
+    pub fn expire(&mut self) -> Vec<PendingRequest> {
+        let now = Instant::now();
+        let expired = self.pending.iter()
+            .filter(|&(_, pending)| pending.deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        self.stats.timed_out += expired.len() as u64;
+        expired.into_iter()
+            .filter_map(|id| self.pending.remove(&id))
+            .collect()
+    }
+
+    // Remove every request involving the closed connection, returning those that were waiting on it
+    pub fn close_connection(&mut self, addr: &SocketAddr) -> Vec<PendingRequest> {
+        let involved = self.pending.iter()
+            .filter(|&(_, pending)| pending.dest == *addr || pending.sender == *addr)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        // NOTE: Requests sent by the closed connection have nobody left to report to
+        let failed = involved.into_iter()
+            .filter_map(|id| self.pending.remove(&id))
+            .filter(|pending| pending.sender != *addr)
+            .collect::<Vec<_>>();
+
+        self.stats.dest_closed += failed.len() as u64;
+        failed
+    }
+
+    // The number of requests sent to `addr` that are still waiting on a response
+    pub fn load(&self, addr: &SocketAddr) -> usize {
+        self.pending.values()
+            .filter(|pending| pending.dest == *addr)
+            .count()
+    }
+
+    pub fn stats(&self) -> RequestStats {
+        let mut stats = self.stats.clone();
+        stats.pending = self.pending.len();
+        stats
+    }
+}
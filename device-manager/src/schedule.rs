@@ -0,0 +1,332 @@
+
+use std::sync::{Arc, Mutex};
+
+use chrono;
+use chrono::{Datelike, DateTime, Local, NaiveDateTime, TimeZone, Timelike};
+use serde_json;
+
+/*
+Crawl schedules decide when each index root gets pushed onto the reindexing queue. A schedule is a
+';'-separated list of triggers, and the root is queued whenever any of them fires:
+
+  startup           crawl the root once when the device-manager starts
+  idle              crawl the root once the device has gone idle (see `--index-idle-after`)
+  manual            never crawl automatically (the root can still be queued with the `reindex` action)
+  every <N><unit>   crawl on a fixed interval, where unit is one of s, m, h, d, w (eg. "every 6h")
+  <cron>            crawl according to a 5-field cron expression (eg. "0 3 * * 1-5")
+*/
+
+#[derive(Clone, Debug)]
+pub enum Trigger {
+    Startup,
+    Idle,
+    Manual,
+    Interval(chrono::Duration),
+    Cron(CronExpr),
+}
+
+impl Trigger {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+        match spec.to_lowercase().as_str() {
+            "startup" | "on startup" => return Ok(Trigger::Startup),
+            "idle" | "when idle" => return Ok(Trigger::Idle),
+            "manual" | "manual only" | "never" => return Ok(Trigger::Manual),
+            _ => {}
+        }
+
+        if spec.split_whitespace().count() == 5 {
+            return CronExpr::parse(spec).map(Trigger::Cron);
+        }
+
+        let interval = spec.trim_start_matches("every").trim();
+        parse_interval(interval)
+            .map(Trigger::Interval)
+            .ok_or(format!("Unrecognized schedule trigger {:?}", spec))
+    }
+}
+
+// Parse a schedule specification into its list of triggers
+pub fn parse_schedule(spec: &str) -> Result<Vec<Trigger>, String> {
+    spec.split(';')
+        .filter(|trigger| !trigger.trim().is_empty())
+        .map(Trigger::parse)
+        .collect()
+}
+
+fn parse_interval(spec: &str) -> Option<chrono::Duration> {
+    let split = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or(spec.len());
+    let (count, unit) = spec.split_at(split);
+    let count = count.parse::<i64>().ok().filter(|&count| count > 0)?;
+
+    match unit.trim() {
+        "s" => Some(chrono::Duration::seconds(count)),
+        "m" => Some(chrono::Duration::minutes(count)),
+        "h" => Some(chrono::Duration::hours(count)),
+        "d" => Some(chrono::Duration::days(count)),
+        "w" => Some(chrono::Duration::weeks(count)),
+        _ => None
+    }
+}
+
+
+// A standard 5-field cron expression: "minute hour day-of-month month day-of-week"
+#[derive(Clone, Debug)]
+pub struct CronExpr {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+
+    // Cron matches *either* the day-of-month or the day-of-week when both are restricted
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl CronExpr {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Cron expression {:?} must have 5 fields", spec));
+        }
+
+        let mut weekdays = parse_cron_field(fields[4], 0, 7)?;
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+
+        Ok(Self{
+            minutes: parse_cron_field(fields[0], 0, 59)?,
+            hours: parse_cron_field(fields[1], 0, 23)?,
+            days: parse_cron_field(fields[2], 1, 31)?,
+            months: parse_cron_field(fields[3], 1, 12)?,
+            weekdays: weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+
+    fn matches_day(&self, time: &NaiveDateTime) -> bool {
+        if !self.months[time.month() as usize] {
+            return false;
+        }
+
+        let day = self.days[time.day() as usize];
+        let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    // Find the first matching minute strictly after `after`
+    pub fn next_after(&self, after: &DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let limit = start + chrono::Duration::days(366 * 4);
+
+        let mut time = start;
+        while time < limit {
+            if !self.matches_day(&time) {
+                time = (time + chrono::Duration::days(1)).with_hour(0)?.with_minute(0)?;
+
+            } else if !self.hours[time.hour() as usize] {
+                time = (time + chrono::Duration::hours(1)).with_minute(0)?;
+
+            } else if !self.minutes[time.minute() as usize] {
+                time = time + chrono::Duration::minutes(1);
+
+            } else {
+                // NOTE: Local times that don't exist (ie. skipped by daylight savings) are passed over
+                if let Some(time) = Local.from_local_datetime(&time).earliest() {
+                    return Some(time);
+                }
+                time = time + chrono::Duration::minutes(1);
+            }
+        }
+
+        None
+    }
+}
+
+// Parse a single cron field into a lookup table indexed by value
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut allowed = vec![false; max as usize + 1];
+    let invalid = || format!("Invalid cron field {:?} (values must be within {}-{})", field, min, max);
+
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(idx) => (&part[..idx], part[idx + 1..].parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some(idx) = range.find('-') {
+            let lo = range[..idx].parse::<u32>().map_err(|_| invalid())?;
+            let hi = range[idx + 1..].parse::<u32>().map_err(|_| invalid())?;
+            (lo, hi)
+        } else {
+            let val = range.parse::<u32>().map_err(|_| invalid())?;
+            (val, if step > 1 { max } else { val })
+        };
+
+        if step == 0 || lo < min || hi > max || lo > hi {
+            return Err(invalid());
+        }
+
+        for val in (lo..hi + 1).filter(|val| (val - lo) % step == 0) {
+            allowed[val as usize] = true;
+        }
+    }
+
+    Ok(allowed)
+}
+
+
+// The crawl schedule for a single index root
+struct RootSchedule {
+    root: String,
+    spec: String,
+    triggers: Vec<Trigger>,
+    started: bool,
+    last_run: Option<DateTime<Local>>,
+    next_run: Option<DateTime<Local>>,
+}
+
+impl RootSchedule {
+    // Recompute when this root should next be crawled
+    fn refresh(&mut self, state: &Activity) {
+        let base = self.last_run.unwrap_or(state.started);
+
+        self.next_run = self.triggers.iter()
+            .filter_map(|trigger| match *trigger {
+                Trigger::Startup if !self.started => Some(state.started),
+                Trigger::Startup => None,
+                Trigger::Manual => None,
+                Trigger::Interval(interval) => Some(base + interval),
+                Trigger::Cron(ref cron) => cron.next_after(&base),
+
+                // Only crawl once per idle period
+                Trigger::Idle => match self.last_run {
+                    Some(last_run) if last_run >= state.last_activity => None,
+                    _ => Some(state.last_activity + state.idle_after),
+                },
+            })
+            .min();
+    }
+}
+
+struct Activity {
+    started: DateTime<Local>,
+    last_activity: DateTime<Local>,
+    idle_after: chrono::Duration,
+}
+
+struct ScheduleState {
+    roots: Vec<RootSchedule>,
+    activity: Activity,
+}
+
+#[derive(Clone)]
+pub struct CrawlSchedule {
+    state: Arc<Mutex<ScheduleState>>,
+}
+
+impl CrawlSchedule {
+    pub fn new() -> Self {
+        let now = Local::now();
+        Self{
+            state: Arc::new(Mutex::new(ScheduleState{
+                roots: Vec::new(),
+                activity: Activity{
+                    started: now,
+                    last_activity: now,
+                    idle_after: chrono::Duration::minutes(15),
+                },
+            })),
+        }
+    }
+
+    pub fn set_idle_after(&self, idle_after: chrono::Duration) {
+        self.state.lock().unwrap().activity.idle_after = idle_after;
+    }
+
+    // Register (or replace) the crawl schedule for the given root
+    pub fn add_root(&self, root: &str, spec: &str, triggers: Vec<Trigger>) {
+        let mut state = self.state.lock().unwrap();
+        state.roots.retain(|schedule| schedule.root != root);
+
+        let mut schedule = RootSchedule{
+            root: root.to_string(),
+            spec: spec.to_string(),
+            triggers: triggers,
+            started: false,
+            last_run: None,
+            next_run: None,
+        };
+        schedule.refresh(&state.activity);
+        state.roots.push(schedule);
+    }
+
+    pub fn roots(&self) -> Vec<String> {
+        self.state.lock().unwrap().roots.iter()
+            .map(|schedule| schedule.root.clone())
+            .collect()
+    }
+
+    // Note that the device is in use, delaying any crawls that wait for it to go idle
+    pub fn touch(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.activity.last_activity = Local::now();
+
+        let ScheduleState{ ref mut roots, ref activity } = *state;
+        for schedule in roots.iter_mut() {
+            schedule.refresh(activity);
+        }
+    }
+
+    // Collect all roots whose crawl is due, marking them as having been run
+    pub fn take_due(&self) -> Vec<String> {
+        let now = Local::now();
+        let mut state = self.state.lock().unwrap();
+
+        let ScheduleState{ ref mut roots, ref activity } = *state;
+        roots.iter_mut()
+            .filter(|schedule| schedule.next_run.map_or(false, |next| next <= now))
+            .map(|schedule| {
+                schedule.started = true;
+                schedule.last_run = Some(now);
+                schedule.refresh(activity);
+                schedule.root.clone()
+            })
+            .collect()
+    }
+
+    // Record that the root was queued outside of its schedule (ie. through the `reindex` action)
+    pub fn mark_run(&self, root: &str) {
+        let mut state = self.state.lock().unwrap();
+
+        let ScheduleState{ ref mut roots, ref activity } = *state;
+        for schedule in roots.iter_mut().filter(|schedule| schedule.root == root) {
+            schedule.last_run = Some(Local::now());
+            schedule.refresh(activity);
+        }
+    }
+
+    // Produce a description of every root's schedule for reporting to apps
+    pub fn status(&self) -> serde_json::Value {
+        let state = self.state.lock().unwrap();
+        let roots = state.roots.iter()
+            .map(|schedule| json!({
+                "root": schedule.root,
+                "schedule": schedule.spec,
+                "last_run": schedule.last_run.map(|time| time.to_rfc3339()),
+                "next_run": schedule.next_run.map(|time| time.to_rfc3339()),
+            }))
+            .collect::<Vec<_>>();
+
+        json!(roots)
+    }
+}
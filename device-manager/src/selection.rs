@@ -0,0 +1,129 @@
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+/*
+A role may be provided by several connections at once (eg. two audio plugins). Whenever a message is sent
+To a role, the role's selection policy orders the live providers by preference. The message is delivered
+To the first provider that accepts it, failing over to the next one if the send fails:
+
+  priority      prefer providers with the highest handshake `priority`, in order of registration
+  round-robin   rotate through the providers on every message
+  least-loaded  prefer the provider with the fewest requests still waiting on a response
+  most-recent   prefer the provider that most recently sent a message
+  same-device   prefer providers on the same device as the sender, falling back to priority
+
+Policies are set either through the `--role-policy` argument or through the `selection` field of a
+Handshake `body`. Configured policies always take precedence over those advertised in handshakes.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelectionPolicy {
+    Priority,
+    RoundRobin,
+    LeastLoaded,
+    MostRecent,
+    SameDevice,
+}
+
+impl SelectionPolicy {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec.trim().to_lowercase().replace('_', "-").as_str() {
+            "priority" | "primary" => Ok(SelectionPolicy::Priority),
+            "round-robin" => Ok(SelectionPolicy::RoundRobin),
+            "least-loaded" => Ok(SelectionPolicy::LeastLoaded),
+            "most-recent" => Ok(SelectionPolicy::MostRecent),
+            "same-device" => Ok(SelectionPolicy::SameDevice),
+            _ => Err(format!("Unrecognized selection policy {:?}", spec)),
+        }
+    }
+}
+
+impl Default for SelectionPolicy {
+    fn default() -> Self {
+        SelectionPolicy::Priority
+    }
+}
+
+// The routing state of a single provider for a role
+pub struct Candidate {
+    pub addr: SocketAddr,
+    pub priority: i64,
+    pub load: usize,
+    pub last_active: Instant,
+}
+
+#[derive(Default)]
+pub struct RoleSelector {
+    configured: HashMap<String, SelectionPolicy>,
+    advertised: HashMap<String, SelectionPolicy>,
+    cursors: HashMap<String, usize>,
+}
+
+impl RoleSelector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Set the policy for a role from the manager's configuration
+    pub fn configure(&mut self, role: &str, policy: SelectionPolicy) {
+        self.configured.insert(role.to_string(), policy);
+    }
+
+    // Set the policy for a role as requested by an app's handshake
+    pub fn advertise(&mut self, role: &str, policy: SelectionPolicy) {
+        if let Some(configured) = self.configured.get(role) {
+            if *configured != policy {
+                info!("Ignoring {:?} selection policy for role {:?} in favor of configured {:?} policy", policy, role, configured);
+            }
+        }
+        self.advertised.insert(role.to_string(), policy);
+    }
+
+    pub fn policy(&self, role: &str) -> SelectionPolicy {
+        self.configured.get(role)
+            .or(self.advertised.get(role))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Order the role's providers by preference for a message coming from `sender`
+    // NOTE: `candidates` must be given in order of registration
+    pub fn order(&mut self, role: &str, sender: &SocketAddr, mut candidates: Vec<Candidate>) -> Vec<SocketAddr> {
+        let policy = self.policy(role);
+
+        // NOTE: All sorts are stable, so ties are always broken by registration order
+        match policy {
+            SelectionPolicy::Priority => {
+                candidates.sort_by(|a, b| b.priority.cmp(&a.priority));
+            },
+            SelectionPolicy::RoundRobin => {
+                if !candidates.is_empty() {
+                    let cursor = self.cursors.entry(role.to_string()).or_insert(0);
+                    let len = candidates.len();
+                    candidates.rotate_left(*cursor % len);
+                    *cursor = (*cursor + 1) % len;
+                }
+            },
+            SelectionPolicy::LeastLoaded => {
+                candidates.sort_by(|a, b| a.load.cmp(&b.load).then(b.priority.cmp(&a.priority)));
+            },
+            SelectionPolicy::MostRecent => {
+                candidates.sort_by(|a, b| b.last_active.cmp(&a.last_active));
+            },
+            SelectionPolicy::SameDevice => {
+                // NOTE: Apps connected through the unix socket are registered under the unspecified address
+                let on_device = |ip: IpAddr| ip.is_loopback() || ip.is_unspecified();
+                let remote = |candidate: &Candidate| candidate.addr.ip() != sender.ip()
+                    && !(on_device(candidate.addr.ip()) && on_device(sender.ip()));
+                candidates.sort_by(|a, b| remote(a).cmp(&remote(b)).then(b.priority.cmp(&a.priority)));
+            },
+        }
+
+        trace!("Ordered providers for role {:?} by {:?} policy", role, policy);
+        candidates.into_iter()
+            .map(|candidate| candidate.addr)
+            .collect()
+    }
+}
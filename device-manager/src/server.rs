@@ -1,33 +1,90 @@
 
+use std::cmp;
+use std::io::Error;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use clap;
 use futures;
+use futures::future;
+use tokio;
 use tokio::prelude::*;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{Delay, Interval};
 
-use networking::spawn::spawn_connection;
+use networking::codec::{self, Codec, Negotiation};
+use networking::queue::OverflowPolicy;
+use networking::spawn::spawn_stream;
+use networking::tls::{ClientAuth, PeerCertificate, TlsConfig, TlsOptions};
+use networking::websocket::spawn_websocket;
+use device;
 use device::DeviceManager;
+use local;
+use local::LocalOptions;
+use selection::SelectionPolicy;
+
+// How the connections are secured and encoded
+#[derive(Clone)]
+struct Transport {
+    tls: Option<TlsConfig>,
+
+    // The codec picked for the connections that we make to other managers
+    link_codec: Arc<dyn Codec>,
+}
 
 // NOTE: I need the 'Box' type because I'm returning 2 different 'futures::Future' types
 // The `impl Trait` syntax doesn't work in this case because of compiler type-checking requirements
-fn create_server(device: DeviceManager, addr: SocketAddr, parent: Option<SocketAddr>) -> Box<dyn futures::Future<Item=(), Error=()> + Send> {
+fn create_server(device: DeviceManager, addr: Option<SocketAddr>, socket: Option<LocalOptions>, websocket: Option<(SocketAddr, Vec<String>)>, parent: Option<(SocketAddr, Duration)>, peers: Vec<SocketAddr>, transport: Transport) -> Box<dyn futures::Future<Item=(), Error=()> + Send> {
     let ai_device = device.clone();
-    info!("Spawning device manager server listening on {:?}", addr);
-    let server = TcpListener::bind(&addr)
-        .expect("Failed to bind server to specified socket address")
-        .incoming()
-        .for_each(move |conn| Ok(spawn_connection(conn, device.clone())))
-        .map_err(|err| error!("Server Error: {:?}", err));
+    let listener_transport = transport.clone();
+    let server: Box<dyn futures::Future<Item=(), Error=()> + Send> = match addr {
+        Some(addr) => {
+            info!("Spawning device manager server listening on {:?}", addr);
+            let listener = TcpListener::bind(&addr)
+                .expect("Failed to bind server to specified socket address")
+                .incoming()
+                .for_each(move |conn| {
+                    // NOTE: The handshake is performed in its own task so that a slow client can't hold up the listener
+                    let accept = establish(conn, device.clone(), listener_transport.clone(), false)
+                        .map(|_| ())
+                        .map_err(|err| warn!("Failed to accept connection: {:?}", err));
+                    tokio::spawn(accept);
+                    Ok(())
+                })
+                .map_err(|err| error!("Server Error: {:?}", err));
+            Box::new(listener)
+        },
+        None => Box::new(future::ok(())),
+    };
+
+    // Let local apps connect through the unix socket as well
+    let server = match socket {
+        Some(socket) => Box::new(server.join(local::listen(ai_device.clone(), socket)).map(|_| ())),
+        None => server,
+    };
 
-    if let Some(paddr) = parent {
+    // Let browser-based apps connect through the WebSocket gateway
+    let server = match websocket {
+        Some((ws_addr, origins)) => Box::new(server.join(listen_websocket(ai_device.clone(), ws_addr, origins, transport.tls.clone())).map(|_| ())),
+        None => server,
+    };
+
+    // Link up with the managers on the other devices that we know about
+    let peers = future::join_all(peers.into_iter()
+        .map(|peer| {
+            info!("Connecting to peer device at {:?}", peer);
+            connect_manager(ai_device.clone(), peer, transport.clone())
+        })
+        .collect::<Vec<_>>());
+    let server = server.join(peers).map(|_| ());
+
+    if let Some((paddr, max_backoff)) = parent {
         info!("Initializing web-node device-manager");
         info!("Connecting to parent device at {:?}", paddr);
 
-        let client = TcpStream::connect(&paddr)
-            .and_then(move |conn| Ok(spawn_connection(conn, ai_device)))
-            .map_err(|err| { error!("Client error: {:?}", err) });
-
+        let client = maintain_uplink(ai_device, paddr, max_backoff, transport);
         Box::new(server.join(client).map(|_| ()))
 
     } else {
@@ -36,6 +93,118 @@ fn create_server(device: DeviceManager, addr: SocketAddr, parent: Option<SocketA
     }
 }
 
+// Accept WebSocket connections on `addr`, performing the TLS handshake first if it's enabled (ie. `wss://`)
+fn listen_websocket(device: DeviceManager, addr: SocketAddr, origins: Vec<String>, tls: Option<TlsConfig>) -> Box<dyn futures::Future<Item=(), Error=()> + Send> {
+    info!("Spawning device manager WebSocket gateway listening on {:?}", addr);
+    let gateway = TcpListener::bind(&addr)
+        .expect("Failed to bind WebSocket gateway to specified socket address")
+        .incoming()
+        .for_each(move |conn| {
+            let peer = match conn.peer_addr() {
+                Ok(peer) => peer,
+                Err(err) => {
+                    warn!("Failed to extract peer address from WebSocket connection: {:?}", err);
+                    return Ok(());
+                },
+            };
+
+            // NOTE: The upgrade is performed in its own task so that a slow client can't hold up the listener
+            let (device, origins) = (device.clone(), origins.clone());
+            let upgrade: Box<dyn futures::Future<Item=(), Error=Error> + Send> = match tls {
+                Some(ref tls) => Box::new(tls.accept(conn).and_then(move |stream| spawn_websocket(stream, peer, device, origins))),
+                None => spawn_websocket(conn, peer, device, origins),
+            };
+            tokio::spawn(upgrade.map_err(move |err| warn!("Failed to accept WebSocket connection from {:?}: {:?}", peer, err)));
+            Ok(())
+        })
+        .map_err(|err| error!("WebSocket Gateway Error: {:?}", err));
+
+    Box::new(gateway)
+}
+
+// Start serving the connection, performing the TLS handshake first if it's enabled
+// NOTE: We pick the codec for connections that we make, and let the other side pick it for the rest
+fn establish(conn: TcpStream, device: DeviceManager, transport: Transport, outbound: bool) -> Box<dyn futures::Future<Item=SocketAddr, Error=Error> + Send> {
+    let addr = match conn.peer_addr() {
+        Ok(addr) => addr,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    let negotiation = if outbound { Negotiation::Offer(transport.link_codec) } else { Negotiation::Accept };
+    match transport.tls {
+        None => {
+            spawn_stream(conn, addr, device, negotiation);
+            Box::new(future::ok(addr))
+        },
+        Some(ref tls) if outbound => Box::new(tls.connect(conn)
+            .map(move |stream| {
+                info!("Established TLS connection to {:?} (certificate {:?})", addr, stream.peer_fingerprint());
+                spawn_stream(stream, addr, device, negotiation);
+                addr
+            })),
+        Some(ref tls) => Box::new(tls.accept(conn)
+            .map(move |stream| {
+                info!("Accepted TLS connection from {:?} (certificate {:?})", addr, stream.peer_fingerprint());
+                spawn_stream(stream, addr, device, negotiation);
+                addr
+            })),
+    }
+}
+
+// Connect to the device manager at `addr` and start a link with it
+// NOTE: Failing to connect is only logged, as the manager can still serve its own device
+fn connect_manager(device: DeviceManager, addr: SocketAddr, transport: Transport) -> Box<dyn futures::Future<Item=(), Error=()> + Send> {
+    let link_device = device.clone();
+    let connect = TcpStream::connect(&addr)
+        .and_then(move |conn| establish(conn, device, transport, true))
+        .map(move |conn_addr| link_device.link_manager(conn_addr))
+        .then(move |res| {
+            if let Err(err) = res {
+                error!("Failed to connect to device manager at {:?}: {:?}", addr, err);
+            }
+            Ok(())
+        });
+
+    Box::new(connect)
+}
+
+// Keep a link to the parent device manager, reconnecting with exponential backoff whenever it drops
+fn maintain_uplink(device: DeviceManager, parent: SocketAddr, max_backoff: Duration, transport: Transport) -> Box<dyn futures::Future<Item=(), Error=()> + Send> {
+    let initial_backoff = Duration::from_secs(INITIAL_BACKOFF_SECS);
+
+    let uplink = future::loop_fn(Duration::from_secs(0), move |backoff| {
+        let device = device.clone();
+        let transport = transport.clone();
+        Delay::new(Instant::now() + backoff)
+            .map_err(|err| error!("Parent reconnection timer failed: {:?}", err))
+            .and_then(move |_| {
+                device.uplink_connecting();
+                let conn_device = device.clone();
+                TcpStream::connect(&parent)
+                    .and_then(move |conn| establish(conn, conn_device, transport, true))
+                    .then(move |res| -> Box<dyn futures::Future<Item=future::Loop<(), Duration>, Error=()> + Send> {
+                        match res {
+                            Ok(conn_addr) => {
+                                match device.uplink_connected(conn_addr) {
+                                    // Start reconnecting as soon as the link drops
+                                    Some(closed) => Box::new(closed.then(move |_| Ok(future::Loop::Continue(initial_backoff)))),
+                                    None => Box::new(future::ok(future::Loop::Break(()))),
+                                }
+                            },
+                            Err(err) => {
+                                device.uplink_failed();
+                                let backoff = cmp::min(cmp::max(backoff * 2, initial_backoff), max_backoff);
+                                warn!("Failed to connect to parent device at {:?}: {:?}. Retrying in {:?}", parent, err, backoff);
+                                Box::new(future::ok(future::Loop::Continue(backoff)))
+                            },
+                        }
+                    })
+            })
+    });
+
+    Box::new(uplink)
+}
+
 pub fn launch<'a>(device: DeviceManager, args: &'a clap::ArgMatches) -> impl futures::Future {
     trace!("Launching server task system");
 
@@ -46,12 +215,172 @@ pub fn launch<'a>(device: DeviceManager, args: &'a clap::ArgMatches) -> impl fut
         .expect("Value of `addr` field was not a valid socket address");
     info!("Parsed device-server listening address: {:?}", addr);
 
-    // let parent = "127.0.0.1:6141".parse::<SocketAddr>().ok();
-    let parent = None;
+    let socket = args.value_of("socket").map(|path| {
+        let mode = args.value_of("socket-mode")
+            .map(|mode| u32::from_str_radix(mode, 8).expect("Value of `socket-mode` field was not a valid octal file mode"))
+            .unwrap_or(DEFAULT_SOCKET_MODE);
+        let allowed_uids = args.values_of("socket-allow-uid")
+            .into_iter()
+            .flat_map(|uids| uids)
+            .map(|uid| uid.parse::<u32>().expect("Value of `socket-allow-uid` field was not a valid user id"))
+            .collect();
+
+        LocalOptions{ path: PathBuf::from(path), mode: mode, allowed_uids: allowed_uids }
+    });
+    info!("Parsed device-server unix socket: {:?}", socket);
+
+    let websocket = args.value_of("websocket-addr").map(|ws_addr| {
+        let ws_addr = ws_addr.parse::<SocketAddr>().expect("Value of `websocket-addr` field was not a valid socket address");
+        let origins = args.values_of("websocket-origin")
+            .into_iter()
+            .flat_map(|origins| origins)
+            .map(String::from)
+            .collect::<Vec<_>>();
+        (ws_addr, origins)
+    });
+    info!("Parsed device-server WebSocket gateway: {:?}", websocket);
+
+    // Local apps may be limited to the unix socket, so that no other device (or local user) can reach us over tcp
+    let socket_only = args.value_of("socket-only")
+        .map(|only| only.to_lowercase().parse::<bool>().expect("Value of `socket-only` field was not a valid boolean"))
+        .unwrap_or(false);
+    let addr = if socket_only {
+        if socket.is_none() {
+            panic!("`socket-only` requires a `socket` path to listen on");
+        }
+        None
+    } else {
+        Some(addr)
+    };
+
+    let parent = args.value_of("parent")
+        .map(|parent| parent.parse::<SocketAddr>().expect("Value of `parent` field was not a valid socket address"));
     info!("Parsed device-server parent address: {:?}", parent);
 
+    let parent = parent.map(|parent| {
+        let buffer_size = args.value_of("parent-buffer")
+            .map(|size| size.parse::<usize>().expect("Value of `parent-buffer` field was not a valid number"))
+            .unwrap_or(DEFAULT_PARENT_BUFFER);
+        let max_backoff = args.value_of("parent-max-backoff")
+            .map(|secs| secs.parse::<u64>().expect("Value of `parent-max-backoff` field was not a valid number"))
+            .unwrap_or(DEFAULT_MAX_BACKOFF_SECS);
+
+        device.set_parent(parent, buffer_size);
+        (parent, Duration::from_secs(max_backoff))
+    });
+
+    // Configure the routing loop protection
+    let seen_cache_size = args.value_of("seen-cache-size")
+        .map(|size| size.parse::<usize>().expect("Value of `seen-cache-size` field was not a valid number"))
+        .unwrap_or(device::DEFAULT_SEEN_CACHE_SIZE);
+    let max_hops = args.value_of("max-hops")
+        .map(|hops| hops.parse::<usize>().expect("Value of `max-hops` field was not a valid number"))
+        .unwrap_or(device::DEFAULT_MAX_HOPS);
+    device.set_routing_limits(seen_cache_size, max_hops);
+
+    // Apply any configured role selection policies
+    for spec in args.values_of("role-policy").into_iter().flat_map(|specs| specs) {
+        match spec.rfind('=').map(|idx| (&spec[..idx], SelectionPolicy::parse(&spec[idx + 1..]))) {
+            Some((role, Ok(policy))) => device.set_role_policy(role, policy),
+            Some((_, Err(err))) => warn!("Ignoring `role-policy` {:?}: {}", spec, err),
+            None => warn!("Ignoring `role-policy` {:?}: Expected a value of the form ROLE=POLICY", spec),
+        }
+    }
+
+    // Require apps to authenticate, and restrict what they may do once they have
+    if let Some(secret) = args.value_of("auth-secret") {
+        info!("Requiring connections to authenticate in their handshakes");
+        device.set_auth_secret(secret);
+    }
+
+    let permission_default = match args.value_of("permission-default").unwrap_or("allow") {
+        "allow" => true,
+        "deny" => false,
+        _ => panic!("Value of `permission-default` field must be one of `allow` or `deny`"),
+    };
+    device.set_permission_default(permission_default);
+
+    for (name, allow) in vec![("allow", true), ("deny", false)] {
+        for spec in args.values_of(name).into_iter().flat_map(|specs| specs) {
+            if let Err(err) = device.add_permission_rules(spec, allow) {
+                panic!("Value of `{}` field {:?} was not a valid permission rule: {}", name, spec, err);
+            }
+        }
+    }
+
+    // Bound the outgoing queue of every connection, so a stuck app can't make us buffer messages without limit
+    let queue_capacity = args.value_of("queue-capacity")
+        .map(|size| size.parse::<usize>().expect("Value of `queue-capacity` field was not a valid number"))
+        .unwrap_or(DEFAULT_QUEUE_CAPACITY);
+    let queue_overflow = args.value_of("queue-overflow")
+        .map(|policy| OverflowPolicy::parse(policy).expect("Value of `queue-overflow` field was not a valid overflow policy"))
+        .unwrap_or(OverflowPolicy::Block);
+    device.set_queue_options(queue_capacity, queue_overflow);
+
+    // Periodically fail any routed requests that have gone unanswered for too long
+    let request_timeout = args.value_of("request-timeout")
+        .map(|secs| secs.parse::<u64>().expect("Value of `request-timeout` field was not a valid number"))
+        .unwrap_or(device::DEFAULT_REQUEST_TIMEOUT_SECS);
+    device.set_request_timeout(Duration::from_secs(request_timeout));
+
+    // Notice apps (and managers) that have stopped responding, instead of routing to them forever
+    let heartbeat_interval = args.value_of("heartbeat-interval")
+        .map(|secs| secs.parse::<u64>().expect("Value of `heartbeat-interval` field was not a valid number"))
+        .unwrap_or(device::DEFAULT_HEARTBEAT_INTERVAL_SECS);
+    let heartbeat_misses = args.value_of("heartbeat-misses")
+        .map(|misses| misses.parse::<u32>().expect("Value of `heartbeat-misses` field was not a valid number"))
+        .unwrap_or(device::DEFAULT_HEARTBEAT_MISSES);
+    device.set_heartbeat_options(Duration::from_secs(heartbeat_interval), heartbeat_misses);
+
+    let expiry_device = device.clone();
+    let expire_requests = Interval::new(Instant::now(), Duration::from_secs(1))
+        .for_each(move |_| {
+            expiry_device.expire_requests();
+            Ok(())
+        })
+        .map_err(|err| error!("Request timeout task failed: {:?}", err));
+
+    // Secure the listener and the links to other managers with TLS when a certificate is configured
+    let tls = match (args.value_of("tls-cert"), args.value_of("tls-key")) {
+        (Some(cert), Some(key)) => {
+            let client_auth = args.value_of("tls-client-auth")
+                .map(|auth| ClientAuth::parse(auth).expect("Value of `tls-client-auth` field must be one of `none`, `optional`, or `required`"))
+                .unwrap_or(ClientAuth::None);
+            let options = TlsOptions{
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+                ca: args.value_of("tls-ca").map(PathBuf::from),
+                client_auth: client_auth,
+                pins: args.values_of("tls-pin").into_iter().flat_map(|pins| pins).map(String::from).collect(),
+            };
+            info!("Parsed device-server TLS configuration: {:?}", options);
+
+            Some(TlsConfig::load(&options).expect("Failed to load the TLS certificate configuration"))
+        },
+        (None, None) => None,
+        _ => panic!("Both `tls-cert` and `tls-key` must be given to enable TLS"),
+    };
+
+    // Pick the codec that our links to other managers use
+    let link_codec = args.value_of("link-codec")
+        .map(|name| codec::lookup(name).expect("Value of `link-codec` field must be one of `json`, `msgpack`, or `cbor`"))
+        .unwrap_or(codec::json());
+    info!("Parsed device-server link codec: {:?}", link_codec.name());
+
+    // Parse out the managers on other devices to link with
+    let peers = args.values_of("peer")
+        .into_iter()
+        .flat_map(|peers| peers)
+        .map(|peer| peer.parse::<SocketAddr>().expect("Value of `peer` field was not a valid socket address"))
+        .collect::<Vec<_>>();
+    info!("Parsed device-server peer addresses: {:?}", peers);
+
     // Create the server "futures"
-    create_server(device.clone(), addr, parent)
+    let transport = Transport{ tls: tls, link_codec: link_codec };
+    create_server(device.clone(), addr, socket, websocket, parent, peers, transport)
+        .select2(expire_requests)
+        .map(|_| ())
+        .map_err(|_| ())
 }
 
 pub fn add_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
@@ -62,4 +391,164 @@ pub fn add_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
             .value_name("IP")
             .help("Listening port and address for the device manager")
             .takes_value(true))
+        .arg(Arg::with_name("socket")
+            .long("socket")
+            .value_name("PATH")
+            .help("Path of a unix domain socket to also listen for local plugin connections on")
+            .takes_value(true))
+        .arg(Arg::with_name("socket-mode")
+            .long("socket-mode")
+            .value_name("MODE")
+            .help("Octal file permissions of the unix socket (default 600)")
+            .takes_value(true))
+        .arg(Arg::with_name("socket-allow-uid")
+            .long("socket-allow-uid")
+            .value_name("UID")
+            .help("User id of processes allowed to connect through the unix socket (may be repeated, default any)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("socket-only")
+            .long("socket-only")
+            .value_name("BOOL")
+            .help("Whether to only listen on the unix socket, instead of also listening on `addr`")
+            .takes_value(true))
+        .arg(Arg::with_name("websocket-addr")
+            .long("websocket-addr")
+            .value_name("IP")
+            .help("Listening port and address for the WebSocket gateway used by browser-based apps")
+            .takes_value(true))
+        .arg(Arg::with_name("websocket-origin")
+            .long("websocket-origin")
+            .value_name("ORIGIN")
+            .help("Origin of a web page allowed to connect through the WebSocket gateway (may be repeated, default any)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("parent")
+            .long("parent")
+            .value_name("IP")
+            .help("Socket address of the parent device manager to keep a link to")
+            .takes_value(true))
+        .arg(Arg::with_name("parent-max-backoff")
+            .long("parent-max-backoff")
+            .value_name("SECONDS")
+            .help("Maximum number of seconds to wait between attempts to reconnect to the parent")
+            .takes_value(true))
+        .arg(Arg::with_name("parent-buffer")
+            .long("parent-buffer")
+            .value_name("MESSAGES")
+            .help("Number of upstream messages to hold on to while the link to the parent is down")
+            .takes_value(true))
+        .arg(Arg::with_name("peer")
+            .long("peer")
+            .value_name("IP")
+            .help("Socket address of a device manager on another device to route messages through")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("role-policy")
+            .long("role-policy")
+            .value_name("ROLE=POLICY")
+            .help("Selection policy used to choose between apps registered for a role (priority, round-robin, least-loaded, most-recent, same-device)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("auth-secret")
+            .long("auth-secret")
+            .value_name("SECRET")
+            .help("Shared secret that apps must authenticate with (or sign tokens with) in their handshakes")
+            .takes_value(true))
+        .arg(Arg::with_name("allow")
+            .long("allow")
+            .value_name("SUBJECT=PERMISSION")
+            .help("Permissions to grant to a role, an app (uuid:UUID), or every app (*) (may be repeated)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("deny")
+            .long("deny")
+            .value_name("SUBJECT=PERMISSION")
+            .help("Permissions to deny to a role, an app (uuid:UUID), or every app (*) (may be repeated)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("permission-default")
+            .long("permission-default")
+            .value_name("allow|deny")
+            .help("Whether to allow messages that no permission rule matches")
+            .takes_value(true))
+        .arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .value_name("PATH")
+            .help("PEM certificate chain to secure connections with TLS (requires `tls-key`)")
+            .takes_value(true))
+        .arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .value_name("PATH")
+            .help("PEM private key for the TLS certificate")
+            .takes_value(true))
+        .arg(Arg::with_name("tls-ca")
+            .long("tls-ca")
+            .value_name("PATH")
+            .help("PEM CA certificates used to authenticate the certificates of peers")
+            .takes_value(true))
+        .arg(Arg::with_name("tls-client-auth")
+            .long("tls-client-auth")
+            .value_name("MODE")
+            .help("Whether connecting apps and managers must present a certificate: none, optional, or required")
+            .takes_value(true))
+        .arg(Arg::with_name("tls-pin")
+            .long("tls-pin")
+            .value_name("SHA256")
+            .help("Fingerprint of a peer certificate to accept, rejecting all others (may be repeated)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("link-codec")
+            .long("link-codec")
+            .value_name("CODEC")
+            .help("Codec used to encode messages sent over links to other managers: json, msgpack, or cbor")
+            .takes_value(true))
+        .arg(Arg::with_name("heartbeat-interval")
+            .long("heartbeat-interval")
+            .value_name("SECONDS")
+            .help("Number of seconds between heartbeats sent to every connection that answers them (0 disables heartbeats)")
+            .takes_value(true))
+        .arg(Arg::with_name("heartbeat-misses")
+            .long("heartbeat-misses")
+            .value_name("HEARTBEATS")
+            .help("Number of heartbeats in a row a connection may leave unanswered before it is considered lost")
+            .takes_value(true))
+        .arg(Arg::with_name("max-hops")
+            .long("max-hops")
+            .value_name("HOPS")
+            .help("Maximum number of routing steps a message may take before it is dropped")
+            .takes_value(true))
+        .arg(Arg::with_name("queue-capacity")
+            .long("queue-capacity")
+            .value_name("MESSAGES")
+            .help("Number of outgoing messages that may wait on a connection before its overflow policy applies")
+            .takes_value(true))
+        .arg(Arg::with_name("queue-overflow")
+            .long("queue-overflow")
+            .value_name("POLICY")
+            .help("What to do with messages for a connection whose outgoing queue is full")
+            .takes_value(true))
+        .arg(Arg::with_name("request-timeout")
+            .long("request-timeout")
+            .value_name("SECONDS")
+            .help("Number of seconds to wait on a response to a routed request before failing it")
+            .takes_value(true))
+        .arg(Arg::with_name("seen-cache-size")
+            .long("seen-cache-size")
+            .value_name("MESSAGES")
+            .help("Number of recently routed messages remembered for duplicate and loop detection")
+            .takes_value(true))
 }
+
+const INITIAL_BACKOFF_SECS: u64 = 1;
+const DEFAULT_MAX_BACKOFF_SECS: u64 = 60;
+const DEFAULT_PARENT_BUFFER: usize = 256;
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+const DEFAULT_SOCKET_MODE: u32 = 0o600;
@@ -0,0 +1,162 @@
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use clap;
+use futures::future::{self, Either};
+use tokio::prelude::*;
+use tokio::timer::Delay;
+use tokio_signal;
+
+use device::DeviceManager;
+use networking::comm::{Signal, Wait};
+
+/*
+The device manager shuts down in phases once it's asked to, either by a `quit` message, SIGINT, or SIGTERM:
+
+  signal     the listener, indexer, and timer tasks are woken and stop, so no new work is taken on
+  drain      every app is sent a `quit` notice and every linked manager a `stop`, after which each connection
+             Is closed as soon as its outgoing queue has been flushed
+  persist    the registered persistence hooks are run (eg. saving the index cache)
+
+The drain phase is bounded by a hard deadline (see `--shutdown-timeout`). Any connection that hasn't closed by
+Then is closed forcibly, so that a hung app can never keep the device manager alive.
+*/
+
+type Hook = Box<dyn FnMut() + Send>;
+
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Signal,
+    drained: Signal,
+    hooks: Arc<Mutex<Vec<(String, Hook)>>>,
+    timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(timeout: Duration) -> Self {
+        Self{
+            requested: Signal::new(),
+            drained: Signal::new(),
+            hooks: Arc::new(Mutex::new(Vec::new())),
+            timeout: timeout,
+        }
+    }
+
+    // Start shutting down the device manager, waking every task that is waiting on the shutdown
+    pub fn trigger(&self, reason: &str) {
+        if self.requested.trigger() {
+            info!("Shutting down the device manager: {}", reason);
+        }
+    }
+
+    // Create a future that completes once the shutdown has been triggered
+    pub fn wait(&self) -> Wait {
+        self.requested.wait()
+    }
+
+    // Mark that every connection has closed
+    pub fn drained(&self) {
+        if self.drained.trigger() {
+            debug!("Every connection has been closed");
+        }
+    }
+
+    // Register a hook to persist state once the connections have been drained
+    pub fn on_shutdown<F: FnMut() + Send + 'static>(&self, name: &str, hook: F) {
+        self.hooks.lock().unwrap().push((name.to_string(), Box::new(hook)));
+    }
+
+    fn run_hooks(&self) {
+        let hooks = self.hooks.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for (name, mut hook) in hooks {
+            debug!("Running shutdown hook {:?}", name);
+            hook();
+        }
+    }
+}
+
+// Drain the device manager's connections and persist its state
+// NOTE: This triggers the shutdown if it hasn't been already (ie. because the server stopped unexpectedly)
+pub fn drain(device: DeviceManager, shutdown: Shutdown) -> impl Future<Item=(), Error=()> {
+    shutdown.trigger("the device manager's tasks have stopped");
+
+    let deadline = Instant::now() + shutdown.timeout;
+    let draining = device.drain_connections();
+    info!("Waiting up to {:?} for {} connections to close", shutdown.timeout, draining);
+
+    shutdown.drained.wait()
+        .select2(Delay::new(deadline))
+        .then(move |res| {
+            if let Ok(Either::B(_)) = res {
+                warn!("Connections did not close before the shutdown deadline. Closing them forcibly");
+                device.close_connections();
+            }
+
+            shutdown.run_hooks();
+            Ok(())
+        })
+}
+
+// Trigger the shutdown when the process is interrupted or terminated
+// NOTE: The returned future completes once the shutdown has been triggered (by any means)
+pub fn listen_for_signals(shutdown: Shutdown) -> impl Future<Item=(), Error=()> {
+    let interrupt = tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map(|_| "received SIGINT")
+        .map_err(|(err, _)| error!("Failed to listen for SIGINT: {:?}", err));
+
+    let signals = interrupt.select(terminate())
+        .map(|(reason, _)| reason)
+        .map_err(|_| ())
+        // NOTE: If we can't listen for signals, the shutdown can still be triggered by a `quit` message
+        .or_else(|_| future::empty::<&'static str, ()>());
+
+    let trigger = shutdown.clone();
+    signals.select2(shutdown.wait())
+        .map(move |res| {
+            if let Either::A((reason, _)) = res {
+                trigger.trigger(reason);
+            }
+        })
+        .map_err(|_| ())
+}
+
+#[cfg(unix)]
+fn terminate() -> Box<dyn Future<Item=&'static str, Error=()> + Send> {
+    use tokio_signal::unix::{Signal as UnixSignal, SIGTERM};
+
+    let terminate = UnixSignal::new(SIGTERM)
+        .flatten_stream()
+        .into_future()
+        .map(|_| "received SIGTERM")
+        .map_err(|(err, _)| error!("Failed to listen for SIGTERM: {:?}", err));
+    Box::new(terminate)
+}
+
+#[cfg(not(unix))]
+fn terminate() -> Box<dyn Future<Item=&'static str, Error=()> + Send> {
+    Box::new(future::empty())
+}
+
+pub fn create<'a>(args: &'a clap::ArgMatches) -> Shutdown {
+    let timeout = args.value_of("shutdown-timeout")
+        .map(|secs| secs.parse::<u64>().expect("Value of `shutdown-timeout` field was not a valid number"))
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+    info!("Parsed device-server shutdown timeout: {} seconds", timeout);
+
+    Shutdown::new(Duration::from_secs(timeout))
+}
+
+pub fn add_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    use clap::Arg;
+
+    app.arg(Arg::with_name("shutdown-timeout")
+            .long("shutdown-timeout")
+            .value_name("SECONDS")
+            .help("Number of seconds to wait on connections to close during shutdown before closing them forcibly")
+            .takes_value(true))
+}
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
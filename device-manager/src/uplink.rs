@@ -0,0 +1,129 @@
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use chrono::{DateTime, Local};
+use futures::sync::oneshot;
+use serde_json;
+
+/*
+A device manager may be given a "parent" manager (see `--parent`) that it keeps a link to at all times. The
+Link is reconnected with exponential backoff whenever it drops, and the manager re-sends its link handshake
+(and with it, the roles of its apps) every time the link comes back up. Messages that need to travel upstream
+While the link is down are buffered, and sent on to the parent once the link is re-established.
+*/
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkStatus {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+pub struct Uplink {
+    parent: SocketAddr,
+    status: LinkStatus,
+    conn: Option<SocketAddr>,
+    attempts: u32,
+    since: DateTime<Local>,
+
+    // Upstream messages waiting on the link to come back up
+    buffer: VecDeque<serde_json::Value>,
+    capacity: usize,
+    dropped: u64,
+
+    // Resolves when the current connection to the parent closes
+    closed: Option<oneshot::Sender<()>>,
+}
+
+impl Uplink {
+    pub fn new(parent: SocketAddr, capacity: usize) -> Self {
+        Self{
+            parent: parent,
+            status: LinkStatus::Disconnected,
+            conn: None,
+            attempts: 0,
+            since: Local::now(),
+            buffer: VecDeque::new(),
+            capacity: capacity,
+            dropped: 0,
+            closed: None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.status == LinkStatus::Connected
+    }
+
+    pub fn conn(&self) -> Option<SocketAddr> {
+        self.conn.filter(|_| self.is_connected())
+    }
+
+    pub fn connecting(&mut self) {
+        self.attempts += 1;
+        self.set_status(LinkStatus::Connecting);
+    }
+
+    // Record a failed connection attempt
+    pub fn failed(&mut self) {
+        self.set_status(LinkStatus::Disconnected);
+    }
+
+    // Record that the parent is reachable through `conn`, returning a signal for when the link drops
+    pub fn connected(&mut self, conn: SocketAddr) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.conn = Some(conn);
+        self.attempts = 0;
+        self.closed = Some(tx);
+        self.set_status(LinkStatus::Connected);
+        rx
+    }
+
+    // Record that the connection closed, returning whether it was the link to the parent
+    pub fn disconnected(&mut self, conn: &SocketAddr) -> bool {
+        if self.conn != Some(*conn) {
+            return false;
+        }
+
+        self.conn = None;
+        self.set_status(LinkStatus::Disconnected);
+        if let Some(closed) = self.closed.take() {
+            let _ = closed.send(());
+        }
+        true
+    }
+
+    // Hold on to a message until the link comes back up, dropping the oldest message if the buffer is full
+    pub fn buffer(&mut self, msg: serde_json::Value) {
+        if self.buffer.len() >= self.capacity {
+            self.buffer.pop_front();
+            self.dropped += 1;
+            warn!("Upstream buffer is full. Dropping the oldest message waiting on the link to {:?}", self.parent);
+        }
+        self.buffer.push_back(msg);
+    }
+
+    pub fn drain(&mut self) -> Vec<serde_json::Value> {
+        self.buffer.drain(..).collect()
+    }
+
+    fn set_status(&mut self, status: LinkStatus) {
+        if self.status != status {
+            info!("Link to parent device manager {:?} is now {:?}", self.parent, status);
+            self.status = status;
+            self.since = Local::now();
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "addr": self.parent.to_string(),
+            "status": self.status,
+            "since": self.since.to_rfc3339(),
+            "attempts": self.attempts,
+            "buffered": self.buffer.len(),
+            "dropped": self.dropped,
+        })
+    }
+}
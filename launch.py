@@ -73,7 +73,7 @@ def launch_device(config):
     print("Launching the device manager")
     manager = config['device_manager']
 
-    if not _will_plugins_connect(manager['addr'], config['loader_config']):
+    if not _will_plugins_connect(manager, config['loader_config']):
         print("Configuration Error: Plugins not set to connect to local device manager")
         return 1
 
@@ -106,8 +106,11 @@ def launch_device(config):
         for proc in procs:
             proc.kill()
 
-def _will_plugins_connect(manager_addr, loader_config):
-    return manager_addr == "127.0.0.1:{}".format(loader_config['port'])
+def _will_plugins_connect(manager, loader_config):
+    if loader_config.get('socket') is not None:
+        return manager.get('socket') == loader_config['socket']
+
+    return manager['addr'] == "127.0.0.1:{}".format(loader_config['port'])
 
 
 def launch_ai_node(config):
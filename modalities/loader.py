@@ -2,7 +2,10 @@
 
 import argparse
 import asyncio
+import hashlib
+import inspect
 import socket
+import ssl
 import threading
 import time
 import traceback
@@ -12,7 +15,7 @@ from common import plugins as plugin_system
 from common.msg import Message
 
 
-def reader(comm, sock, plugin, loop):
+def reader(comm, sock, codec, plugin, loop):
     """
     Thread callback to dispatch and handle messages sent to this plugin
     """
@@ -30,23 +33,26 @@ def reader(comm, sock, plugin, loop):
             log.error("Exception while handling message id={}: {}".format(msg.id, e))
             log.error("  " + traceback.format_exc())
 
+            msg.args = [{'code': 'handler_failed', 'message': str(e), 'action': msg.action}]
             msg.action = 'error'
-            msg.args = str(e)
             msg.return_to_sender()
             comm.send(msg, log)
 
     # For every message that we receive from the server
-    for msg in protocol.JsonCodec.get_messages(sock, log):
+    for msg in codec.get_messages(sock, log, on_heartbeat=comm.send_heartbeat):
         if Message.is_quit(msg):
             log.debug("Received quit message in reader thread: msg.id={}".format(msg.id))
             break
 
         # If we have requested this message in some other handler
-        elif msg.id in comm.events:
-            log.info("Received response to message id={}. Resuming sender routine".format(msg.id))
+        # NOTE: Replies created by the device-manager (ie. errors) reference the request through 'parent_id'
+        # Acks also reference the request, but only report delivery so they're passed on to the plugin handles
+        elif msg.id in comm.events or (msg.parent_id in comm.events and msg.action != 'ack'):
+            request_id = msg.id if msg.id in comm.events else msg.parent_id
+            log.info("Received response to message id={}. Resuming sender routine".format(request_id))
 
-            comm.events[msg.id].value = msg
-            loop.call_soon_threadsafe(comm.events[msg.id].set)
+            comm.events[request_id].value = msg
+            loop.call_soon_threadsafe(comm.events[request_id].set)
 
         # Otherwise call the registered plugin handler
         else:
@@ -56,28 +62,76 @@ def reader(comm, sock, plugin, loop):
     log.debug("Closing reader thread")
 
 
-def writer(comm, sock, log):
+def writer(comm, sock, codec, log):
     """
     Thread callback responsible for sending messages out of the plugin
     """
     while True:
         msg = comm.get_msg()
-        protocol.JsonCodec.send_message(msg, sock, log)
+        codec.send_message(msg, sock, log)
 
+        if protocol.is_heartbeat(msg):
+            continue
         if Message.is_quit(msg):
             log.debug("Received quit message in writer thread")
             break
 
 
-async def handshake(plugin, _plugin_handles, comm):
+def secure(sock, loader_args, log):
+    """
+    Wrap the connection to the device-manager in TLS if a certificate authority or pinned certificate is configured
+    """
+    ca, pin = loader_args['tls_ca'], loader_args['tls_pin']
+    if ca is None and pin is None:
+        return sock
+
+    # NOTE: The device-manager is addressed by ip, so its certificate is authenticated by the ca or the pin instead of its name
+    context = ssl.SSLContext(ssl.PROTOCOL_TLS_CLIENT)
+    context.check_hostname = False
+    if pin is not None:
+        context.verify_mode = ssl.CERT_NONE
+    else:
+        context.load_verify_locations(cafile=ca)
+
+    if loader_args['tls_cert'] is not None:
+        context.load_cert_chain(loader_args['tls_cert'], loader_args['tls_key'])
+
+    sock = context.wrap_socket(sock)
+    fingerprint = hashlib.sha256(sock.getpeercert(binary_form=True)).hexdigest()
+    if pin is not None and fingerprint != pin.replace(':', '').lower():
+        sock.close()
+        raise ssl.SSLError("Device-manager certificate {} is not pinned".format(fingerprint))
+
+    log.info("Secured connection to device-manager with TLS (certificate {})".format(fingerprint))
+    return sock
+
+
+async def handshake(plugin, plugin_handles, comm, auth=None):
     plugin.logger.info("Initiating plugin handshake with device-manager")
 
+    # Advertise the actions that this plugin handles (the system 'ack' and 'error' handles are implied)
+    actions = {
+        action: {'help': inspect.getdoc(handle)}
+        for action, handle in plugin_handles.items()
+        if action not in ('ack', 'error')
+    }
+
     msg = Message(plugin=plugin)
     msg.action = 'handshake'
+    msg.body = {'actions': actions, 'protocol': protocol.PROTOCOL_VERSION, 'heartbeat': True}
+    if auth is not None:
+        msg.body['auth'] = auth
     msg.send_to(role='manager')
-    await comm.wait_for_response(msg, plugin.logger)
+    resp = await comm.wait_for_response(msg, plugin.logger)
 
-    plugin.logger.info("Completed plugin handshake with device-manager")
+    # NOTE: The device-manager closes the connection after rejecting the handshake
+    if resp.action == 'error':
+        plugin.logger.error("Device-manager rejected the plugin handshake: {}".format(resp.args))
+        return False
+
+    version = (resp.response or {}).get('protocol', {}).get('version', protocol.PROTOCOL_VERSION)
+    plugin.logger.info("Completed plugin handshake with device-manager (protocol version {})".format(version))
+    return True
 
 
 async def run(plugin, comm, read_thread, write_thread):
@@ -105,7 +159,16 @@ async def run(plugin, comm, read_thread, write_thread):
     except:
         log.error("EXCEPTION: " + traceback.format_exc())
 
-    log.debug("Stopped running main plugin run loop. Sending stop message to device-manager")
+    log.debug("Stopped running main plugin run loop")
+    stop(plugin, comm)
+
+
+def stop(plugin, comm):
+    """
+    Close the connection to the device-manager, which also stops the reader and writer threads
+    """
+    log = plugin.logger
+    log.debug("Sending stop message to device-manager")
 
     msg = Message(plugin=plugin)
     msg.action = Message.STOP
@@ -120,10 +183,18 @@ if __name__ == "__main__":
     # NOTE: Because the plugins are device-local, the host is almost guaranteed to always be `localhost`. However, I will keep the configuration just in case
     parser.add_argument('--host', type=str, default='127.0.0.1', help='ip address of the host server')
     parser.add_argument('--port', type=int, help='port that the server is listening on')
+    parser.add_argument('--socket', type=str, help='path of the device-manager\'s unix socket, used instead of `host` and `port`')
     parser.add_argument('--log-dir', type=str, help='location to write log files')
     parser.add_argument('--log-level', type=str, help='logging level', default='INFO')
     parser.add_argument('--retry-delay', type=int, help='Num seconds to sleep in between connection retries')
     parser.add_argument('--max-retries', type=int, help='Maximum retry attempts before connection failed')
+    parser.add_argument('--tls-ca', type=str, help='CA certificates to authenticate the device manager with over TLS')
+    parser.add_argument('--tls-pin', type=str, help='SHA-256 fingerprint of the device manager certificate to accept over TLS')
+    parser.add_argument('--tls-cert', type=str, help='Certificate to present to the device manager, if it requires client certificates')
+    parser.add_argument('--tls-key', type=str, help='Private key for the `tls-cert` certificate')
+    parser.add_argument('--codec', type=str, default='json', choices=sorted(protocol.CODECS), help='Encoding of the messages sent to the device manager')
+    parser.add_argument('--auth-secret', type=str, help='Shared secret to authenticate with the device manager')
+    parser.add_argument('--auth-token', type=str, help='Token signed for the plugin\'s role (or its role and uuid) to authenticate with the device manager')
     parser.add_argument('plugin', nargs=argparse.REMAINDER, help='Name of the plugin to launch plus all plugin-specific arguments')
 
     # Extract the arguments from the command line
@@ -140,16 +211,20 @@ if __name__ == "__main__":
         exit(1)
 
     # Launch the networking threads (for communicating with the device manager)
-    host, port = loader_args['host'], loader_args['port']
-    sock, num_connection_attempts = socket.socket(), 0
+    # NOTE: Local plugins may connect through the device-manager's unix socket instead of its tcp port
+    if loader_args['socket'] is not None:
+        address, sock = loader_args['socket'], socket.socket(socket.AF_UNIX)
+    else:
+        address, sock = (loader_args['host'], loader_args['port']), socket.socket()
+    num_connection_attempts = 0
 
     # Handle connection errors
     while True:
-        log.debug("Attempting to connect to {}:{}".format(host, port))
+        log.debug("Attempting to connect to {}".format(address))
         num_connection_attempts += 1
 
         try:
-            sock.connect((host, port))
+            sock.connect(address)
             break
 
         except socket.error as e:
@@ -159,19 +234,32 @@ if __name__ == "__main__":
             log.debug("Connection failed: {}".format(e))
             time.sleep(loader_args['retry_delay'])
 
-    log.info("Connected to {}:{}".format(host, port))
+    log.info("Connected to {}".format(address))
+    sock = secure(sock, loader_args, log)
+
+    codec = protocol.CODECS[loader_args['codec']]
+    codec.select(sock, log)
 
     # Create the communication threads
     comm = protocol.CommChannel(handles)
     loop = asyncio.get_event_loop()
-    read_thread = threading.Thread(target=reader, args=(comm, sock, plugin, loop))
+    read_thread = threading.Thread(target=reader, args=(comm, sock, codec, plugin, loop))
     read_thread.start()
-    write_thread = threading.Thread(target=writer, args=(comm, sock, plugin.logger))
+    write_thread = threading.Thread(target=writer, args=(comm, sock, codec, plugin.logger))
     write_thread.start()
 
+    # Authenticate with the device manager (preferring the token, as it only works for the plugin's role)
+    auth = None
+    if loader_args['auth_token'] is not None:
+        auth = {'token': loader_args['auth_token']}
+    elif loader_args['auth_secret'] is not None:
+        auth = {'secret': loader_args['auth_secret']}
+
     # Run the plugin
-    loop.run_until_complete(handshake(plugin, handles, comm))
-    loop.run_until_complete(run(plugin, comm, read_thread, write_thread))
+    if loop.run_until_complete(handshake(plugin, handles, comm, auth)):
+        loop.run_until_complete(run(plugin, comm, read_thread, write_thread))
+    else:
+        stop(plugin, comm)
     plugin.logger.debug("Quit plugin while {} tasks were still running".format(len(asyncio.Task.all_tasks())))
 
     # Clean up everything
@@ -96,11 +96,13 @@ class AudioPlugin(plugins.Plugin):
         return True
 
     async def handle_play(self, msg, comm):
-        if len(msg.args) == 0:
+        # NOTE: Search results forwarded as a play request carry the song paths in the response
+        songs = msg.args if len(msg.args) != 0 else (msg.response or [])
+        if len(songs) == 0:
             self._log.error("Received play request with no song-or-music data. msg.id={}".format(msg.id))
             return
 
-        song = msg.args[0]
+        song = songs[0]
         if not os.path.exists(song):
             self._log.debug("The requested song `{}` does not exist on the system. Assuming it is a search query instead".format(song))
 
@@ -18,19 +18,15 @@ class CliPlugin(plugins.Plugin):
 
         # Handle registration
         self._register_handle('print', CliPlugin.handle_print)
+        self._register_handle('capabilities.changed', CliPlugin.handle_capabilities_changed)
         self._msg_handles = {
 
         }
 
         # CLI command system
         self._known_roles = []          # TODO: What is this for?
-        self._handle_role_map = {       # TODO: Produce this list dynamically
-            'dispatch': ['dispatch'],
-            'play': ['audio'],
-            'search': ['manager'],
-            'stop': ['manager'],
-            'quit': ['manager']
-        }
+        self._handle_role_map = {}      # Populated from the capabilities advertised to the device-manager
+        self._capabilities_stale = True
         self._current_mode = self.CHAT
 
     # TODO: The message handling code needs to be put into a separate coroutine
@@ -38,6 +34,10 @@ class CliPlugin(plugins.Plugin):
     async def run(self, comm):
         with await self._cli_lock:
             self._print_all_msgs()
+            refresh_capabilities = self._capabilities_stale
+
+        if refresh_capabilities:
+            await self._refresh_handle_role_map(comm)
 
         query = input("> ")
         if query == "":
@@ -127,6 +127,32 @@ class CliPlugin(plugins.Plugin):
         with await self._cli_lock:
             self._msgs.append(resp)
 
+    async def _refresh_handle_role_map(self, comm):
+        """
+        Rebuild the handle -> role mapping from the capabilities registered with the device-manager
+        """
+        self._log.debug("Requesting registered capabilities from device-manager")
+
+        msg = Message(plugin=self)
+        msg.action = 'capabilities'
+        msg.send_to(role='manager')
+        resp = await comm.wait_for_response(msg, self._log)
+
+        if resp.action == 'error' or not isinstance(resp.response, dict):
+            return self._log.error("Failed to retrieve capabilities from device-manager: {}".format(resp.json_packet))
+
+        handle_role_map = {}
+        for role, actions in resp.response.items():
+            for action in actions:
+                handle_role_map.setdefault(action, []).append(role)
+
+        with await self._cli_lock:
+            self._known_roles = list(resp.response.keys())
+            self._handle_role_map = handle_role_map
+            self._capabilities_stale = False
+
+        self._log.info("Updated handle role map: {}".format(handle_role_map))
+
     def _print_and_log(self, msg, level, dont_print_log_level=None):
         log_method = getattr(self._log, level)
         log_method(msg)
@@ -141,6 +167,14 @@ class CliPlugin(plugins.Plugin):
         with await self._cli_lock:
             self._msgs.append(' '.join(msg.args))
 
+    async def handle_capabilities_changed(self, msg, comm):
+        """
+        Notification that an app joined or left the network, changing the available actions
+        """
+        self._log.info("Marking handle role map as stale after capabilities change: {}".format(msg.args))
+        with await self._cli_lock:
+            self._capabilities_stale = True
+
     def _print_all_msgs(self):
         if len(self._msgs) != 0:
             for msg in self._msgs:
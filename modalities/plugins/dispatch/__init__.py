@@ -44,13 +44,15 @@ class DispatchPlugin(plugins.Plugin):
             intent = quest['intent'][0]['value']
 
             if intent in self._intent_handles:
-                await self._intent_handles[intent](self, msg, comm, quest)
+                # NOTE: Handles return True when the response has been forwarded to the sender through another app
+                if await self._intent_handles[intent](self, msg, comm, quest):
+                    return
 
             else:
                 self._log.error("Received message with no registered intent handles. msg.id={} msg={}".format(msg.id, msg))
 
+                msg.args = [{'code': 'unknown_action', 'message': "No registered handle for message", 'action': msg.action}]
                 msg.action = 'error'
-                msg.args = "No registered handle for message"
                 msg.return_to_sender()
 
         elif 'greetings' in quest:
@@ -77,7 +79,6 @@ class DispatchPlugin(plugins.Plugin):
             msg.args = "I have no idea what you meant"
             msg.return_to_sender()
 
-        # TODO: This is wrong, it prevents us from "forwarding" messages from the cli plugin to the audio plugin
         comm.send(msg, self._log)
 
 
@@ -101,10 +102,18 @@ class DispatchPlugin(plugins.Plugin):
         if 'search_query' in quest:
             song = quest['search_query'][0]['value']
 
-        self._log.info("Returning audio request to play {}".format(song))
-        msg.action = 'play'
-        msg.args = song
-        msg.send_to(role='audio')
+        # Have the manager send the search results straight on to the audio plugin
+        self._log.info("Forwarding search results for {} to the audio plugin".format(song))
+        search = Message(plugin=self)
+        search.send_to(role='manager')
+        search.parent_id = msg.id
+        search.action = 'search'
+        search.args = song
+        search.forward_to(role='audio', action='play')
+        comm.send(search, self._log)
+
+        msg.response = "Playing {}".format(song)
+        msg.return_to_sender()
 
     async def _handle_find(self, msg, comm, quest):
         self._log.info("Received request to find search query")
@@ -118,8 +127,8 @@ class DispatchPlugin(plugins.Plugin):
             search.args = [q['value'] for q in quest['search_query']]
             self._log.info("Searching network for search terms: {}".format(search.args))
 
-        resp = await comm.wait_for_response(search, self._log)
-
-        self._log.info("Returning search results to requesting application")
-        msg.response = resp.response
-        msg.return_to_sender()
+        # Have the manager send the search results straight back to the requesting application
+        self._log.info("Forwarding search results to requesting application")
+        search.forward_to(**{key: value for key, value in msg.json_packet['sender'].items() if key in ('uuid', 'role', 'addr')})
+        comm.send(search, self._log)
+        return True
@@ -0,0 +1,607 @@
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::future::{self, Either, Loop};
+use futures::sync::{mpsc, oneshot};
+use serde_json::Value;
+use tokio;
+use tokio::codec::{Framed, LengthDelimitedCodec};
+use tokio::io::{Error, ErrorKind};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::timer::Delay;
+use uuid::Uuid;
+
+use super::codec::{self, Codec};
+use super::comm;
+use super::heartbeat::{self, Frame, Heartbeat, HeartbeatOptions};
+use super::protocol;
+
+/*
+Rust apps talk to the device manager through a `Client`, which takes care of the framing, the codec preamble,
+The handshake, and matching replies to the requests they answer. Handlers are registered for the actions that
+The app provides before connecting, so that they can be advertised in the handshake:
+
+  let client = Client::new(ClientOptions::new(addr, "weather"));
+  client.on("forecast", "Forecast the weather for the next day", |_client, msg| Ok(Some(json!("sunny"))));
+  let alerts = client.subscribe("capabilities.changed");
+
+  let app = client.connect()
+      .and_then(|client| {
+          let msg = client.message("capabilities", json!({ "role": "manager" }));
+          client.request(msg)
+      })
+      .map(|reply| println!("{}", reply["resp"]));
+  tokio::run(app.map_err(|err| eprintln!("{:?}", err)));
+
+Any value returned by a handler is sent back to the sender as the `resp` of its message, and any error is sent
+Back as a `handler_failed` error. Requests resolve to the first reply that carries their `message_id`, or that
+References it through `parent_id` (ie. errors created by the device manager), failing if no reply arrives in time.
+Broadcasts are delivered to the streams subscribed to their action (or to `*`).
+
+The connection is spawned onto the tokio runtime, so the client has to be used from within one. Clients only
+Connect once; once the connection has closed, a new client has to be created to reconnect.
+*/
+
+pub type Handler = Box<dyn Fn(&Client, Value) -> Result<Option<Value>, Error> + Send + Sync>;
+
+pub struct ClientOptions {
+    pub addr: SocketAddr,
+    pub role: String,
+    pub uuid: String,
+
+    // The codec that the client picks for its connection
+    pub codec: Arc<dyn Codec>,
+
+    // Credentials sent in the handshake (ie. `{"secret": ...}` or `{"token": ...}`)
+    pub auth: Option<Value>,
+
+    // How often to retry connecting to the device manager, and how long to wait between attempts
+    pub retries: u32,
+    pub retry_delay: Duration,
+
+    // How long requests wait on their reply
+    pub request_timeout: Duration,
+
+    // How often the device manager is sent heartbeats and how many it may miss (`None` only answers its heartbeats)
+    pub heartbeat: Option<HeartbeatOptions>,
+}
+
+impl ClientOptions {
+    pub fn new(addr: SocketAddr, role: &str) -> Self {
+        Self{
+            addr,
+            role: role.to_string(),
+            uuid: Uuid::new_v4().to_string(),
+            codec: codec::json(),
+            auth: None,
+            retries: DEFAULT_RETRIES,
+            retry_delay: Duration::from_secs(DEFAULT_RETRY_DELAY_SECS),
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+            heartbeat: None,
+        }
+    }
+}
+
+struct Action {
+    help: String,
+    handler: Arc<Handler>,
+}
+
+#[derive(Default)]
+struct ClientState {
+    actions: HashMap<String, Action>,
+    subscriptions: HashMap<String, Vec<mpsc::UnboundedSender<Value>>>,
+
+    // Requests waiting on their reply, by `message_id`
+    pending: HashMap<String, oneshot::Sender<Value>>,
+
+    // The queue of messages to write to the device manager, while connected
+    outgoing: Option<mpsc::UnboundedSender<Value>>,
+}
+
+#[derive(Clone)]
+pub struct Client {
+    options: Arc<ClientOptions>,
+    state: Arc<Mutex<ClientState>>,
+    closed: comm::Signal,
+}
+
+impl Client {
+    pub fn new(options: ClientOptions) -> Self {
+        Self{
+            options: Arc::new(options),
+            state: Arc::new(Mutex::new(ClientState::default())),
+            closed: comm::Signal::new(),
+        }
+    }
+
+    pub fn role(&self) -> &str {
+        &self.options.role
+    }
+
+    pub fn uuid(&self) -> &str {
+        &self.options.uuid
+    }
+
+    // Handle messages with the `action` action, advertising it in the handshake
+    // NOTE: Handlers registered after the handshake aren't advertised until the next one
+    pub fn on<F>(&self, action: &str, help: &str, handler: F)
+        where F: 'static + Fn(&Client, Value) -> Result<Option<Value>, Error> + Send + Sync
+    {
+        let action_info = Action{ help: help.to_string(), handler: Arc::new(Box::new(handler)) };
+        self.state.lock().unwrap().actions.insert(action.to_string(), action_info);
+    }
+
+    // Receive every broadcast with the `action` action (or every broadcast for `*`)
+    pub fn subscribe(&self, action: &str) -> mpsc::UnboundedReceiver<Value> {
+        let (sink, source) = mpsc::unbounded();
+        self.state.lock().unwrap().subscriptions.entry(action.to_string()).or_default().push(sink);
+        source
+    }
+
+    // Connect to the device manager (retrying as configured) and complete the handshake
+    pub fn connect(&self) -> Box<dyn Future<Item=Client, Error=Error> + Send> {
+        let addr = self.options.addr;
+        let (retries, retry_delay) = (self.options.retries, self.options.retry_delay);
+
+        let connect = future::loop_fn(0, move |attempt| {
+            TcpStream::connect(&addr)
+                .then(move |res| -> Box<dyn Future<Item=Loop<TcpStream, u32>, Error=Error> + Send> {
+                    match res {
+                        Ok(conn) => Box::new(future::ok(Loop::Break(conn))),
+                        Err(err) if attempt < retries => {
+                            warn!("Failed to connect to device manager at {:?}: {:?}. Retrying in {:?}", addr, err, retry_delay);
+                            let retry = Delay::new(Instant::now() + retry_delay)
+                                .map(move |_| Loop::Continue(attempt + 1))
+                                .map_err(Error::other);
+                            Box::new(retry)
+                        },
+                        Err(err) => Box::new(future::err(err)),
+                    }
+                })
+        });
+
+        let client = self.clone();
+        let connected = connect
+            .and_then(move |conn| {
+                client.attach(conn)?;
+                Ok(client)
+            })
+            .and_then(|client| client.handshake().map(move |_| client));
+
+        Box::new(connected)
+    }
+
+    // Talk to the device manager over an established stream (ie. a tls stream or a unix socket)
+    // NOTE: This doesn't send the handshake, which has to be done before sending anything else
+    pub fn attach<Io>(&self, conn: Io) -> Result<(), Error>
+        where Io: 'static + AsyncRead + AsyncWrite + Send
+    {
+        let addr = self.options.addr;
+        let codec = self.options.codec.clone();
+        let preamble = match codec.name() {
+            "json" => None,
+            _ => Some(Bytes::from(codec::preamble(&*codec)?)),
+        };
+
+        let (sink, source) = mpsc::unbounded();
+        self.state.lock().unwrap().outgoing = Some(sink);
+
+        // Setup the heartbeats
+        // NOTE: Device managers always answer heartbeats
+        let heartbeat = Heartbeat::new();
+        heartbeat.activate();
+        let (monitor, pings) = heartbeat::monitor(heartbeat.clone(), self.options.heartbeat);
+
+        let (writer, reader) = Framed::new(conn, LengthDelimitedCodec::new()).split();
+
+        // Define the handle for incoming communication
+        let read_client = self.clone();
+        let read_codec = codec.clone();
+        let read_action = reader
+            .for_each(move |frame| {
+                heartbeat.seen();
+                let msg = read_codec.decode(&frame)?;
+                read_client.dispatch(msg);
+                Ok(())
+            })
+            .map_err(|err| { error!("Socket read error: {:?}", err); });
+
+        // Define the handle for outgoing communication
+        // NOTE: The preamble is only needed to pick a codec other than json, which older servers don't understand
+        let messages = source
+            .select(pings.map(|seq| Frame::Ping(seq).to_json()))
+            .filter_map(move |msg| match codec.encode(&msg) {
+                Ok(frame) => Some(Bytes::from(frame)),
+                Err(err) => {
+                    error!("Failed to encode message for {:?}: {:?}", addr, err);
+                    None
+                },
+            })
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Outgoing queue failed"));
+        let write_action = stream::iter_ok(preamble)
+            .chain(messages)
+            .forward(writer)
+            .map(|_| ())
+            .map_err(|err| { error!("Socket write error: {:?}", err); });
+
+        // Spawn the connection, cleaning up after it once either side stops
+        let client = self.clone();
+        let action = read_action
+            .select2(write_action)
+            .select2(self.closed.wait())
+            .select2(monitor)
+            .then(move |_| {
+                info!("Closed connection to device manager at {:?}", addr);
+                client.disconnected();
+                Ok(())
+            });
+
+        tokio::spawn(action);
+        Ok(())
+    }
+
+    // Register with the device manager, advertising the actions that have handlers
+    pub fn handshake(&self) -> Box<dyn Future<Item=Value, Error=Error> + Send> {
+        let actions = self.state.lock().unwrap().actions.iter()
+            .map(|(action, info)| (action.clone(), json!({ "help": info.help })))
+            .collect::<serde_json::Map<_, _>>();
+
+        let mut msg = self.message("handshake", json!({ "role": "manager" }));
+        msg["body"] = json!({ "actions": actions, "protocol": protocol::VERSION, "heartbeat": true });
+        if let Some(ref auth) = self.options.auth {
+            msg["body"]["auth"] = auth.clone();
+        }
+
+        info!("Sending handshake to device manager as role {:?} (uuid {:?})", self.options.role, self.options.uuid);
+        self.request(msg)
+    }
+
+    // Create a message from this app, addressed to `dest` (ie. `{"role": "manager"}` or `{"broadcast": true}`)
+    pub fn message(&self, action: &str, dest: Value) -> Value {
+        json!({
+            "message_id": Uuid::new_v4().to_string(),
+            "route": [],
+            "sender": {
+                "role": self.options.role,
+                "uuid": self.options.uuid,
+            },
+            "dest": dest,
+            "action": action,
+        })
+    }
+
+    // Send the message without waiting on any reply
+    pub fn send(&self, msg: Value) -> Result<(), Error> {
+        let state = self.state.lock().unwrap();
+        let outgoing = state.outgoing.as_ref()
+            .ok_or(Error::new(ErrorKind::NotConnected, "Client is not connected to a device manager"))?;
+
+        outgoing.unbounded_send(msg)
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Connection to the device manager has closed"))
+    }
+
+    // Send the message, resolving to its reply
+    // NOTE: `error` replies resolve to an error describing why the request failed
+    pub fn request(&self, mut msg: Value) -> Box<dyn Future<Item=Value, Error=Error> + Send> {
+        let message_id = match msg.get("message_id").and_then(|id| id.as_str()) {
+            Some(id) => id.to_string(),
+            None => return Box::new(future::err(Error::new(ErrorKind::InvalidInput, "Request has no `message_id`"))),
+        };
+
+        // NOTE: Marking the request lets the device manager tell us if the reply never arrives
+        msg["expects_reply"] = json!(true);
+
+        let (sink, reply) = oneshot::channel();
+        self.state.lock().unwrap().pending.insert(message_id.clone(), sink);
+        if let Err(err) = self.send(msg) {
+            self.state.lock().unwrap().pending.remove(&message_id);
+            return Box::new(future::err(err));
+        }
+
+        let client = self.clone();
+        let timeout = self.options.request_timeout;
+        let reply = reply
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Connection to the device manager closed before the reply arrived"))
+            .select2(Delay::new(Instant::now() + timeout))
+            .then(move |res| match res {
+                Ok(Either::A((reply, _))) => check_reply(reply),
+                Ok(Either::B(_)) => {
+                    client.state.lock().unwrap().pending.remove(&message_id);
+                    Err(Error::new(ErrorKind::TimedOut, format!("No reply to request {:?} within {:?}", message_id, timeout)))
+                },
+                Err(Either::A((err, _))) => Err(err),
+                Err(Either::B((err, _))) => {
+                    client.state.lock().unwrap().pending.remove(&message_id);
+                    Err(Error::other(err))
+                },
+            });
+
+        Box::new(reply)
+    }
+
+    // Close the connection to the device manager
+    pub fn close(&self) {
+        self.closed.trigger();
+    }
+
+    // Create a future that completes once the connection to the device manager has closed
+    pub fn closed(&self) -> comm::Wait {
+        self.closed.wait()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.is_triggered()
+    }
+
+    fn dispatch(&self, msg: Value) {
+        match Frame::parse(&msg) {
+            Some(Frame::Ping(seq)) => {
+                // NOTE: The pong can only fail to send once the connection is closing anyways
+                let _ = self.send(Frame::Pong(seq).to_json());
+                return;
+            },
+            Some(Frame::Pong(_)) => return,
+            None => {},
+        }
+
+        let action = msg.get("action").and_then(|action| action.as_str()).unwrap_or("").to_string();
+        if action == "stop" || action == "quit" {
+            info!("Device manager asked the client to {}", action);
+            self.close();
+            return;
+        }
+
+        // Resolve the request that this message answers
+        // NOTE: Acks also reference the request through `parent_id`, but only report delivery so they're handled normally
+        let message_id = msg.get("message_id").and_then(|id| id.as_str()).map(String::from);
+        let parent_id = msg.get("parent_id").and_then(|id| id.as_str()).map(String::from);
+        let waiting = {
+            let mut state = self.state.lock().unwrap();
+            match message_id.and_then(|id| state.pending.remove(&id)) {
+                Some(waiting) => Some(waiting),
+                None if action != "ack" => parent_id.and_then(|id| state.pending.remove(&id)),
+                None => None,
+            }
+        };
+        if let Some(waiting) = waiting {
+            let _ = waiting.send(msg);
+            return;
+        }
+
+        // Pass broadcasts on to their subscribers, dropping any that have stopped listening
+        if msg["dest"]["broadcast"].as_bool().unwrap_or(false) {
+            let mut state = self.state.lock().unwrap();
+            for key in &[action.as_str(), "*"] {
+                if let Some(subscribers) = state.subscriptions.get_mut(*key) {
+                    subscribers.retain(|subscriber| subscriber.unbounded_send(msg.clone()).is_ok());
+                }
+            }
+            return;
+        }
+
+        let handler = self.state.lock().unwrap().actions.get(&action).map(|info| info.handler.clone());
+        let handler = match handler {
+            Some(handler) => handler,
+            None => {
+                warn!("No handler registered for {:?} message {:?}", action, msg.get("message_id"));
+                return;
+            },
+        };
+
+        // Send any results back to the sender
+        let mut reply = msg.clone();
+        reply["dest"] = msg["sender"].clone();
+        match handler(self, msg) {
+            Ok(None) => return,
+            Ok(Some(resp)) => reply["resp"] = resp,
+            Err(err) => {
+                error!("Failed to handle {:?} message {:?}: {:?}", action, reply.get("message_id"), err);
+                reply["action"] = json!("error");
+                reply["args"] = json!([{ "code": "handler_failed", "message": err.to_string(), "action": action }]);
+            },
+        }
+
+        if let Err(err) = self.send(reply) {
+            error!("Failed to send reply to {:?} message: {:?}", action, err);
+        }
+    }
+
+    // Stop waiting on anything from the closed connection
+    fn disconnected(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.outgoing = None;
+        state.pending.clear();
+        state.subscriptions.clear();
+        drop(state);
+
+        self.closed.trigger();
+    }
+}
+
+// Turn `error` replies into errors describing why the request failed
+fn check_reply(reply: Value) -> Result<Value, Error> {
+    if reply.get("action").and_then(|action| action.as_str()) != Some("error") {
+        return Ok(reply);
+    }
+
+    // NOTE: The device manager describes errors in their first argument, while python apps send a plain description
+    let args = reply.get("args").cloned().unwrap_or(Value::Null);
+    let description = match args.get(0).and_then(|err| err.get("message")) {
+        Some(description) => description.as_str().map(String::from).unwrap_or(description.to_string()),
+        None => args.as_str().map(String::from).unwrap_or(args.to_string()),
+    };
+    let kind = match args.get(0).and_then(|err| err.get("code")).and_then(|code| code.as_str()) {
+        Some("unauthenticated") | Some("permission_denied") => ErrorKind::PermissionDenied,
+        Some("malformed_message") | Some("malformed_arguments") => ErrorKind::InvalidInput,
+        Some("request_timeout") => ErrorKind::TimedOut,
+        Some("incompatible_protocol") => ErrorKind::ConnectionRefused,
+        _ => ErrorKind::Other,
+    };
+    Err(Error::new(kind, description))
+}
+
+const DEFAULT_RETRIES: u32 = 5;
+const DEFAULT_RETRY_DELAY_SECS: u64 = 1;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener as StdListener;
+
+    use tokio::net::TcpListener;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    // Serve a single connection as a stand-in for the device manager
+    // NOTE: `echo` is answered with its arguments, `fail` with an error that only references it through `parent_id`,
+    // `announce` with a few broadcasts, and `slow` never at all
+    fn serve(listener: TcpListener) -> impl Future<Item=(), Error=()> + Send {
+        listener.incoming()
+            .into_future()
+            .map_err(|(err, _)| panic!("Failed to accept the client: {:?}", err))
+            .and_then(|(conn, _)| {
+                let (writer, reader) = Framed::new(conn.unwrap(), LengthDelimitedCodec::new()).split();
+                reader
+                    .map(|frame| {
+                        let msg = serde_json::from_slice::<Value>(&frame).unwrap();
+                        let replies = respond(msg).into_iter().map(|reply| Bytes::from(serde_json::to_vec(&reply).unwrap()));
+                        stream::iter_ok::<_, Error>(replies)
+                    })
+                    .flatten()
+                    .forward(writer)
+                    .map(|_| ())
+                    .map_err(|err: Error| panic!("Fake device manager failed: {:?}", err))
+            })
+    }
+
+    fn respond(msg: Value) -> Vec<Value> {
+        let mut reply = msg.clone();
+        reply["dest"] = msg["sender"].clone();
+
+        match msg["action"].as_str().unwrap_or("") {
+            "handshake" => {
+                reply["resp"] = json!({ "protocol": { "version": protocol::VERSION } });
+                vec![reply]
+            },
+            "echo" => {
+                reply["resp"] = msg["args"].clone();
+                vec![reply]
+            },
+            "fail" => vec![json!({
+                "message_id": "error-id",
+                "parent_id": msg["message_id"],
+                "route": [],
+                "sender": { "role": "manager" },
+                "dest": msg["sender"],
+                "action": "error",
+                "args": [{ "code": "permission_denied", "message": "Not allowed" }],
+            })],
+            "announce" => ["weather.changed", "other.changed"].iter()
+                .map(|action| json!({
+                    "message_id": format!("{}-id", action),
+                    "route": [],
+                    "sender": { "role": "weather" },
+                    "dest": { "broadcast": true },
+                    "action": action,
+                }))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    // Reserve a free port, leaving nothing listening on it
+    fn free_addr() -> SocketAddr {
+        StdListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    fn start(runtime: &mut Runtime, options: ClientOptions) -> Client {
+        let listener = TcpListener::bind(&options.addr).unwrap();
+        runtime.spawn(serve(listener));
+        runtime.block_on(Client::new(options).connect()).unwrap()
+    }
+
+    #[test]
+    fn retries_connecting() {
+        let mut runtime = Runtime::new().unwrap();
+        let addr = free_addr();
+        let mut options = ClientOptions::new(addr, "weather");
+        options.retry_delay = Duration::from_millis(100);
+
+        // NOTE: The device manager only starts listening after the first few attempts have failed
+        let late_server = Delay::new(Instant::now() + Duration::from_millis(250))
+            .map_err(|err| panic!("Timer failed: {:?}", err))
+            .and_then(move |_| serve(TcpListener::bind(&addr).unwrap()));
+        runtime.spawn(late_server);
+
+        let client = runtime.block_on(Client::new(options).connect()).unwrap();
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn gives_up_connecting() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut options = ClientOptions::new(free_addr(), "weather");
+        options.retries = 2;
+        options.retry_delay = Duration::from_millis(10);
+
+        let err = runtime.block_on(Client::new(options).connect()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn matches_replies() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = start(&mut runtime, ClientOptions::new(free_addr(), "weather"));
+
+        let mut msg = client.message("echo", json!({ "role": "manager" }));
+        msg["args"] = json!(["sunny"]);
+        let reply = runtime.block_on(client.request(msg)).unwrap();
+        assert_eq!(reply["resp"], json!(["sunny"]));
+
+        // Errors created by the device manager only reference the request through `parent_id`
+        let msg = client.message("fail", json!({ "role": "manager" }));
+        let err = runtime.block_on(client.request(msg)).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(err.to_string(), "Not allowed");
+    }
+
+    #[test]
+    fn times_out_requests() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut options = ClientOptions::new(free_addr(), "weather");
+        options.request_timeout = Duration::from_millis(100);
+        let client = start(&mut runtime, options);
+
+        let msg = client.message("slow", json!({ "role": "manager" }));
+        let err = runtime.block_on(client.request(msg)).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(client.state.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn delivers_subscribed_broadcasts() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = Client::new(ClientOptions::new(free_addr(), "weather"));
+        let changes = client.subscribe("weather.changed");
+        let everything = client.subscribe("*");
+
+        let listener = TcpListener::bind(&client.options.addr).unwrap();
+        runtime.spawn(serve(listener));
+        let client = runtime.block_on(client.connect()).unwrap();
+        client.send(client.message("announce", json!({ "role": "manager" }))).unwrap();
+
+        let (changed, _) = runtime.block_on(changes.into_future()).ok().unwrap();
+        assert_eq!(changed.unwrap()["action"], json!("weather.changed"));
+
+        let all = runtime.block_on(everything.take(2).collect()).unwrap();
+        let actions = all.iter().map(|msg| msg["action"].clone()).collect::<Vec<_>>();
+        assert_eq!(actions, vec![json!("weather.changed"), json!("other.changed")]);
+    }
+}
@@ -0,0 +1,125 @@
+
+use std::sync::Arc;
+
+use rmp_serde;
+use serde_cbor;
+use serde_json;
+use serde_json::Value;
+use tokio::io::{Error, ErrorKind};
+
+/*
+Messages are sent as length-delimited frames, whose contents are encoded by the connection's codec:
+
+  json       utf-8 encoded json (the default)
+  msgpack    MessagePack, which is far more compact for large payloads such as search results
+  cbor       CBOR
+
+The connecting side picks the codec by sending a preamble as its first frame, which is always json encoded:
+
+  { "codec": "msgpack" }
+
+Every frame after the preamble uses the chosen codec, in both directions. If the first frame is an ordinary
+Message instead, the connection uses json (so older apps don't need to change). Messages are decoded into
+Json values before being routed, so the server transcodes between connections that use different codecs.
+*/
+
+pub trait Codec: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn encode(&self, msg: &Value) -> Result<Vec<u8>, Error>;
+    fn decode(&self, frame: &[u8]) -> Result<Value, Error>;
+}
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, msg: &Value) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(msg).map_err(Error::from)
+    }
+
+    fn decode(&self, frame: &[u8]) -> Result<Value, Error> {
+        serde_json::from_slice(frame).map_err(Error::from)
+    }
+}
+
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode(&self, msg: &Value) -> Result<Vec<u8>, Error> {
+        rmp_serde::to_vec(msg).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    fn decode(&self, frame: &[u8]) -> Result<Value, Error> {
+        rmp_serde::from_slice(frame).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn encode(&self, msg: &Value) -> Result<Vec<u8>, Error> {
+        serde_cbor::to_vec(msg).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    fn decode(&self, frame: &[u8]) -> Result<Value, Error> {
+        serde_cbor::from_slice(frame).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+// How a connection settles on its codec
+#[derive(Clone)]
+pub enum Negotiation {
+    // Wait for the other side to pick the codec in its first frame (ie. for accepted connections)
+    Accept,
+
+    // Pick the codec ourselves, sending the preamble before anything else (ie. for outgoing connections)
+    Offer(Arc<dyn Codec>),
+}
+
+pub fn lookup(name: &str) -> Option<Arc<dyn Codec>> {
+    match name {
+        "json" => Some(Arc::new(JsonCodec)),
+        "msgpack" => Some(Arc::new(MessagePackCodec)),
+        "cbor" => Some(Arc::new(CborCodec)),
+        _ => None,
+    }
+}
+
+pub fn json() -> Arc<dyn Codec> {
+    Arc::new(JsonCodec)
+}
+
+// Encode the preamble that picks `codec` for the connection
+pub fn preamble(codec: &dyn Codec) -> Result<Vec<u8>, Error> {
+    JsonCodec.encode(&json!({ "codec": codec.name() }))
+}
+
+// Determine the codec picked by the first frame on the connection
+// NOTE: If the frame was an ordinary json message instead of a preamble, it's returned so it can be handled
+pub fn negotiate(frame: &[u8]) -> Result<(Arc<dyn Codec>, Option<Value>), Error> {
+    let msg = JsonCodec.decode(frame)?;
+    let picked = msg.as_object()
+        .filter(|fields| fields.len() == 1)
+        .and_then(|fields| fields.get("codec"))
+        .and_then(|codec| codec.as_str())
+        .map(String::from);
+
+    match picked {
+        Some(name) => match lookup(&name) {
+            Some(codec) => Ok((codec, None)),
+            None => Err(Error::new(ErrorKind::InvalidData, format!("Connection picked the unsupported codec {:?}", name))),
+        },
+        None => Ok((json(), Some(msg))),
+    }
+}
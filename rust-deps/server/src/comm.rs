@@ -1,28 +1,74 @@
 extern crate tokio;
 
-use std;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 
+use futures::task::{self, Task};
 use tokio::prelude::{Async, Future};
 
-pub struct FutureChannel<T> {
-    rx: mpsc::Receiver<T>,
+/*
+A signal that can be triggered once, waking every task that is waiting on it. Clones of a signal share the
+Same state, so any clone may trigger it. Tasks that start waiting after the signal was triggered complete
+Immediately.
+*/
+
+#[derive(Clone, Default)]
+pub struct Signal {
+    inner: Arc<Mutex<SignalState>>,
+}
+
+#[derive(Default)]
+struct SignalState {
+    triggered: bool,
+    waiters: Vec<Task>,
 }
 
-impl<T: std::fmt::Debug> FutureChannel<T> {
-    pub fn new(rx: mpsc::Receiver<T>) -> Self {
-        Self{ rx: rx }
+impl Signal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Trigger the signal, returning whether this call was the one that triggered it
+    pub fn trigger(&self) -> bool {
+        let mut state = self.inner.lock().unwrap();
+        if state.triggered {
+            return false;
+        }
+
+        state.triggered = true;
+        for waiter in state.waiters.drain(..) {
+            waiter.notify();
+        }
+        true
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.inner.lock().unwrap().triggered
+    }
+
+    // Create a future that completes once the signal is triggered
+    pub fn wait(&self) -> Wait {
+        Wait{ inner: self.inner.clone() }
     }
 }
 
-impl<T: std::fmt::Debug> Future for FutureChannel<T> {
-    type Item = T;
-    type Error = mpsc::RecvError;
+pub struct Wait {
+    inner: Arc<Mutex<SignalState>>,
+}
+
+impl Future for Wait {
+    type Item = ();
+    type Error = ();
 
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        match self.rx.try_recv() {
-            Ok(val) => Ok(Async::Ready(val)),
-            Err(_) => Ok(Async::NotReady)
+        let mut state = self.inner.lock().unwrap();
+        if state.triggered {
+            return Ok(Async::Ready(()));
+        }
+
+        // Register the current task to be woken when the signal is triggered
+        if !state.waiters.iter().any(|waiter| waiter.will_notify_current()) {
+            state.waiters.push(task::current());
         }
+        Ok(Async::NotReady)
     }
 }
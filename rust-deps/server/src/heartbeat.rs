@@ -0,0 +1,171 @@
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::sync::mpsc;
+use serde_json::Value;
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+/*
+Connections are kept alive by heartbeats, so that peers which have hung (or gone to sleep) are noticed instead
+Of lingering forever. Every interval, each side sends a ping frame, which the other side answers with a pong:
+
+  { "heartbeat": "ping", "seq": 12 }
+  { "heartbeat": "pong", "seq": 12 }
+
+Heartbeat frames are handled by the connection itself and never reach the server. Any frame received from the
+Peer counts as a sign of life, so a busy peer never needs to answer promptly. Once the peer has gone silent for
+The configured number of intervals in a row, the connection is considered lost and closed. WebSocket connections
+Use the WebSocket ping and pong frames instead, which browsers answer automatically.
+
+Peers written before heartbeats existed would never answer a ping, so a connection's heartbeat stays idle (sending
+No pings and never losing the peer) until it's activated, ie. once the peer has said that it answers heartbeats.
+*/
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatOptions {
+    pub interval: Duration,
+
+    // The number of intervals in a row that the peer may stay silent before the connection is lost
+    pub misses: u32,
+}
+
+struct HeartbeatState {
+    last_seen: Instant,
+    misses: u32,
+    lost: bool,
+    active: bool,
+}
+
+// The liveness of the peer on a connection
+#[derive(Clone)]
+pub struct Heartbeat {
+    state: Arc<Mutex<HeartbeatState>>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self{
+            state: Arc::new(Mutex::new(HeartbeatState{
+                last_seen: Instant::now(),
+                misses: 0,
+                lost: false,
+                active: false,
+            })),
+        }
+    }
+
+    // Start pinging the peer, now that it's known to answer heartbeats
+    pub fn activate(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.active = true;
+        state.last_seen = Instant::now();
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state.lock().unwrap().active
+    }
+
+    // Record that a frame was just received from the peer
+    pub fn seen(&self) {
+        self.state.lock().unwrap().last_seen = Instant::now();
+    }
+
+    pub fn last_seen(&self) -> Instant {
+        self.state.lock().unwrap().last_seen
+    }
+
+    // Whether the connection was closed for the peer not answering
+    pub fn is_lost(&self) -> bool {
+        self.state.lock().unwrap().lost
+    }
+
+    pub fn to_json(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let silent = state.last_seen.elapsed();
+        json!({
+            "last_seen_ms": silent.as_secs() * 1000 + u64::from(silent.subsec_millis()),
+            "misses": state.misses,
+            "lost": state.lost,
+            "active": state.active,
+        })
+    }
+
+    // Count the interval that just passed, returning whether the peer has now been silent for too long
+    fn tick(&self, options: &HeartbeatOptions) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.active {
+            return false;
+        }
+
+        if state.last_seen.elapsed() >= options.interval {
+            state.misses += 1;
+        } else {
+            state.misses = 0;
+        }
+
+        state.lost = state.misses >= options.misses;
+        state.lost
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Heartbeat frames sent over length-delimited connections
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Frame {
+    Ping(u64),
+    Pong(u64),
+}
+
+impl Frame {
+    pub fn parse(msg: &Value) -> Option<Self> {
+        let seq = msg.get("seq").and_then(|seq| seq.as_u64()).unwrap_or(0);
+        match msg.get("heartbeat").and_then(|kind| kind.as_str()) {
+            Some("ping") => Some(Frame::Ping(seq)),
+            Some("pong") => Some(Frame::Pong(seq)),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match *self {
+            Frame::Ping(seq) => json!({ "heartbeat": "ping", "seq": seq }),
+            Frame::Pong(seq) => json!({ "heartbeat": "pong", "seq": seq }),
+        }
+    }
+}
+
+// Watch the peer's heartbeat, producing the sequence numbers of the pings to send it
+// NOTE: The returned future completes once the peer is lost, and never completes if heartbeats are disabled
+pub fn monitor(heartbeat: Heartbeat, options: Option<HeartbeatOptions>) -> (Box<dyn Future<Item=(), Error=()> + Send>, mpsc::UnboundedReceiver<u64>) {
+    let (pings, receiver) = mpsc::unbounded();
+    let options = match options {
+        Some(options) => options,
+        None => return (Box::new(future::empty()), receiver),
+    };
+
+    let mut seq = 0;
+    let monitor = Interval::new(Instant::now() + options.interval, options.interval)
+        .map_err(|err| error!("Heartbeat timer failed: {:?}", err))
+        .for_each(move |_| {
+            if heartbeat.tick(&options) {
+                return Err(());
+            }
+            if !heartbeat.is_active() {
+                return Ok(());
+            }
+
+            seq += 1;
+            pings.unbounded_send(seq).map_err(|_| ())
+        })
+        .then(|_| Ok(()));
+
+    (Box::new(monitor), receiver)
+}
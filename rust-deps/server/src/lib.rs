@@ -1,27 +1,56 @@
-extern crate futures;
+extern crate bytes;
+#[macro_use] extern crate futures;
+extern crate ring;
+extern crate rmp_serde;
+extern crate rustls;
+extern crate serde_cbor;
 extern crate tokio;
-// extern crate tokio_codec;
-extern crate tokio_io;
-extern crate tokio_serde_json;
-extern crate serde_json;
+extern crate tokio_rustls;
+extern crate tokio_tungstenite;
+extern crate uuid;
+extern crate webpki;
+#[macro_use] extern crate serde_json;
 #[macro_use] extern crate log;
+#[cfg(test)] extern crate rcgen;
 
 pub mod spawn;
+pub mod client;
+pub mod codec;
 pub mod comm;
+pub mod heartbeat;
+pub mod protocol;
+pub mod queue;
+pub mod tls;
+pub mod websocket;
 
 use std::net::SocketAddr;
-use std::sync::mpsc;
 
 use serde_json::Value;
 use tokio::io::Error;
 
 
-pub type Closer = mpsc::Sender<()>;
-pub type Communicator = futures::sync::mpsc::UnboundedSender<Value>;
+pub type Closer = comm::Signal;
+pub type Communicator = queue::SendQueue;
 
 pub trait BasicServer : Clone + Send {
     fn handle_request(&mut self, msg: Value, addr: &SocketAddr) -> Result<(), Error>;
     fn handle_response(&mut self, msg: Value, addr: &SocketAddr) -> Value;
     fn add_connection(&self, addr: SocketAddr, close_signal: Closer, write_signal: Communicator) -> Result<(), Error>;
     fn drop_connection(&mut self, addr: SocketAddr);
+
+    // The capacity and overflow policy of the outgoing queues created for new connections
+    fn queue_options(&self) -> queue::QueueOptions {
+        queue::QueueOptions::default()
+    }
+
+    // How often new connections are sent heartbeats and how many the peer may miss (`None` disables heartbeats)
+    fn heartbeat_options(&self) -> Option<heartbeat::HeartbeatOptions> {
+        None
+    }
+
+    // Keep track of the liveness of a newly added connection
+    fn watch_connection(&self, _addr: SocketAddr, _heartbeat: heartbeat::Heartbeat) {}
+
+    // Called before dropping a connection whose peer stopped answering heartbeats
+    fn connection_lost(&mut self, _addr: SocketAddr) {}
 }
@@ -0,0 +1,154 @@
+
+use std::convert::TryFrom;
+
+use serde_json::Value;
+
+/*
+Apps and device managers say which revisions of the message protocol they speak in the `protocol` field of their
+Handshake body, either as a single version or as a range:
+
+  "protocol": 2
+  "protocol": { "min": 1, "max": 2 }
+
+The device manager picks the highest version that both sides speak, and replies to the handshake with it and the
+Range that the manager supports. Handshakes that give no version are from apps written before versions were
+Exchanged, which speak version 1. Handshakes that share no version with the manager are rejected.
+
+  1   the original protocol (`args` may be a single value, and `forward` may be a boolean)
+  2   `args` are always a list, the arguments of `error` messages always give a `code` and `message`, and
+      `forward` gives the targets that a response is passed on to
+
+Every message received over a connection is upgraded from the connection's version to the current version
+Before it's handled, and every message written to a connection is downgraded to the connection's version, so that
+Apps and the device manager can be upgraded separately. Version 1 apps are sent the description of an error as its
+Only argument, and are never sent `forward` targets (so a response from them ends the forwarding chain).
+*/
+
+// The protocol version spoken by this crate
+pub const VERSION: u32 = 2;
+
+// The oldest protocol version that can still be upgraded to the current version
+pub const MIN_VERSION: u32 = 1;
+
+// The version spoken by apps that don't give one in their handshake
+pub const LEGACY_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VersionRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl VersionRange {
+    pub fn supported() -> Self {
+        Self{ min: MIN_VERSION, max: VERSION }
+    }
+
+    // Extract the versions given in the `protocol` field of a handshake body
+    pub fn parse(body: Option<&Value>) -> Result<Self, String> {
+        let protocol = match body.and_then(|body| body.get("protocol")) {
+            Some(protocol) => protocol,
+            None => return Ok(Self{ min: LEGACY_VERSION, max: LEGACY_VERSION }),
+        };
+
+        if let Some(version) = protocol.as_u64() {
+            let version = parse_version(version)?;
+            return Ok(Self{ min: version, max: version });
+        }
+
+        let min = protocol.get("min").and_then(|min| min.as_u64());
+        let max = protocol.get("max").and_then(|max| max.as_u64());
+        match (min, max) {
+            (Some(min), Some(max)) if min <= max => Ok(Self{ min: parse_version(min)?, max: parse_version(max)? }),
+            _ => Err("`protocol` in handshake must be a version or an object with a `min` and `max` version".to_string()),
+        }
+    }
+
+    // Pick the highest version that both ranges include
+    pub fn negotiate(&self, other: &VersionRange) -> Option<u32> {
+        let version = self.max.min(other.max);
+        Some(version).filter(|version| *version >= self.min.max(other.min))
+    }
+
+    pub fn describe(&self) -> String {
+        if self.min == self.max {
+            format!("version {}", self.min)
+        } else {
+            format!("versions {}-{}", self.min, self.max)
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "min": self.min, "max": self.max })
+    }
+}
+
+fn parse_version(version: u64) -> Result<u32, String> {
+    u32::try_from(version).map_err(|_| format!("Protocol version {} in handshake is out of range", version))
+}
+
+// Upgrade a message sent with the `version` protocol to the shapes of the current version
+pub fn upgrade(msg: &mut Value, version: u32) {
+    if version < 2 {
+        upgrade_v1(msg);
+    }
+}
+
+fn upgrade_v1(msg: &mut Value) {
+    let is_error = msg.get("action").and_then(|action| action.as_str()) == Some("error");
+    let fields = match msg.as_object_mut() {
+        Some(fields) => fields,
+        None => return,
+    };
+
+    // NOTE: A boolean `forward` never forwarded anything
+    if fields.get("forward").is_some_and(|forward| forward.is_boolean()) {
+        fields.remove("forward");
+    }
+
+    let args = match fields.remove("args") {
+        None | Some(Value::Null) => return,
+        Some(Value::Array(args)) => args,
+        Some(arg) => vec![arg],
+    };
+
+    // Errors only described what went wrong
+    let args = args.into_iter()
+        .map(|arg| match arg {
+            Value::String(ref description) if is_error => json!({ "code": "handler_failed", "message": description }),
+            arg => arg,
+        })
+        .collect::<Vec<_>>();
+    fields.insert("args".to_string(), Value::Array(args));
+}
+
+// Downgrade a message of the current version to the shapes of the `version` protocol
+pub fn downgrade(msg: &mut Value, version: u32) {
+    if version < 2 {
+        downgrade_v1(msg);
+    }
+}
+
+fn downgrade_v1(msg: &mut Value) {
+    let is_error = msg.get("action").and_then(|action| action.as_str()) == Some("error");
+    let fields = match msg.as_object_mut() {
+        Some(fields) => fields,
+        None => return,
+    };
+
+    // NOTE: Version 1 apps only knew `forward` as a boolean, which never forwarded anything
+    if fields.get("forward").is_some_and(|forward| !forward.is_boolean()) {
+        fields.remove("forward");
+    }
+
+    // Errors only described what went wrong
+    if is_error {
+        let description = fields.get("args")
+            .and_then(|args| args.get(0))
+            .and_then(|arg| arg.get("message"))
+            .cloned();
+        if let Some(description) = description {
+            fields.insert("args".to_string(), description);
+        }
+    }
+}
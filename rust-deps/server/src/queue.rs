@@ -0,0 +1,323 @@
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::task::{self, Task};
+use serde_json::Value;
+use tokio::io::{Error, ErrorKind};
+use tokio::prelude::{Async, Future, Stream};
+use tokio::timer::Delay;
+
+use super::comm::Signal;
+
+/*
+Every connection's outgoing messages wait in a bounded queue until the connection's writer can send them. When
+A slow consumer lets its queue fill up, the queue's overflow policy decides what happens to the next message:
+
+  block          the message is queued anyways, but the producer stops reading until there is room again
+  drop-oldest    the oldest queued message is dropped to make room for the new one
+  drop-newest    the new message is dropped, failing the send
+  disconnect     the connection is closed, as the consumer can't keep up
+
+Blocking never holds up a thread. Sending to a full queue parks the task that sent the message (ie. the reader of
+The connection that the message came from), and connections only read their next message once every queue that
+Their task is parked on has room again (see `throttle`). If a queue stays full for longer than the block timeout,
+Its consumer is considered stalled, and further messages for it are dropped until it catches up. Sends made
+Outside of a task can't be parked, so they're dropped when the queue is full.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    Block,
+    DropOldest,
+    DropNewest,
+    Disconnect,
+}
+
+impl OverflowPolicy {
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy {
+            "block" => Some(OverflowPolicy::Block),
+            "drop-oldest" => Some(OverflowPolicy::DropOldest),
+            "drop-newest" => Some(OverflowPolicy::DropNewest),
+            "disconnect" => Some(OverflowPolicy::Disconnect),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OverflowPolicy::Block => "block",
+            OverflowPolicy::DropOldest => "drop-oldest",
+            OverflowPolicy::DropNewest => "drop-newest",
+            OverflowPolicy::Disconnect => "disconnect",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueOptions {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+    pub block_timeout: Duration,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self{
+            capacity: DEFAULT_QUEUE_CAPACITY,
+            policy: OverflowPolicy::Block,
+            block_timeout: Duration::from_millis(DEFAULT_BLOCK_TIMEOUT_MILLIS),
+        }
+    }
+}
+
+struct QueueState {
+    buffer: VecDeque<Value>,
+    options: QueueOptions,
+    closed: bool,
+
+    // The writer task waiting on messages to send
+    reader: Option<Task>,
+
+    // The tasks waiting on room in the queue, and when the queue filled up
+    producers: Vec<Task>,
+    full_since: Option<Instant>,
+
+    // Counters for monitoring the queue
+    sent: u64,
+    dropped: u64,
+    high_water: usize,
+}
+
+// The producing side of a connection's queue
+#[derive(Clone)]
+pub struct SendQueue {
+    state: Arc<Mutex<QueueState>>,
+    disconnect: Signal,
+}
+
+// The consuming side of a connection's queue, yielding messages until the queue is closed and flushed
+pub struct Receiver {
+    state: Arc<Mutex<QueueState>>,
+}
+
+// The queues that the current task is waiting on room in
+task_local!(static BLOCKED_ON: RefCell<Vec<SendQueue>> = RefCell::new(Vec::new()));
+
+// Create a queue for a connection, which is closed through `disconnect` under the `Disconnect` policy
+pub fn channel(options: QueueOptions, disconnect: Signal) -> (SendQueue, Receiver) {
+    let state = Arc::new(Mutex::new(QueueState{
+        buffer: VecDeque::new(),
+        options,
+        closed: false,
+        reader: None,
+        producers: Vec::new(),
+        full_since: None,
+        sent: 0,
+        dropped: 0,
+        high_water: 0,
+    }));
+
+    (SendQueue{ state: state.clone(), disconnect }, Receiver{ state })
+}
+
+impl SendQueue {
+    pub fn send(&self, msg: Value) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(Error::new(ErrorKind::BrokenPipe, "Connection's outgoing queue was closed"));
+        }
+
+        if state.buffer.len() >= state.options.capacity {
+            match state.options.policy {
+                OverflowPolicy::Block => {
+                    let full_since = *state.full_since.get_or_insert_with(Instant::now);
+                    if !task::is_in_task() || full_since.elapsed() >= state.options.block_timeout {
+                        state.dropped += 1;
+                        return Err(Error::new(ErrorKind::WouldBlock, "Timed out waiting for room in the connection's outgoing queue"));
+                    }
+
+                    // NOTE: The message is still queued, the producer just doesn't produce anything more until there's room
+                    park_producer(&mut state);
+                    BLOCKED_ON.with(|blocked| {
+                        let mut blocked = blocked.borrow_mut();
+                        if !blocked.iter().any(|queue| Arc::ptr_eq(&queue.state, &self.state)) {
+                            blocked.push(self.clone());
+                        }
+                    });
+                },
+                OverflowPolicy::DropOldest => {
+                    state.buffer.pop_front();
+                    state.dropped += 1;
+                },
+                OverflowPolicy::DropNewest => {
+                    state.dropped += 1;
+                    return Err(Error::new(ErrorKind::WouldBlock, "Connection's outgoing queue is full"));
+                },
+                OverflowPolicy::Disconnect => {
+                    state.dropped += state.buffer.len() as u64 + 1;
+                    state.buffer.clear();
+                    state.closed = true;
+                    self.disconnect.trigger();
+                    return Err(Error::new(ErrorKind::ConnectionAborted, "Connection was closed for not keeping up with its outgoing queue"));
+                },
+            }
+        }
+
+        state.buffer.push_back(msg);
+        state.high_water = state.high_water.max(state.buffer.len());
+        if let Some(reader) = state.reader.take() {
+            reader.notify();
+        }
+        Ok(())
+    }
+
+    // Stop accepting messages, letting the writer finish once the queued messages have been sent
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        if let Some(reader) = state.reader.take() {
+            reader.notify();
+        }
+        wake_producers(&mut state);
+    }
+
+    // Check whether the current task still has to wait on room in the queue, returning until when it has to wait
+    // NOTE: Producers stop waiting once the queue's consumer is considered stalled
+    fn wait_for_room(&self) -> Option<Instant> {
+        let mut state = self.state.lock().unwrap();
+        let deadline = state.full_since.map(|full_since| full_since + state.options.block_timeout)?;
+        if state.closed || state.buffer.len() < state.options.capacity || Instant::now() >= deadline {
+            return None;
+        }
+
+        park_producer(&mut state);
+        Some(deadline)
+    }
+
+    pub fn set_policy(&self, policy: OverflowPolicy) {
+        self.state.lock().unwrap().options.policy = policy;
+    }
+
+    pub fn depth(&self) -> usize {
+        self.state.lock().unwrap().buffer.len()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        json!({
+            "depth": state.buffer.len(),
+            "capacity": state.options.capacity,
+            "policy": state.options.policy.name(),
+            "high_water": state.high_water,
+            "sent": state.sent,
+            "dropped": state.dropped,
+        })
+    }
+}
+
+impl Stream for Receiver {
+    type Item = Value;
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        match state.buffer.pop_front() {
+            Some(msg) => {
+                state.sent += 1;
+                if state.buffer.len() < state.options.capacity {
+                    state.full_since = None;
+                    wake_producers(&mut state);
+                }
+                Ok(Async::Ready(Some(msg)))
+            },
+            None if state.closed => Ok(Async::Ready(None)),
+            None => {
+                state.reader = Some(task::current());
+                Ok(Async::NotReady)
+            },
+        }
+    }
+}
+
+impl Drop for Receiver {
+    // Release any producers that are still waiting on the writer
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        wake_producers(&mut state);
+    }
+}
+
+fn park_producer(state: &mut QueueState) {
+    if !state.producers.iter().any(|producer| producer.will_notify_current()) {
+        state.producers.push(task::current());
+    }
+}
+
+fn wake_producers(state: &mut QueueState) {
+    for producer in state.producers.drain(..) {
+        producer.notify();
+    }
+}
+
+// Pause the stream (ie. a connection's reader) while its task is waiting on room in any full `Block` queue
+pub fn throttle<S: Stream>(stream: S) -> Throttle<S> {
+    Throttle{ stream, timer: None }
+}
+
+pub struct Throttle<S> {
+    stream: S,
+    timer: Option<Delay>,
+}
+
+impl<S: Stream> Stream for Throttle<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        loop {
+            let deadline = BLOCKED_ON.with(|blocked| {
+                let mut blocked = blocked.borrow_mut();
+                let mut deadline = None;
+                blocked.retain(|queue| match queue.wait_for_room() {
+                    Some(until) => {
+                        deadline = Some(deadline.map_or(until, |deadline: Instant| deadline.min(until)));
+                        true
+                    },
+                    None => false,
+                });
+                deadline
+            });
+
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => {
+                    self.timer = None;
+                    return self.stream.poll();
+                },
+            };
+
+            // NOTE: The timer wakes us up to stop waiting on a consumer that has stalled
+            let timer = self.timer.get_or_insert_with(|| Delay::new(deadline));
+            if timer.deadline() != deadline {
+                timer.reset(deadline);
+            }
+            match timer.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => continue,
+                Err(err) => {
+                    error!("Queue throttle timer failed: {:?}", err);
+                    self.timer = None;
+                    return self.stream.poll();
+                },
+            }
+        }
+    }
+}
+
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+const DEFAULT_BLOCK_TIMEOUT_MILLIS: u64 = 1000;
@@ -1,67 +1,155 @@
 
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 
+use bytes::Bytes;
+use futures::sync::mpsc;
 use tokio;
+use tokio::codec::{Framed, LengthDelimitedCodec};
 use tokio::prelude::*;
 use tokio::net::TcpStream;
-#[allow(deprecated)]
-use tokio_io::codec::length_delimited;
-// use tokio::codec::*;
-
-use futures;
-use serde_json::Value;
-use tokio_serde_json::*;
 
 use super::*;
+use super::codec::{self, Negotiation};
 use super::comm;
+use super::heartbeat::{self, Frame, Heartbeat};
+use super::queue;
 
 
 pub fn spawn_connection<Server: 'static + BasicServer>(conn: TcpStream, server: Server) {
+    let addr = conn.peer_addr().expect("Failed to extract peer address from TcpStream");
+    spawn_stream(conn, addr, server, Negotiation::Accept);
+}
+
+// Serve the connection over any stream (ie. a `tls::TlsStream`), registering it with the server under `addr`
+pub fn spawn_stream<Server, Io>(conn: Io, addr: SocketAddr, server: Server, negotiation: Negotiation)
+    where Server: 'static + BasicServer, Io: 'static + AsyncRead + AsyncWrite + Send
+{
     // Setup stop communication
-    let (tx, cancel) = mpsc::channel();
-    let cancel = comm::FutureChannel::new(cancel);
+    let close = comm::Signal::new();
 
     // Setup communication channels
-    let (sink, source) = futures::sync::mpsc::unbounded();
+    let (sink, source) = queue::channel(server.queue_options(), close.clone());
 
     // Register the connection
-    let addr = conn.peer_addr().expect("Failed to extract peer address from TcpStream");
-    server.add_connection(addr, tx, sink).expect("Failed to add connection");
+    server.add_connection(addr, close.clone(), sink).expect("Failed to add connection");
+
+    // Setup the heartbeats
+    let heartbeat = Heartbeat::new();
+    server.watch_connection(addr, heartbeat.clone());
+    let (monitor, pings) = heartbeat::monitor(heartbeat.clone(), server.heartbeat_options());
+    let (pongs, pong_source) = mpsc::unbounded();
 
-    // Setup the json communicators
-    // TODO: We need to update this has the length_delimited stuff has been deprecated and moved
-    // Unfortunately, the WriteJson repo relies on the writer to have a `BytesMut` sink while the updated codec has a `Bytes` sink
-    // This requirement is derived from tokio_serde
-    // let (writer, reader) = Framed::new(conn, length_delimited::LengthDelimitedCodec::new()).split();
-    // let writer = WriteJson::<_, Value>::new(writer);
+    // Setup the codec negotiation
+    // NOTE: Nothing is written until the codec has been picked, so the other side can always decode our messages
+    let ready = comm::Signal::new();
+    let (selected, offered) = match negotiation {
+        Negotiation::Accept => (codec::json(), None),
+        Negotiation::Offer(codec) => {
+            ready.trigger();
+            (codec.clone(), Some(codec))
+        },
+    };
+    let selected = Arc::new(Mutex::new(selected));
 
-    let (writer, reader) = length_delimited::Framed::new(conn).split();
-    let writer = WriteJson::<_, Value>::new(writer)
-        .sink_map_err(|err| { error!("Error in json serialization: {:?}", err); });
-    let reader = ReadJson::<_, Value>::new(reader);
+    let (writer, reader) = Framed::new(conn, LengthDelimitedCodec::new()).split();
+    let writer = writer.sink_map_err(|err| { error!("Socket write error: {:?}", err); });
 
     // Define the handle for incoming communication
     let mut read_state = server.clone();
-    let read_action = reader
-        .for_each(move |msg| read_state.handle_request(msg, &addr))
+    let (read_selected, read_ready) = (selected.clone(), ready.clone());
+    let mut negotiating = offered.is_none();
+    let read_heartbeat = heartbeat.clone();
+    let read_action = queue::throttle(reader)
+        .for_each(move |frame| {
+            read_heartbeat.seen();
+            if negotiating {
+                negotiating = false;
+                let (codec, msg) = codec::negotiate(&frame)?;
+                debug!("Connection {:?} picked the {:?} codec", addr, codec.name());
+                *read_selected.lock().unwrap() = codec;
+                read_ready.trigger();
+
+                return match msg {
+                    Some(msg) => read_state.handle_request(msg, &addr),
+                    None => Ok(()),
+                };
+            }
+
+            let msg = read_selected.lock().unwrap().decode(&frame)?;
+            match Frame::parse(&msg) {
+                Some(Frame::Ping(seq)) => {
+                    // NOTE: The pong can only fail to send once the writer has stopped, which closes the connection anyways
+                    let _ = pongs.unbounded_send(Frame::Pong(seq).to_json());
+                    Ok(())
+                },
+                Some(Frame::Pong(_)) => Ok(()),
+                None => read_state.handle_request(msg, &addr),
+            }
+        })
         .map(|_| ())
         .map_err(|err| { error!("Socket read error: {:?}", err); });
 
     // Define the handle for outgoing communication
+    // NOTE: The preamble is only needed to pick a codec other than json, which older servers don't understand
+    // NOTE: Heartbeat frames are sent alongside the queued messages, but stop once the queue has been closed and flushed
     let mut write_state = server.clone();
-    let write_action = source
-        .map(move |msg| write_state.handle_response(msg, &addr))
-        .forward(writer)
+    let heartbeats = pings
+        .map(|seq| Some(Frame::Ping(seq).to_json()))
+        .select(pong_source.map(Some));
+    let write_action = ready.wait()
+        .and_then(move |_| {
+            let codec = selected.lock().unwrap().clone();
+            let preamble = offered
+                .filter(|codec| codec.name() != "json")
+                .and_then(|codec| codec::preamble(&*codec)
+                    .map_err(|err| error!("Failed to encode codec preamble for {:?}: {:?}", addr, err))
+                    .ok())
+                .map(Bytes::from);
+
+            let messages = source
+                .map(move |msg| Some(write_state.handle_response(msg, &addr)))
+                .chain(stream::once(Ok(None)))
+                .select(heartbeats)
+                .take_while(|msg| Ok(msg.is_some()))
+                .filter_map(|msg| msg)
+                .filter_map(move |msg| match codec.encode(&msg) {
+                    Ok(frame) => Some(Bytes::from(frame)),
+                    Err(err) => {
+                        error!("Failed to encode message for {:?}: {:?}", addr, err);
+                        None
+                    },
+                });
+
+            stream::iter_ok(preamble).chain(messages).forward(writer)
+        })
         .map(|_| ())
         .map_err(|err| { error!("Socket write error: {:?}", err); });
 
+    run_connection(addr, server, close, heartbeat, monitor, read_action, write_action);
+}
+
+// Run the connection's read and write actions until either finishes, the connection is closed, or the peer is lost
+pub fn run_connection<Server, M, R, W>(addr: SocketAddr, server: Server, close: comm::Signal, heartbeat: Heartbeat, monitor: M, read_action: R, write_action: W)
+    where Server: 'static + BasicServer, M: 'static + Future<Item=(), Error=()> + Send, R: 'static + Future<Item=(), Error=()> + Send, W: 'static + Future<Item=(), Error=()> + Send
+{
     // Combine the actions for tokio registration
+    // NOTE: The connection is unregistered however it ends, including when reading or writing fails
     let mut close_state = server.clone();
     let action = read_action
         .select2(write_action)
-        .select2(cancel)
-        .map(move |_| close_state.drop_connection(addr))
-        .map_err(|_| { error!("Error closing the server"); });
+        .select2(close.wait())
+        .select2(monitor)
+        .then(move |res| {
+            if res.is_err() {
+                debug!("Connection to {:?} ended with an error", addr);
+            }
+            if heartbeat.is_lost() {
+                warn!("Lost connection to {:?}, which stopped answering heartbeats", addr);
+                close_state.connection_lost(addr);
+            }
+            close_state.drop_connection(addr);
+            Ok(())
+        });
 
     // Spawn the connection
     tokio::spawn(action);
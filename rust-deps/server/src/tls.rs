@@ -0,0 +1,419 @@
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use ring::digest;
+use rustls;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, Session, TLSError};
+use rustls::internal::pemfile;
+use tokio::io::{AsyncRead, AsyncWrite, Error, ErrorKind};
+use tokio_rustls::{Accept, Connect, TlsAcceptor, TlsConnector};
+use webpki;
+
+pub use tokio_rustls::{client, server};
+
+/*
+Connections may optionally be secured with TLS, both on the listener and on the connections we open to other
+Device managers. Devices are addressed by ip, so the certificates that peers present are authenticated against the
+Configured CA certificates, or against a set of pinned certificate fingerprints, rather than against a hostname.
+When any certificates are pinned, only those certificates are accepted (whether or not they chain to the CA), which
+Also lets devices use self-signed certificates. Fingerprints are the hex-encoded SHA-256 of the certificate's DER.
+
+The listener may additionally require (or just request) certificates from the apps and managers connecting to it.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAuth {
+    None,
+    Optional,
+    Required,
+}
+
+impl ClientAuth {
+    pub fn parse(auth: &str) -> Option<Self> {
+        match auth {
+            "none" => Some(ClientAuth::None),
+            "optional" => Some(ClientAuth::Optional),
+            "required" => Some(ClientAuth::Required),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub ca: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+    pub pins: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct TlsConfig {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+impl TlsConfig {
+    pub fn load(options: &TlsOptions) -> Result<Self, Error> {
+        let certs = load_certs(&options.cert)?;
+        let key = load_key(&options.key)?;
+
+        let mut roots = RootCertStore::empty();
+        if let Some(ref ca) = options.ca {
+            for cert in load_certs(ca)? {
+                roots.add(&cert)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, format!("Invalid CA certificate in {:?}: {:?}", ca, err)))?;
+            }
+        }
+
+        let pins = options.pins.iter()
+            .map(|pin| pin.replace(":", "").to_lowercase())
+            .collect::<Vec<_>>();
+        if options.client_auth != ClientAuth::None && roots.is_empty() && pins.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Client authentication requires a CA certificate or pinned certificates"));
+        }
+
+        let verifier = Arc::new(PeerVerifier{ roots, pins, client_auth: options.client_auth });
+
+        let mut server = ServerConfig::new(verifier.clone());
+        server.set_single_cert(certs.clone(), key.clone())
+            .map_err(|err| Error::new(ErrorKind::InvalidData, format!("Invalid certificate or key: {:?}", err)))?;
+
+        let mut client = ClientConfig::new();
+        client.set_single_client_cert(certs, key);
+        client.dangerous().set_certificate_verifier(verifier);
+
+        Ok(Self{
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            connector: TlsConnector::from(Arc::new(client)),
+        })
+    }
+
+    // Perform the server side of the TLS handshake over a connection accepted by the listener
+    pub fn accept<IO: AsyncRead + AsyncWrite>(&self, conn: IO) -> Accept<IO> {
+        self.acceptor.accept(conn)
+    }
+
+    // Perform the client side of the TLS handshake over a connection we opened
+    // NOTE: The peer isn't authenticated by name (see `PeerVerifier`), so every session uses the same placeholder
+    pub fn connect<IO: AsyncRead + AsyncWrite>(&self, conn: IO) -> Connect<IO> {
+        let name = webpki::DNSNameRef::try_from_ascii_str(PEER_NAME).unwrap();
+        self.connector.connect(name, conn)
+    }
+}
+
+// Compute the fingerprint used to pin a certificate
+pub fn fingerprint(cert: &[u8]) -> String {
+    digest::digest(&digest::SHA256, cert).as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn load_certs(path: &PathBuf) -> Result<Vec<Certificate>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(ref certs) if certs.is_empty() => Err(Error::new(ErrorKind::InvalidData, format!("No certificates found in {:?}", path))),
+        Ok(certs) => Ok(certs),
+        Err(_) => Err(Error::new(ErrorKind::InvalidData, format!("Failed to parse certificates in {:?}", path))),
+    }
+}
+
+fn load_key(path: &PathBuf) -> Result<PrivateKey, Error> {
+    let mut pem = Vec::new();
+    File::open(path)?.read_to_end(&mut pem)?;
+
+    // NOTE: Keys may be given in either PKCS#8 or RSA format
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut pem.as_slice()).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut pem.as_slice()).unwrap_or_default();
+    pkcs8.into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or(Error::new(ErrorKind::InvalidData, format!("No private key found in {:?}", path)))
+}
+
+// Authenticates the certificates presented by either side of a connection
+struct PeerVerifier {
+    roots: RootCertStore,
+    pins: Vec<String>,
+    client_auth: ClientAuth,
+}
+
+impl PeerVerifier {
+    fn verify(&self, presented: &[Certificate], is_server: bool) -> Result<(), TLSError> {
+        let cert = presented.first().ok_or(TLSError::NoCertificatesPresented)?;
+        if !self.pins.is_empty() {
+            let print = fingerprint(&cert.0);
+            if self.pins.contains(&print) {
+                return Ok(());
+            }
+            return Err(TLSError::General(format!("Certificate {} is not pinned", print)));
+        }
+
+        if self.roots.is_empty() {
+            return Err(TLSError::General("No CA or pinned certificates are configured to authenticate peers".to_string()));
+        }
+
+        let end_entity = webpki::EndEntityCert::from(&cert.0).map_err(TLSError::WebPKIError)?;
+        let chain = presented[1..].iter().map(|cert| cert.0.as_ref()).collect::<Vec<_>>();
+        let anchors = self.roots.roots.iter().map(|root| root.to_trust_anchor()).collect::<Vec<_>>();
+        let now = webpki::Time::try_from(SystemTime::now()).map_err(|_| TLSError::FailedToGetCurrentTime)?;
+
+        let verified = if is_server {
+            end_entity.verify_is_valid_tls_server_cert(SUPPORTED_SIG_ALGS, &webpki::TLSServerTrustAnchors(&anchors), &chain, now)
+        } else {
+            end_entity.verify_is_valid_tls_client_cert(SUPPORTED_SIG_ALGS, &webpki::TLSClientTrustAnchors(&anchors), &chain, now)
+        };
+        verified.map_err(TLSError::WebPKIError)
+    }
+}
+
+impl rustls::ServerCertVerifier for PeerVerifier {
+    fn verify_server_cert(&self, _roots: &RootCertStore, presented: &[Certificate], _name: webpki::DNSNameRef, _ocsp: &[u8]) -> Result<rustls::ServerCertVerified, TLSError> {
+        self.verify(presented, true).map(|_| rustls::ServerCertVerified::assertion())
+    }
+}
+
+impl rustls::ClientCertVerifier for PeerVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.client_auth != ClientAuth::None
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.client_auth == ClientAuth::Required
+    }
+
+    fn client_auth_root_subjects(&self) -> rustls::DistinguishedNames {
+        self.roots.get_subjects()
+    }
+
+    fn verify_client_cert(&self, presented: &[Certificate]) -> Result<rustls::ClientCertVerified, TLSError> {
+        self.verify(presented, false).map(|_| rustls::ClientCertVerified::assertion())
+    }
+}
+
+// Gives the certificate that the other side of a TLS connection authenticated with
+pub trait PeerCertificate {
+    // The fingerprint of the certificate the peer authenticated with (if any)
+    fn peer_fingerprint(&self) -> Option<String>;
+}
+
+impl<IO> PeerCertificate for server::TlsStream<IO> {
+    fn peer_fingerprint(&self) -> Option<String> {
+        session_fingerprint(self.get_ref().1)
+    }
+}
+
+impl<IO> PeerCertificate for client::TlsStream<IO> {
+    fn peer_fingerprint(&self) -> Option<String> {
+        session_fingerprint(self.get_ref().1)
+    }
+}
+
+fn session_fingerprint<S: Session>(session: &S) -> Option<String> {
+    session.get_peer_certificates()
+        .and_then(|certs| certs.first().map(|cert| fingerprint(&cert.0)))
+}
+
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+const PEER_NAME: &str = "device-manager";
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use rcgen;
+    use tokio;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::prelude::{Future, Stream};
+
+    use super::*;
+
+    // A certificate and its key, along with the files they were written to
+    struct Identity {
+        cert: rcgen::Certificate,
+        pem: String,
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    }
+
+    impl Identity {
+        fn fingerprint(&self) -> String {
+            let der = pemfile::certs(&mut self.pem.as_bytes()).unwrap();
+            fingerprint(&der[0].0)
+        }
+    }
+
+    fn scratch_dir(test: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("server-tls-{}-{}", test, ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_identity(dir: &Path, name: &str, cert: rcgen::Certificate, pem: String) -> Identity {
+        let cert_path = dir.join(format!("{}.crt", name));
+        let key_path = dir.join(format!("{}.key", name));
+        fs::write(&cert_path, &pem).unwrap();
+        fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        Identity{ cert, pem, cert_path, key_path }
+    }
+
+    fn ca(dir: &Path) -> Identity {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let pem = cert.serialize_pem().unwrap();
+        write_identity(dir, "ca", cert, pem)
+    }
+
+    fn self_signed(dir: &Path, name: &str) -> Identity {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let pem = cert.serialize_pem().unwrap();
+        write_identity(dir, name, cert, pem)
+    }
+
+    fn signed_by(dir: &Path, name: &str, ca: &Identity) -> Identity {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let pem = cert.serialize_pem_with_signer(&ca.cert).unwrap();
+        write_identity(dir, name, cert, pem)
+    }
+
+    fn config(identity: &Identity, ca: Option<&Identity>, client_auth: ClientAuth, pins: Vec<String>) -> TlsConfig {
+        TlsConfig::load(&TlsOptions{
+            cert: identity.cert_path.clone(),
+            key: identity.key_path.clone(),
+            ca: ca.map(|ca| ca.cert_path.clone()),
+            client_auth,
+            pins,
+        }).unwrap()
+    }
+
+    // Connect `client` to `server` over loopback, returning the fingerprints that each side saw (or the first error)
+    fn handshake(server: TlsConfig, client: TlsConfig) -> Result<(Option<String>, Option<String>), Error> {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let accept = listener.incoming()
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(move |(conn, _)| server.accept(conn.unwrap()))
+            .and_then(|stream| tokio::io::read_exact(stream, [0u8; 5]))
+            .map(|(stream, buf)| {
+                assert_eq!(&buf, b"hello");
+                stream.peer_fingerprint()
+            });
+
+        let connect = TcpStream::connect(&addr)
+            .and_then(move |conn| client.connect(conn))
+            .and_then(|stream| tokio::io::write_all(stream, b"hello"))
+            .and_then(|(stream, _)| tokio::io::flush(stream))
+            .map(|stream| stream.peer_fingerprint());
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(accept.join(connect))
+    }
+
+    #[test]
+    fn handshake_with_ca() {
+        let dir = scratch_dir("ca");
+        let ca = ca(&dir);
+        let server = signed_by(&dir, "server", &ca);
+        let client = signed_by(&dir, "client", &ca);
+
+        let (seen_by_server, seen_by_client) = handshake(
+            config(&server, Some(&ca), ClientAuth::None, vec![]),
+            config(&client, Some(&ca), ClientAuth::None, vec![]),
+        ).unwrap();
+
+        assert_eq!(seen_by_server, None);
+        assert_eq!(seen_by_client, Some(server.fingerprint()));
+    }
+
+    #[test]
+    fn mutual_auth() {
+        let dir = scratch_dir("mutual");
+        let ca = ca(&dir);
+        let server = signed_by(&dir, "server", &ca);
+        let client = signed_by(&dir, "client", &ca);
+
+        let (seen_by_server, seen_by_client) = handshake(
+            config(&server, Some(&ca), ClientAuth::Required, vec![]),
+            config(&client, Some(&ca), ClientAuth::None, vec![]),
+        ).unwrap();
+
+        assert_eq!(seen_by_server, Some(client.fingerprint()));
+        assert_eq!(seen_by_client, Some(server.fingerprint()));
+    }
+
+    #[test]
+    fn mutual_auth_rejects_unknown_client() {
+        let dir = scratch_dir("unknown-client");
+        let ca = ca(&dir);
+        let server = signed_by(&dir, "server", &ca);
+        let client = self_signed(&dir, "client");
+
+        let res = handshake(
+            config(&server, Some(&ca), ClientAuth::Required, vec![]),
+            config(&client, Some(&ca), ClientAuth::None, vec![]),
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn accepts_pinned_certificate() {
+        let dir = scratch_dir("pinned");
+        let server = self_signed(&dir, "server");
+        let client = self_signed(&dir, "client");
+
+        // NOTE: Pins may be given in the colon-separated form that most tools print
+        let pin = server.fingerprint().as_bytes()
+            .chunks(2)
+            .map(|pair| String::from_utf8(pair.to_vec()).unwrap().to_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+
+        let (seen_by_server, seen_by_client) = handshake(
+            config(&server, None, ClientAuth::Required, vec![client.fingerprint()]),
+            config(&client, None, ClientAuth::None, vec![pin]),
+        ).unwrap();
+
+        assert_eq!(seen_by_server, Some(client.fingerprint()));
+        assert_eq!(seen_by_client, Some(server.fingerprint()));
+    }
+
+    #[test]
+    fn rejects_unpinned_certificate() {
+        let dir = scratch_dir("unpinned");
+        let ca = ca(&dir);
+        let server = signed_by(&dir, "server", &ca);
+        let client = self_signed(&dir, "client");
+        let other = self_signed(&dir, "other");
+
+        // NOTE: Once anything is pinned, certificates that chain to the CA aren't enough
+        let res = handshake(
+            config(&server, None, ClientAuth::None, vec![]),
+            config(&client, Some(&ca), ClientAuth::None, vec![other.fingerprint()]),
+        );
+        assert!(res.is_err());
+    }
+}
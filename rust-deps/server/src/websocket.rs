@@ -0,0 +1,107 @@
+
+use std::net::SocketAddr;
+
+use serde_json;
+use serde_json::Value;
+use tokio::io::{Error, ErrorKind};
+use tokio::prelude::*;
+use tokio_tungstenite::accept_hdr_async;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+
+use super::*;
+use super::comm;
+use super::heartbeat::{self, Heartbeat};
+use super::queue;
+use super::spawn::run_connection;
+
+/*
+Browsers can't speak the length-delimited framing, so apps running in a browser (ie. dashboards and remotes) may
+Connect over a WebSocket instead. Every WebSocket frame carries exactly one json message, in the same format as
+Any other connection. Text and binary frames are both accepted, and every message sent to the app is sent as a
+Text frame. Once the WebSocket handshake completes, the client is registered with the server like any other
+Connection, so the app's handshake and the routing of its messages are unchanged.
+
+Browsers send the `Origin` of the page that opened the WebSocket, which lets the server stop arbitrary websites
+From connecting through a visitor's browser. When a list of allowed origins is given, upgrades from any other
+Origin (or without an origin) are rejected.
+*/
+
+// Upgrade the connection to a WebSocket, serving it once the WebSocket handshake completes
+pub fn spawn_websocket<Server, Io>(conn: Io, addr: SocketAddr, server: Server, origins: Vec<String>) -> Box<dyn Future<Item=(), Error=Error> + Send>
+    where Server: 'static + BasicServer, Io: 'static + AsyncRead + AsyncWrite + Send
+{
+    let check_origin = move |request: &Request| {
+        let origin = request.headers.find_first("Origin")
+            .map(|origin| String::from_utf8_lossy(origin).into_owned());
+        trace!("Received WebSocket upgrade from {:?} for {:?} (origin {:?})", addr, request.path, origin);
+
+        match origin {
+            _ if origins.is_empty() => Ok(None),
+            Some(ref origin) if origins.contains(origin) => Ok(None),
+            origin => {
+                warn!("Rejecting WebSocket upgrade from {:?} with disallowed origin {:?}", addr, origin);
+                Err(ErrorResponse::from(StatusCode::FORBIDDEN))
+            },
+        }
+    };
+
+    let upgrade = accept_hdr_async(conn, check_origin)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, format!("WebSocket handshake failed: {:?}", err)))
+        .map(move |socket| {
+            info!("Upgraded connection from {:?} to a WebSocket", addr);
+
+            // Setup stop communication
+            let close = comm::Signal::new();
+
+            // Setup communication channels
+            let (sink, source) = queue::channel(server.queue_options(), close.clone());
+
+            // Register the connection
+            server.add_connection(addr, close.clone(), sink).expect("Failed to add connection");
+
+            // Setup the heartbeats (using WebSocket pings, which browsers answer automatically)
+            let heartbeat = Heartbeat::new();
+            heartbeat.activate();
+            server.watch_connection(addr, heartbeat.clone());
+            let (monitor, pings) = heartbeat::monitor(heartbeat.clone(), server.heartbeat_options());
+
+            let (writer, reader) = socket.split();
+            let writer = writer.sink_map_err(|err| { error!("WebSocket write error: {:?}", err); });
+
+            // Define the handle for incoming communication
+            // NOTE: Pings are answered automatically when the socket is next read from or written to
+            let mut read_state = server.clone();
+            let read_heartbeat = heartbeat.clone();
+            let read_action = queue::throttle(reader)
+                .map_err(|err| Error::other(format!("{:?}", err)))
+                .for_each(move |frame| {
+                    read_heartbeat.seen();
+                    let msg: Value = match frame {
+                        Message::Text(text) => serde_json::from_str(&text)?,
+                        Message::Binary(data) => serde_json::from_slice(&data)?,
+                        Message::Ping(_) | Message::Pong(_) | Message::Close(_) => return Ok(()),
+                    };
+                    read_state.handle_request(msg, &addr)
+                })
+                .map(|_| ())
+                .map_err(|err| { error!("WebSocket read error: {:?}", err); });
+
+            // Define the handle for outgoing communication
+            let mut write_state = server.clone();
+            let pings = pings.map(|seq| Some(Message::Ping(seq.to_string().into_bytes())));
+            let write_action = source
+                .map(move |msg| Some(Message::Text(write_state.handle_response(msg, &addr).to_string())))
+                .chain(stream::once(Ok(None)))
+                .select(pings)
+                .take_while(|frame| Ok(frame.is_some()))
+                .filter_map(|frame| frame)
+                .forward(writer)
+                .map(|_| ());
+
+            run_connection(addr, server, close, heartbeat, monitor, read_action, write_action);
+        });
+
+    Box::new(upgrade)
+}
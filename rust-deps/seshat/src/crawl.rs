@@ -21,12 +21,20 @@ TODO: We currently split files between handlers based on their extension
 pub trait Crawler {
     fn is_relevant_file(&self, entry: &DirEntry) -> bool;
     fn crawl(&self, fdir: WalkDir, index: &mut idx::IndexWriter) -> u64;
+
+    // Crawl `folder` as part of the crawl root `root`, replacing the documents previously found there
+    fn recrawl(&self, folder: &str, root: &str, index: &mut idx::IndexWriter) -> u64 {
+        index.begin_crawl(folder, root);
+        let num_files = self.crawl(WalkDir::new(folder), index);
+        index.end_crawl();
+        num_files
+    }
 }
 
 
 pub struct WindowsCrawler {
-    default_handle: sync::Arc<handle::FileHandler>,
-    handles: HashMap<String, sync::Arc<handle::FileHandler>>
+    default_handle: sync::Arc<dyn handle::FileHandler>,
+    handles: HashMap<String, sync::Arc<dyn handle::FileHandler>>
 }
 
 impl WindowsCrawler {
@@ -37,7 +45,7 @@ impl WindowsCrawler {
         }
     }
 
-    pub fn register_handle(&mut self, exts: &[&str], handle: sync::Arc<handle::FileHandler>) {
+    pub fn register_handle(&mut self, exts: &[&str], handle: sync::Arc<dyn handle::FileHandler>) {
         for ext in exts {
             self.handles.insert(ext.to_string(), handle.clone());
         }
@@ -1,8 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::RandomState;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 
 use evmap;
@@ -10,6 +10,8 @@ use serde::{Serialize, Serializer};
 use serde::ser::SerializeMap;
 use serde_json;
 
+use super::persist;
+
 // TODO: Find a way to "shard" the database into multiple files (for memory and distributed)
 // TODO: Find a way to minimize file recalculations during crawling
 
@@ -22,41 +24,207 @@ type _IndexReader = evmap::ReadHandle<String, Element, MetaInformation, RandomSt
 
 pub struct IndexWriter {
     write_handle: _IndexWriter,
-    root_channel: mpsc::Receiver<String>
+    root_channel: mpsc::Receiver<String>,
+
+    // Track which tags and crawl root each document was indexed under, so that crawls can replace them
+    documents: HashMap<Element, Document>,
+    current_root: Option<String>,
+    nested_roots: Vec<String>,
+
+    // Persistence state, set once the writer is attached to a cache file through `persist_to`
+    snapshot: Option<PathBuf>,
+    journal: Option<persist::Journal>,
 }
 
 impl IndexWriter {
     pub fn add(&mut self, tag: &str, path: String) -> &mut Self {
         for word in tag.to_lowercase().split(" ") {
-            self.write_handle.insert(word.to_string(), path.clone());
+            self.insert(word.to_string(), path.clone());
         }
 
         self
     }
 
+    fn insert(&mut self, tag: String, doc: Element) {
+        // NOTE: Documents stay attributed to the most specific root they live in, even when an outer root crawled them
+        let root = self.nested_roots.iter()
+            .filter(|nested| Path::new(&doc).starts_with(nested))
+            .max_by_key(|nested| nested.len())
+            .or(self.current_root.as_ref())
+            .cloned();
+        {
+            let document = self.documents.entry(doc.clone()).or_insert(Document::default());
+            if root.is_some() {
+                document.root = root.clone();
+            }
+
+            // Skip documents that are already indexed under this tag
+            if !document.tags.insert(tag.clone()) {
+                return;
+            }
+        }
+
+        self.record(persist::JournalEntry::Insert{ tag: tag.clone(), doc: doc.clone(), root: root });
+        self.write_handle.insert(tag, doc);
+    }
+
+    // Drop the document from the index entirely
+    fn forget(&mut self, doc: &Element) {
+        if let Some(document) = self.documents.remove(doc) {
+            for tag in document.tags {
+                self.record(persist::JournalEntry::Remove{ tag: tag.clone(), doc: doc.clone() });
+                self.write_handle.remove(tag, doc.clone());
+            }
+
+            self.record(persist::JournalEntry::Forget{ doc: doc.clone() });
+        }
+    }
+
+    fn record(&mut self, entry: persist::JournalEntry) {
+        if let Some(ref mut journal) = self.journal {
+            if let Err(err) = journal.record(&entry) {
+                error!("Failed to record index change in journal {:?}: {:?}", journal.path(), err);
+            }
+        }
+    }
+
+    // Prepare for a (re)crawl of `folder`, which lives under the crawl root `root`
+    // Every document previously found in `folder` is dropped, whichever root it was crawled under, as the crawl walks
+    // The whole folder again. As the changes aren't visible until the next commit, readers see the old documents
+    // Replaced by the results of the crawl all at once
+    // NOTE: Roots nested inside `folder` keep their documents, so a later crawl of the nested root still replaces them
+    pub fn begin_crawl(&mut self, folder: &str, root: &str) {
+        let folder_path = Path::new(folder);
+        let stale: Vec<Element> = self.documents.keys()
+            .filter(|doc| Path::new(doc).starts_with(folder_path))
+            .cloned()
+            .collect();
+
+        let mut nested_roots: Vec<String> = stale.iter()
+            .filter_map(|doc| self.documents[doc].root.clone())
+            .filter(|nested| nested != root && Path::new(nested).starts_with(root))
+            .collect();
+        nested_roots.sort();
+        nested_roots.dedup();
+
+        trace!("Dropping {} documents from {:?} before recrawling {:?}", stale.len(), root, folder);
+        for doc in &stale {
+            self.forget(doc);
+        }
+
+        self.current_root = Some(root.to_string());
+        self.nested_roots = nested_roots;
+    }
+
+    pub fn end_crawl(&mut self) {
+        self.current_root = None;
+        self.nested_roots.clear();
+        self.commit();
+    }
+
+    // Record all future changes in the journal for `filepath`, so that they survive until the next checkpoint
+    // NOTE: This assumes the writer already holds the contents of `filepath` (ie. it was loaded through `load_file`)
+    pub fn persist_to(&mut self, filepath: &Path) -> Result<(), io::Error> {
+        self.journal = Some(persist::Journal::open(filepath)?);
+        self.snapshot = Some(filepath.to_path_buf());
+        Ok(())
+    }
+
+    pub fn snapshot_path(&self) -> Option<&Path> {
+        self.snapshot.as_ref().map(|path| path.as_path())
+    }
+
+    // Write out a full snapshot of the index and clear the journal
+    pub fn checkpoint(&mut self) -> Result<(), io::Error> {
+        let filepath = self.snapshot.clone()
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "Index writer has not been attached to a cache file"))?;
+
+        self.commit();
+        let mut snapshot = persist::Snapshot::default();
+        snapshot.tags = self.write_handle.map_into(|k, v| (k.clone(), v.to_vec()));
+        for (doc, document) in &self.documents {
+            if let Some(ref root) = document.root {
+                snapshot.roots.entry(root.clone())
+                    .or_insert(HashSet::new())
+                    .insert(doc.clone());
+            }
+        }
+
+        persist::write_atomic(&filepath, |file| serde_json::to_writer(file, &snapshot)
+            .map_err(|err| err.into()))?;
+
+        // NOTE: If we crash before the journal is cleared, replaying it is harmless as replay skips present entries
+        if let Some(ref mut journal) = self.journal {
+            journal.truncate()?;
+        }
+
+        Ok(())
+    }
+
     pub fn load_file(&mut self, filepath: &Path) {
-        let map: HashMap<String, ElementList> = fs::File::open(filepath)
-            .and_then(|file| serde_json::from_reader(file)
-                .map_err(|err| err.into()))
-            .unwrap_or(HashMap::new());
-
-        for (k, v) in &map {
-            for item in v {
-                self.write_handle.insert(k.clone(), item.clone());
+        let snapshot = read_file(filepath).unwrap_or(persist::Snapshot::default());
+        self.load_snapshot(&snapshot);
+    }
+
+    pub fn load_snapshot(&mut self, snapshot: &persist::Snapshot) {
+        let mut roots = HashMap::new();
+        for (root, docs) in &snapshot.roots {
+            for doc in docs {
+                roots.insert(doc, root);
+            }
+        }
+
+        for (tag, docs) in &snapshot.tags {
+            for doc in docs {
+                let document = self.documents.entry(doc.clone()).or_insert(Document::default());
+                document.root = roots.get(doc).map(|root| root.to_string());
+
+                // NOTE: Older cache files may list a document multiple times under the same tag
+                if document.tags.insert(tag.clone()) {
+                    self.write_handle.insert(tag.clone(), doc.clone());
+                }
             }
         }
         self.commit();
     }
 
     pub fn commit(&mut self) {
+        if let Some(ref mut journal) = self.journal {
+            if let Err(err) = journal.sync() {
+                error!("Failed to sync index journal {:?}: {:?}", journal.path(), err);
+            }
+        }
+
         self.write_handle.refresh();
     }
 
+    // Collect the folders that have been queued for reindexing
+    // Duplicates and folders that are contained within another queued folder are removed, as crawling
+    // The outer folder will already cover them
     pub fn queued_folders(&self) -> Vec<String> {
-        self.root_channel.try_iter().collect()
+        let mut queued: Vec<String> = self.root_channel.try_iter().collect();
+
+        // NOTE: Sorting places every folder before any of its sub-folders
+        queued.sort();
+        queued.dedup();
+
+        let mut folders: Vec<String> = Vec::new();
+        for folder in queued {
+            if !folders.iter().any(|outer| Path::new(&folder).starts_with(outer)) {
+                folders.push(folder);
+            }
+        }
+
+        folders
     }
 }
 
+#[derive(Default)]
+struct Document {
+    root: Option<String>,
+    tags: HashSet<String>,
+}
+
 #[derive(Clone)]
 pub struct Index {
     read_handle: _IndexReader,
@@ -75,6 +243,11 @@ impl Index {
         let writer = IndexWriter{
             write_handle: writer,
             root_channel: dequeue,
+            documents: HashMap::new(),
+            current_root: None,
+            nested_roots: Vec::new(),
+            snapshot: None,
+            journal: None,
         };
 
         (index, writer)
@@ -83,14 +256,17 @@ impl Index {
     pub fn from_file(filepath: &Path) -> (Self, IndexWriter) {
         let (reader, mut writer) = Index::new();
         writer.load_file(filepath);
+        if let Err(err) = writer.persist_to(filepath) {
+            error!("Failed to open index journal for {:?}: {:?}", filepath, err);
+        }
         (reader, writer)
     }
 
+    // NOTE: This only writes the tag map, it leaves the journal untouched and doesn't record crawl roots
+    // Use `IndexWriter::checkpoint` to write a full snapshot
     pub fn write_file(&self, path: &Path) -> Result<(), io::Error> {
-        fs::File::create(path)
-            .and_then(|file| serde_json::to_writer(file, self)
-                .map_err(|err| err.into()))
-            .and_then(|_| Ok(()))
+        persist::write_atomic(path, |file| serde_json::to_writer(file, self)
+            .map_err(|err| err.into()))
     }
 
     pub fn retrieve(&self, query: &str) -> Vec<ElementList> {
@@ -109,6 +285,29 @@ impl Index {
     pub fn len(&self) -> usize {
         self.read_handle.len()
     }
+
+    // Take a snapshot of the current tag -> document mapping
+    pub fn entries(&self) -> HashMap<String, ElementList> {
+        self.read_handle.map_into(|k, v| (k.clone(), v.to_vec()))
+    }
+}
+
+// Read the contents of an index cache file (and its journal) without constructing an index
+// NOTE: Unlike `IndexWriter::load_file`, this reports any read/parse errors to the caller
+pub fn read_file(filepath: &Path) -> Result<persist::Snapshot, io::Error> {
+    // NOTE: A journal without a snapshot means we crashed before the first checkpoint was written
+    let mut snapshot = match fs::File::open(filepath) {
+        Ok(file) => persist::Snapshot::from_reader(io::BufReader::new(file))?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound && persist::journal_path(filepath).exists() => persist::Snapshot::default(),
+        Err(err) => return Err(err),
+    };
+
+    // Replay any changes made since the snapshot was written
+    for entry in persist::Journal::replay(filepath)? {
+        snapshot.apply(entry);
+    }
+
+    Ok(snapshot)
 }
 
 impl Serialize for Index {
@@ -119,3 +318,47 @@ impl Serialize for Index {
         map.end()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crawl(writer: &mut IndexWriter, folder: &str, root: &str, files: &[(&str, &str)]) {
+        writer.begin_crawl(folder, root);
+        for &(tag, path) in files {
+            writer.add(tag, path.to_string());
+        }
+        writer.end_crawl();
+    }
+
+    #[test]
+    fn nested_roots_drop_deleted_files() {
+        let (index, mut writer) = Index::new();
+        crawl(&mut writer, "/data/inner", "/data/inner", &[("alpha", "/data/inner/a.txt"), ("beta", "/data/inner/b.txt")]);
+
+        // The outer root walks over the nested root as well
+        crawl(&mut writer, "/data", "/data", &[
+            ("gamma", "/data/c.txt"),
+            ("alpha", "/data/inner/a.txt"),
+            ("beta", "/data/inner/b.txt"),
+        ]);
+        assert_eq!(writer.documents["/data/inner/a.txt"].root, Some("/data/inner".to_string()));
+        assert_eq!(writer.documents["/data/c.txt"].root, Some("/data".to_string()));
+
+        // `b.txt` has since been deleted
+        crawl(&mut writer, "/data/inner", "/data/inner", &[("alpha", "/data/inner/a.txt")]);
+        assert_eq!(index.retrieve("alpha"), vec![vec!["/data/inner/a.txt".to_string()]]);
+        assert_eq!(index.retrieve("beta"), vec![Vec::<String>::new()]);
+        assert_eq!(index.retrieve("gamma"), vec![vec!["/data/c.txt".to_string()]]);
+    }
+
+    #[test]
+    fn outer_roots_drop_deleted_files_in_nested_roots() {
+        let (index, mut writer) = Index::new();
+        crawl(&mut writer, "/data/inner", "/data/inner", &[("alpha", "/data/inner/a.txt"), ("beta", "/data/inner/b.txt")]);
+        crawl(&mut writer, "/data", "/data", &[("alpha", "/data/inner/a.txt")]);
+
+        assert_eq!(index.retrieve("beta"), vec![Vec::<String>::new()]);
+        assert_eq!(writer.documents["/data/inner/a.txt"].root, Some("/data/inner".to_string()));
+    }
+}
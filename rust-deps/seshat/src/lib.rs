@@ -1,14 +1,18 @@
 
 extern crate array_tool;
 extern crate evmap;
+#[macro_use]
 extern crate log;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
 extern crate walkdir;
 
 pub mod index;
 pub mod crawl;
 pub mod handle;
+pub mod persist;
 
 mod search;
 
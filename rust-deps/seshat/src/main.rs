@@ -1,27 +1,28 @@
 
-extern crate array_tool;
-extern crate evmap;
+extern crate clap;
 #[macro_use]
 extern crate log;
-extern crate serde;
+extern crate seshat;
+#[macro_use]
 extern crate serde_json;
 extern crate tags;
 extern crate walkdir;
 
-use walkdir::{DirEntry, WalkDir};
-
-// For testing
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::io;
+use std::io::Write;
 use std::path::Path;
+use std::process;
 use std::sync;
 use std::time::SystemTime;
 
-pub mod index;
-pub mod crawl;
-pub mod handle;
-mod search;
+use walkdir::DirEntry;
 
-use crawl::Crawler;
+use seshat::crawl::{self, Crawler};
+use seshat::handle;
+use seshat::index;
+use seshat::persist;
 
 // NOTE: This is a per-project implementation (ie. not part of the seshat library)
 struct MusicHandler;
@@ -71,36 +72,376 @@ impl handle::FileHandler for MusicHandler {
 // TODO: Insert system callbacks for when files are created/deleted
     // NOTE: Deleted files are a slightly lower priority
 
+/*
+This program is a command-line frontend over the seshat library. It operates on the same index cache
+File that the device-manager loads (through `--index-cache`), allowing the index to be built, queried,
+And repaired without having to spin up the whole network.
+*/
 
 fn main() {
-    // Open the output test file
-    // NOTE: This isn't really necessary for final implementations (may want to remove from handle interface)
-    // let output_file = Path::new("_files.txt");
-    // let mut output = match File::create(&output_file) {
-    //     Err(_why) => panic!("couldn't create output tracking file"),
-    //     Ok(file) => file,
-    // };
+    let args = load_configuration();
+    let (command, sub) = match args.subcommand() {
+        (command, Some(sub)) => (command, sub),
+        _ => unreachable!("clap should require a subcommand"),
+    };
+
+    // NOTE: `--index` is global, so it may be given either before or after the subcommand
+    let index_file = sub.value_of("index")
+        .or(args.value_of("index"))
+        .unwrap_or(DEFAULT_INDEX_FILE);
+    let index_file = Path::new(index_file);
+
+    let result = match command {
+        "index" => run_index(index_file, sub),
+        "search" => run_search(index_file, sub),
+        "stats" => run_stats(index_file, sub),
+        "export" => run_export(index_file, sub),
+        "verify" => run_verify(index_file, sub),
+        "compact" => run_compact(index_file, sub),
+        _ => unreachable!("clap only accepts the registered subcommands"),
+    };
+
+    match result {
+        Ok(true) => {},
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("seshat: {}", err);
+            process::exit(2);
+        }
+    }
+}
+
+// Subcommand results. `Ok(false)` indicates the command ran but found problems
+type CommandResult = Result<bool, io::Error>;
+
 
+//
+// Subcommand implementations
+//
+fn run_index<'a>(index_file: &Path, args: &clap::ArgMatches<'a>) -> CommandResult {
     // Initialize the file handlers
     let mut crawler = crawl::WindowsCrawler::new();
     crawler.register_handle(&["mp3", "mp4", "m4a"], sync::Arc::new(MusicHandler));
 
-    // Time everything (not necessary for final implementations)
+    // Either extend the existing index or start from scratch
+    let (idx, mut writer) = if args.is_present("fresh") {
+        let (idx, mut writer) = index::Index::new();
+        writer.persist_to(index_file)?;
+        (idx, writer)
+    } else {
+        index::Index::from_file(index_file)
+    };
+
+    // Time everything so we can get some feedback on crawling performance
     let now = SystemTime::now();
+    let mut num_files = 0;
+    for root in args.values_of("roots").into_iter().flat_map(|roots| roots) {
+        println!("Crawling {}", root);
+        num_files += crawler.recrawl(root, root, &mut writer);
+    }
 
-    // Start working on the indexer
-    let index_file = Path::new("index.json");
-    let (idx, mut writer) = index::Index::from_file(&index_file);
-    let num_files = crawler.crawl(WalkDir::new("C:\\"), &mut writer);
+    match now.elapsed() {
+        Ok(time) => println!("Visited {} files in {} seconds", num_files, time.as_secs()),
+        Err(_) => println!("Visited {} files in ERR seconds", num_files),
+    }
 
-    if let Ok(time) = now.elapsed() {
-        println!("Visited {} files in {} seconds", num_files, time.as_secs());
-    } else {
-        println!("Visited {} files in ERR seconds", num_files);
+    writer.checkpoint()?;
+    println!("Wrote {} tags to {}", idx.len(), index_file.display());
+    Ok(true)
+}
+
+fn run_search<'a>(index_file: &Path, args: &clap::ArgMatches<'a>) -> CommandResult {
+    let idx = open_index(index_file)?;
+    let query = args.values_of("query")
+        .map(|words| words.collect::<Vec<_>>().join(" "))
+        .unwrap_or(String::new());
+
+    let mut results = seshat::default_search(&query, &idx);
+    if let Some(limit) = args.value_of("limit") {
+        let limit = limit.parse::<usize>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "`limit` must be a positive integer"))?;
+        results.truncate(limit);
+    }
+
+    match args.value_of("format").unwrap_or("plain") {
+        "json" => println!("{}", serde_json::to_string_pretty(&results)?),
+        "count" => println!("{}", results.len()),
+        _ => for path in &results {
+            println!("{}", path);
+        },
+    }
+
+    Ok(true)
+}
+
+fn run_stats<'a>(index_file: &Path, args: &clap::ArgMatches<'a>) -> CommandResult {
+    let snapshot = index::read_file(index_file)?;
+    let entries = &snapshot.tags;
+
+    let num_tags = entries.len();
+    let num_refs: usize = entries.values().map(|docs| docs.len()).sum();
+    let documents = entries.values()
+        .flat_map(|docs| docs.iter())
+        .collect::<HashSet<_>>();
+
+    // Find the tags that reference the most documents
+    let top = args.value_of("top")
+        .and_then(|top| top.parse::<usize>().ok())
+        .unwrap_or(10);
+    let mut tags = entries.iter()
+        .map(|(tag, docs)| (tag, docs.len()))
+        .collect::<Vec<_>>();
+    tags.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    tags.truncate(top);
+
+    let file_size = fs::metadata(index_file).map(|meta| meta.len()).unwrap_or(0);
+
+    println!("Index file:    {}", index_file.display());
+    println!("File size:     {} bytes", file_size);
+    println!("Tags:          {}", num_tags);
+    println!("Documents:     {}", documents.len());
+    println!("References:    {}", num_refs);
+    if num_tags > 0 {
+        println!("Avg docs/tag:  {:.2}", num_refs as f64 / num_tags as f64);
     }
 
-    let search_results = search::default_search("muse", &idx);
-    println!("{:#?}", search_results);
+    if !snapshot.roots.is_empty() {
+        let mut roots = snapshot.roots.iter().collect::<Vec<_>>();
+        roots.sort_by(|a, b| a.0.cmp(b.0));
 
-    idx.write_file(&index_file).expect("Failed to write cache file");
+        println!("Roots:");
+        for (root, docs) in roots {
+            println!("  {:>8}  {}", docs.len(), root);
+        }
+    }
+
+    if !tags.is_empty() {
+        println!("Top tags:");
+        for (tag, count) in tags {
+            println!("  {:>8}  {}", count, tag);
+        }
+    }
+
+    Ok(true)
 }
+
+fn run_export<'a>(index_file: &Path, args: &clap::ArgMatches<'a>) -> CommandResult {
+    let snapshot = index::read_file(index_file)?;
+    let entries = &snapshot.tags;
+
+    let mut out: Box<dyn Write> = match args.value_of("output") {
+        Some(file) => Box::new(io::BufWriter::new(fs::File::create(file)?)),
+        None => Box::new(io::BufWriter::new(io::stdout())),
+    };
+
+    // Sort the export so that it can be diffed between runs
+    let mut tags = entries.keys().collect::<Vec<_>>();
+    tags.sort();
+
+    match args.value_of("format").unwrap_or("json") {
+        "tsv" => for tag in tags {
+            for doc in &entries[tag] {
+                writeln!(out, "{}\t{}", tag, doc)?;
+            }
+        },
+        _ => {
+            let sorted_tags = tags.into_iter()
+                .map(|tag| (tag.clone(), json!(entries[tag])))
+                .collect::<serde_json::Map<_, _>>();
+            let sorted_roots = snapshot.roots.iter()
+                .map(|(root, docs)| {
+                    let mut docs = docs.iter().collect::<Vec<_>>();
+                    docs.sort();
+                    (root.clone(), json!(docs))
+                })
+                .collect::<BTreeMap<_, _>>();
+
+            serde_json::to_writer_pretty(&mut out, &json!({ "tags": sorted_tags, "roots": sorted_roots }))?;
+            writeln!(out)?;
+        }
+    }
+
+    out.flush()?;
+    Ok(true)
+}
+
+fn run_verify<'a>(index_file: &Path, args: &clap::ArgMatches<'a>) -> CommandResult {
+    let snapshot = index::read_file(index_file)?;
+    let entries = &snapshot.tags;
+    let check_paths = !args.is_present("skip-paths");
+
+    let mut problems = 0;
+    let mut missing = HashSet::new();
+    for (tag, docs) in entries {
+        if tag.is_empty() || tag.contains(' ') || tag.to_lowercase() != *tag {
+            println!("malformed tag {:?}", tag);
+            problems += 1;
+        }
+
+        if docs.is_empty() {
+            println!("tag {:?} references no documents", tag);
+            problems += 1;
+        }
+
+        let mut seen = HashSet::new();
+        for doc in docs {
+            if !seen.insert(doc) {
+                println!("tag {:?} references {:?} multiple times", tag, doc);
+                problems += 1;
+            }
+
+            if check_paths && !missing.contains(doc) && !Path::new(doc).exists() {
+                println!("document {:?} no longer exists", doc);
+                missing.insert(doc);
+                problems += 1;
+            }
+        }
+    }
+
+    // Every document should only have been found under one crawl root
+    let mut owners = HashMap::new();
+    for (root, docs) in &snapshot.roots {
+        for doc in docs {
+            if let Some(other) = owners.insert(doc, root) {
+                println!("document {:?} belongs to both {:?} and {:?}", doc, other, root);
+                problems += 1;
+            }
+        }
+    }
+
+    println!("Checked {} tags in {}: {} problem(s) found", entries.len(), index_file.display(), problems);
+    Ok(problems == 0)
+}
+
+fn run_compact<'a>(index_file: &Path, args: &clap::ArgMatches<'a>) -> CommandResult {
+    let snapshot = index::read_file(index_file)?;
+    let keep_missing = args.is_present("keep-missing");
+    let before: usize = snapshot.tags.values().map(|docs| docs.len()).sum();
+
+    // Remove duplicate and dead references, dropping any tags that become empty
+    let mut exists = HashMap::new();
+    let mut compacted = HashMap::new();
+    for (tag, docs) in snapshot.tags.into_iter() {
+        let mut seen = HashSet::new();
+        let docs = docs.into_iter()
+            .filter(|doc| seen.insert(doc.clone()))
+            .filter(|doc| keep_missing || *exists.entry(doc.clone())
+                .or_insert_with(|| Path::new(doc).exists()))
+            .collect::<index::ElementList>();
+
+        if !docs.is_empty() {
+            compacted.insert(tag, docs);
+        }
+    }
+
+    let after: usize = compacted.values().map(|docs| docs.len()).sum();
+    let num_tags = compacted.len();
+
+    // Documents that were dropped from every tag also get dropped from their roots
+    let mut roots = snapshot.roots;
+    for docs in roots.values_mut() {
+        docs.retain(|doc| keep_missing || exists.get(doc).cloned().unwrap_or(false));
+    }
+    roots.retain(|_, docs| !docs.is_empty());
+
+    // NOTE: The compacted snapshot already includes any journaled changes, so the checkpoint can clear the journal
+    let (_, mut writer) = index::Index::new();
+    writer.load_snapshot(&persist::Snapshot{ tags: compacted, roots: roots });
+    writer.persist_to(index_file)?;
+    writer.checkpoint()?;
+
+    println!("Compacted {}: {} -> {} references ({} tags)", index_file.display(), before, after, num_tags);
+    Ok(true)
+}
+
+
+//
+// Helpers
+//
+fn open_index(index_file: &Path) -> Result<index::Index, io::Error> {
+    let snapshot = index::read_file(index_file)?;
+    let (idx, mut writer) = index::Index::new();
+    writer.load_snapshot(&snapshot);
+    Ok(idx)
+}
+
+// Parse the command line arguments
+fn load_configuration<'a>() -> clap::ArgMatches<'a> {
+    use clap::{AppSettings, Arg, SubCommand};
+
+    clap::App::new("seshat")
+        .version("0.1")
+        .author("Grayson Hooper <ghooper96@gmail.com>")
+        .about("Builds, queries, and maintains seshat file-system indices")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("index")
+            .long("index")
+            .short("i")
+            .help("location of the index cache file to operate on")
+            .value_name("JSON")
+            .takes_value(true)
+            .global(true))
+        .subcommand(SubCommand::with_name("index")
+            .about("Crawl the given root folders and write the results to the index")
+            .arg(Arg::with_name("roots")
+                .help("Root folder paths to crawl")
+                .required(true)
+                .multiple(true))
+            .arg(Arg::with_name("fresh")
+                .long("fresh")
+                .help("Discard the existing index contents instead of extending them")))
+        .subcommand(SubCommand::with_name("search")
+            .about("Search the index for documents matching the query")
+            .arg(Arg::with_name("query")
+                .help("Search terms")
+                .required(true)
+                .multiple(true))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .short("f")
+                .help("Output format for the search results")
+                .takes_value(true)
+                .possible_values(&["plain", "json", "count"])
+                .default_value("plain"))
+            .arg(Arg::with_name("limit")
+                .long("limit")
+                .short("n")
+                .help("Maximum number of results to print")
+                .value_name("N")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("stats")
+            .about("Print summary statistics about the index")
+            .arg(Arg::with_name("top")
+                .long("top")
+                .help("Number of most referenced tags to list")
+                .value_name("N")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("export")
+            .about("Write the index contents out in a stable, sorted form")
+            .arg(Arg::with_name("format")
+                .long("format")
+                .short("f")
+                .help("Output format for the exported index")
+                .takes_value(true)
+                .possible_values(&["json", "tsv"])
+                .default_value("json"))
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .help("File to write the export to (defaults to stdout)")
+                .value_name("FILE")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Check the index for malformed tags, duplicate references, and missing documents")
+            .arg(Arg::with_name("skip-paths")
+                .long("skip-paths")
+                .help("Don't check whether indexed documents still exist on disk")))
+        .subcommand(SubCommand::with_name("compact")
+            .about("Rewrite the index without duplicate or dead references")
+            .arg(Arg::with_name("keep-missing")
+                .long("keep-missing")
+                .help("Keep references to documents that no longer exist on disk")))
+        .get_matches()
+}
+
+const DEFAULT_INDEX_FILE: &'static str = "index.json";
@@ -0,0 +1,249 @@
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use super::index::{Element, ElementList};
+
+/*
+Index persistence is split between two files: a full "snapshot" of the index (the cache file) and an
+Append-only "journal" of every change made since that snapshot was taken. Snapshots are always written
+To a temporary file and then renamed over the old one, so a crash mid-write leaves the previous snapshot
+Intact. The journal is replayed on top of the snapshot when the index is loaded, and cleared whenever a
+New snapshot is successfully written.
+*/
+
+// On-disk form of the index
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Snapshot {
+    pub tags: HashMap<String, ElementList>,
+
+    // The documents that were found under each crawl root
+    // NOTE: Documents loaded from caches written before roots were tracked don't belong to any root
+    #[serde(default)]
+    pub roots: HashMap<String, HashSet<Element>>,
+}
+
+// Older cache files only stored the tag map
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SnapshotFormat {
+    Rooted(Snapshot),
+    Legacy(HashMap<String, ElementList>),
+}
+
+impl Snapshot {
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, serde_json::Error> {
+        serde_json::from_reader(reader)
+            .map(|format| match format {
+                SnapshotFormat::Rooted(snapshot) => snapshot,
+                SnapshotFormat::Legacy(tags) => Snapshot{ tags: tags, roots: HashMap::new() },
+            })
+    }
+
+    // Apply a journaled change on top of the snapshot
+    // NOTE: Changes that are already reflected in the snapshot are ignored, as the journal may not have been
+    // Cleared if we crashed right after writing the snapshot
+    pub fn apply(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::Insert{ tag, doc, root } => {
+                // NOTE: Documents only belong to the root they were last crawled under
+                if let Some(root) = root {
+                    for (other, docs) in self.roots.iter_mut() {
+                        if *other != root {
+                            docs.remove(&doc);
+                        }
+                    }
+                    self.roots.retain(|_, docs| !docs.is_empty());
+                    self.roots.entry(root).or_insert(HashSet::new()).insert(doc.clone());
+                }
+
+                let docs = self.tags.entry(tag).or_insert(Vec::new());
+                if !docs.contains(&doc) {
+                    docs.push(doc);
+                }
+            },
+            JournalEntry::Remove{ tag, doc } => {
+                let now_empty = self.tags.get_mut(&tag)
+                    .map(|docs| {
+                        docs.retain(|d| *d != doc);
+                        docs.is_empty()
+                    })
+                    .unwrap_or(false);
+
+                if now_empty {
+                    self.tags.remove(&tag);
+                }
+            },
+            JournalEntry::Forget{ doc } => {
+                for docs in self.roots.values_mut() {
+                    docs.remove(&doc);
+                }
+                self.roots.retain(|_, docs| !docs.is_empty());
+            },
+        }
+    }
+}
+
+// A single recorded change to the index
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum JournalEntry {
+    // `doc` was indexed under `tag` while crawling `root`
+    Insert {
+        tag: String,
+        doc: Element,
+        #[serde(default)]
+        root: Option<String>,
+    },
+
+    // `doc` is no longer indexed under `tag`
+    Remove { tag: String, doc: Element },
+
+    // `doc` has been dropped from the index entirely
+    Forget { doc: Element },
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: io::BufWriter<fs::File>,
+}
+
+impl Journal {
+    // Open the journal belonging to the given snapshot file, creating it if it doesn't exist
+    pub fn open(snapshot: &Path) -> Result<Self, io::Error> {
+        let path = journal_path(snapshot);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+
+        // Terminate any entry left partially written by a crash, so new entries start on their own line
+        let mut file = io::BufWriter::new(file);
+        if ends_in_partial_entry(file.get_mut())? {
+            file.write_all(b"\n")?;
+        }
+
+        Ok(Self{
+            path: path,
+            file: file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&mut self, entry: &JournalEntry) -> Result<(), io::Error> {
+        serde_json::to_writer(&mut self.file, entry)?;
+        self.file.write_all(b"\n")
+    }
+
+    // Make sure all recorded entries have hit the disk
+    pub fn sync(&mut self) -> Result<(), io::Error> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+
+    // Throw away all recorded entries (ie. after they've been folded into a new snapshot)
+    pub fn truncate(&mut self) -> Result<(), io::Error> {
+        self.file.flush()?;
+        self.file.get_ref().set_len(0)?;
+        self.file.get_ref().sync_all()
+    }
+
+    // Read back all entries recorded in the journal for the given snapshot file
+    // NOTE: A crash can leave a partially written entry in the journal. These entries are skipped, as the
+    // Change they describe never made it into a commit
+    pub fn replay(snapshot: &Path) -> Result<Vec<JournalEntry>, io::Error> {
+        let path = journal_path(snapshot);
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut entries = Vec::new();
+        for line in io::BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => warn!("Skipping malformed entry in journal {:?}: {:?}", path, err),
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+// Replace the contents of `path` without ever leaving a partially written file in its place
+pub fn write_atomic<F>(path: &Path, write: F) -> Result<(), io::Error>
+    where F: FnOnce(&mut io::BufWriter<fs::File>) -> Result<(), io::Error>
+{
+    let tmp = with_suffix(path, ".tmp");
+
+    let written = fs::File::create(&tmp)
+        .map(io::BufWriter::new)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.flush()?;
+            file.get_ref().sync_all()
+        });
+
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+
+    fs::rename(&tmp, path)?;
+    sync_parent_dir(path)
+}
+
+fn ends_in_partial_entry(file: &mut fs::File) -> Result<bool, io::Error> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+
+    let mut last = [0u8; 1];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+pub fn journal_path(snapshot: &Path) -> PathBuf {
+    with_suffix(snapshot, ".journal")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path.as_os_str());
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// The rename itself is only durable once the containing directory has been flushed
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<(), io::Error> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    fs::File::open(dir).and_then(|dir| dir.sync_all())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<(), io::Error> {
+    Ok(())
+}